@@ -0,0 +1,24 @@
+//! Feeds arbitrary input to the lexer and parser, which must reject what they can't handle
+//! without panicking, and checks that what parses prints as source that parses back to a tree
+//! that prints the same. Run with `cargo fuzz run parse`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use traash::{lexer, parser};
+
+fuzz_target!(|input: &str| {
+    let _ = lexer::lex_spans(input);
+    let _ = parser::is_incomplete(input);
+    if let Ok(tokens) = lexer::lex(input) {
+        let _ = parser::next_word_position(&tokens);
+        if let Ok(command) = parser::parse(&tokens) {
+            let printed = command.to_string();
+            let reparsed = lexer::lex(&printed).map(|tokens| parser::parse(&tokens));
+            match reparsed {
+                Ok(Ok(reparsed)) => assert_eq!(reparsed.to_string(), printed),
+                other => panic!("{:?} printed as {:?}, which gives {:?}", input, printed, other),
+            }
+        }
+    }
+});
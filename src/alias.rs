@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::lexer::{at_command_start, lex};
+use crate::tokens::Token;
+
+/// Characters that can't appear in an alias name.
+const INVALID: &str = "/$`=\\\"' \t\n|&;<>()";
+
+/// Whether `name` can be defined as an alias.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c| INVALID.contains(c))
+}
+
+/// Replaces aliases in command position with the tokens of their values. Values are lexed anew,
+/// so an alias can expand to operators such as `|` or `&&`. The first word of a value is itself
+/// substituted unless it names an alias already being expanded, and a value that ends in a blank
+/// makes the following word eligible too.
+pub fn substitute(tokens: Vec<Token>, aliases: &HashMap<String, String>) -> Vec<Token> {
+    if aliases.is_empty() {
+        return tokens;
+    }
+    substitute_within(tokens, aliases, &mut vec![]).0
+}
+
+/// Substitutes aliases in `tokens` other than those in `active`. Returns the tokens and whether
+/// the word following them is eligible because the last value ended in a blank.
+fn substitute_within(
+    tokens: Vec<Token>,
+    aliases: &HashMap<String, String>,
+    active: &mut Vec<String>,
+) -> (Vec<Token>, bool) {
+    let mut out = vec![];
+    let mut next_eligible = false;
+    for token in tokens {
+        let eligible = next_eligible || at_command_start(&out);
+        next_eligible = false;
+        let word = match token {
+            Token::Text(word) if eligible && !active.contains(&word) => word,
+            other => {
+                out.push(other);
+                continue;
+            }
+        };
+        let value = match aliases.get(&word) {
+            Some(value) => value,
+            None => {
+                out.push(Token::Text(word));
+                continue;
+            }
+        };
+        match lex(value) {
+            Ok(value_tokens) => {
+                active.push(word);
+                let (expanded, pending) = substitute_within(value_tokens, aliases, active);
+                active.pop();
+                out.extend(expanded);
+                next_eligible = pending || value.ends_with([' ', '\t']);
+            }
+            Err(_) => out.push(Token::Text(word)),
+        }
+    }
+    (out, next_eligible)
+}
+
+#[cfg(test)]
+fn substituted(input: &str, aliases: &[(&str, &str)]) -> Vec<Token> {
+    let aliases = aliases
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    substitute(lex(input).unwrap(), &aliases)
+}
+
+#[test]
+fn substitutes_words_in_command_position() {
+    let aliases = [("ll", "ls -l"), ("count", "wc -l |"), ("up", "cd .. &&")];
+    assert_eq!(
+        substituted("ll ll; echo ll | ll", &aliases),
+        lex("ls -l ll; echo ll | ls -l").unwrap()
+    );
+    assert_eq!(
+        substituted("count cat", &aliases),
+        lex("wc -l | cat").unwrap()
+    );
+    assert_eq!(
+        substituted("up ll", &aliases),
+        lex("cd .. && ls -l").unwrap()
+    );
+    assert_eq!(
+        substituted("\\ll 'll'", &aliases),
+        lex("\\ll 'll'").unwrap()
+    );
+}
+
+#[test]
+fn stops_recursive_aliases() {
+    let aliases = [("ls", "ls -F"), ("a", "b 1"), ("b", "a 2")];
+    assert_eq!(substituted("ls", &aliases), lex("ls -F").unwrap());
+    assert_eq!(substituted("a", &aliases), lex("a 2 1").unwrap());
+}
+
+#[test]
+fn trailing_blank_makes_next_word_eligible() {
+    let aliases = [("sudo", "sudo "), ("ll", "ls -l"), ("run", "sudo")];
+    assert_eq!(
+        substituted("sudo ll ll", &aliases),
+        lex("sudo ls -l ll").unwrap()
+    );
+    assert_eq!(substituted("run ll", &aliases), lex("sudo ls -l").unwrap());
+}
+
+#[test]
+fn alias_builtins_define_and_remove_aliases() {
+    use crate::executor::{capture_output, run_string, StreamSet};
+    use crate::state::State;
+
+    let mut state = State::new();
+    let streams = StreamSet::std();
+    let output = capture_output(
+        &mut state,
+        "alias greet='echo hi |' b=x; alias; alias greet; alias 'a/b=c'; echo $?",
+    );
+    assert_eq!(
+        output,
+        "alias b='x'\nalias greet='echo hi |'\nalias greet='echo hi |'\n1"
+    );
+    run_string(&mut state, "alias greet='echo hi |'", streams);
+    assert_eq!(capture_output(&mut state, "greet tr h H"), "Hi");
+    assert_eq!(run_string(&mut state, "unalias greet", streams), 0);
+    assert!(state.aliases.is_empty());
+    assert_eq!(
+        capture_output(&mut state, "unalias greet 2>&1"),
+        "traash: unalias: greet: not found"
+    );
+}
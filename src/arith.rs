@@ -0,0 +1,490 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::arith::ArithError::{
+    DivisionByZero, InvalidNumber, NotAssignable, Overflow, RecursionLimit, UnexpectedEnd,
+    UnexpectedToken,
+};
+use crate::state::State;
+
+/// How deeply variables whose values are themselves expressions may refer to each other.
+const MAX_VARIABLE_DEPTH: usize = 64;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ArithError {
+    DivisionByZero,
+    Overflow,
+    InvalidNumber(String),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    NotAssignable,
+    RecursionLimit(String),
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DivisionByZero => write!(f, "division by 0"),
+            Overflow => write!(f, "integer overflow"),
+            InvalidNumber(n) => write!(f, "invalid number {}", n),
+            UnexpectedToken(t) => write!(f, "syntax error: unexpected {}", t),
+            UnexpectedEnd => write!(f, "syntax error: operand expected"),
+            NotAssignable => write!(f, "attempted assignment to non-variable"),
+            RecursionLimit(name) => write!(f, "{}: expression recursion level exceeded", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArithToken {
+    Number(String),
+    Name(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+/// Operators, longest first so that the tokenizer matches greedily.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=",
+    "%=", "+=", "-=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "?", ":", "=", ",",
+];
+
+fn tokenize(input: &str) -> Result<Vec<ArithToken>, ArithError> {
+    let mut tokens = vec![];
+    let mut rest = input;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '#' || c == '@'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push(if c.is_ascii_digit() {
+                ArithToken::Number(word.to_string())
+            } else {
+                ArithToken::Name(word.to_string())
+            });
+            rest = &rest[end..];
+        } else if c == '(' {
+            tokens.push(ArithToken::LParen);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(ArithToken::RParen);
+            rest = &rest[1..];
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(ArithToken::Op(op));
+                    rest = &rest[op.len()..];
+                }
+                None => Err(UnexpectedToken(rest.to_string()))?,
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses an integer literal: decimal, `0x` hex, leading-zero octal or `base#digits`.
+fn parse_number(text: &str) -> Result<i64, ArithError> {
+    let invalid = || InvalidNumber(text.to_string());
+    let (base, digits) = if let Some(hash) = text.find('#') {
+        let base = text[..hash].parse::<u32>().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) {
+            return Err(invalid());
+        }
+        (base, &text[hash + 1..])
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, hex)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value
+            .checked_mul(base as i64)
+            .and_then(|v| v.checked_add(digit as i64))
+            .ok_or(Overflow)?;
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    /// `++x`/`--x` when `prefix`, `x++`/`x--` otherwise, with the amount added.
+    Increment(String, i64, bool),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// An assignment, with the binary operator of a compound assignment like `+=`.
+    Assign(String, Option<&'static str>, Box<Expr>),
+}
+
+/// Binding power of binary operators; higher binds tighter.
+fn binary_precedence(op: &str) -> Option<u8> {
+    let precedence = match op {
+        "," => 1,
+        "=" | "*=" | "/=" | "%=" | "+=" | "-=" | "<<=" | ">>=" | "&=" | "^=" | "|=" => 2,
+        "?" => 3,
+        "||" => 4,
+        "&&" => 5,
+        "|" => 6,
+        "^" => 7,
+        "&" => 8,
+        "==" | "!=" => 9,
+        "<" | ">" | "<=" | ">=" => 10,
+        "<<" | ">>" => 11,
+        "+" | "-" => 12,
+        "*" | "/" | "%" => 13,
+        "**" => 14,
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+struct ArithParser {
+    tokens: Vec<ArithToken>,
+    position: usize,
+}
+
+impl ArithParser {
+    fn peek(&self) -> Option<&ArithToken> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<ArithToken> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: ArithToken) -> Result<(), ArithError> {
+        match self.next() {
+            Some(ref t) if *t == expected => Ok(()),
+            Some(t) => Err(UnexpectedToken(describe(&t))),
+            None => Err(UnexpectedEnd),
+        }
+    }
+
+    fn parse_expr(&mut self, min_precedence: u8) -> Result<Expr, ArithError> {
+        let mut left = self.parse_unary()?;
+        while let Some(ArithToken::Op(op)) = self.peek() {
+            let op = *op;
+            let precedence = match binary_precedence(op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            self.next();
+            left = match op {
+                "?" => {
+                    let then = self.parse_expr(1)?;
+                    self.expect(ArithToken::Op(":"))?;
+                    let otherwise = self.parse_expr(precedence)?;
+                    Expr::Conditional(Box::new(left), Box::new(then), Box::new(otherwise))
+                }
+                _ if precedence == 2 => {
+                    let name = match left {
+                        Expr::Var(name) => name,
+                        _ => return Err(NotAssignable),
+                    };
+                    let value = self.parse_expr(precedence)?;
+                    let compound = match op {
+                        "=" => None,
+                        _ => Some(binary_of_assignment(op)),
+                    };
+                    Expr::Assign(name, compound, Box::new(value))
+                }
+                "**" => {
+                    let right = self.parse_expr(precedence)?;
+                    Expr::Binary(op, Box::new(left), Box::new(right))
+                }
+                _ => {
+                    let right = self.parse_expr(precedence + 1)?;
+                    Expr::Binary(op, Box::new(left), Box::new(right))
+                }
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ArithError> {
+        match self.next() {
+            Some(ArithToken::Op(op)) => match op {
+                "++" | "--" => match self.next() {
+                    Some(ArithToken::Name(name)) => {
+                        Ok(Expr::Increment(name, if op == "++" { 1 } else { -1 }, true))
+                    }
+                    _ => Err(NotAssignable),
+                },
+                "-" | "+" | "!" | "~" => {
+                    let operand = self.parse_unary()?;
+                    Ok(Expr::Unary(op, Box::new(operand)))
+                }
+                _ => Err(UnexpectedToken(op.to_string())),
+            },
+            Some(ArithToken::Number(n)) => Ok(Expr::Number(parse_number(&n)?)),
+            Some(ArithToken::Name(name)) => match self.peek() {
+                Some(ArithToken::Op(op)) if *op == "++" || *op == "--" => {
+                    let amount = if *op == "++" { 1 } else { -1 };
+                    self.next();
+                    Ok(Expr::Increment(name, amount, false))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            Some(ArithToken::LParen) => {
+                let inner = self.parse_expr(1)?;
+                self.expect(ArithToken::RParen)?;
+                Ok(inner)
+            }
+            Some(ArithToken::RParen) => Err(UnexpectedToken(")".to_string())),
+            None => Err(UnexpectedEnd),
+        }
+    }
+}
+
+fn describe(token: &ArithToken) -> String {
+    match token {
+        ArithToken::Number(n) | ArithToken::Name(n) => n.clone(),
+        ArithToken::Op(op) => op.to_string(),
+        ArithToken::LParen => "(".to_string(),
+        ArithToken::RParen => ")".to_string(),
+    }
+}
+
+fn binary_of_assignment(op: &'static str) -> &'static str {
+    match op {
+        "*=" => "*",
+        "/=" => "/",
+        "%=" => "%",
+        "+=" => "+",
+        "-=" => "-",
+        "<<=" => "<<",
+        ">>=" => ">>",
+        "&=" => "&",
+        "^=" => "^",
+        _ => "|",
+    }
+}
+
+fn apply_binary(op: &str, a: i64, b: i64) -> Result<i64, ArithError> {
+    let result = match op {
+        "+" => a.checked_add(b),
+        "-" => a.checked_sub(b),
+        "*" => a.checked_mul(b),
+        "/" | "%" if b == 0 => return Err(DivisionByZero),
+        "/" => a.checked_div(b),
+        "%" => a.checked_rem(b),
+        "**" if b < 0 => return Err(InvalidNumber(format!("exponent {}", b))),
+        "**" => u32::try_from(b).ok().and_then(|b| a.checked_pow(b)),
+        "<<" => u32::try_from(b).ok().and_then(|b| a.checked_shl(b)),
+        ">>" => u32::try_from(b).ok().and_then(|b| a.checked_shr(b)),
+        "<" => Some((a < b) as i64),
+        ">" => Some((a > b) as i64),
+        "<=" => Some((a <= b) as i64),
+        ">=" => Some((a >= b) as i64),
+        "==" => Some((a == b) as i64),
+        "!=" => Some((a != b) as i64),
+        "&" => Some(a & b),
+        "^" => Some(a ^ b),
+        "|" => Some(a | b),
+        "," => Some(b),
+        _ => return Err(UnexpectedToken(op.to_string())),
+    };
+    result.ok_or(Overflow)
+}
+
+struct Evaluator<'a> {
+    state: &'a mut State,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    /// Reads a variable. Its value is itself evaluated as an expression, and unset or empty
+    /// variables are 0.
+    fn read(&mut self, name: &str) -> Result<i64, ArithError> {
+        let value = self.state.get_var(name).unwrap_or("").trim().to_string();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(n) = parse_number(&value) {
+            return Ok(n);
+        }
+        if self.depth >= MAX_VARIABLE_DEPTH {
+            return Err(RecursionLimit(name.to_string()));
+        }
+        self.depth += 1;
+        let result = parse(&value).and_then(|expr| self.eval(&expr));
+        self.depth -= 1;
+        result
+    }
+
+    fn write(&mut self, name: &str, value: i64) -> i64 {
+        self.state.set_var(name, &value.to_string());
+        value
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<i64, ArithError> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Var(name) => self.read(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                match *op {
+                    "-" => value.checked_neg().ok_or(Overflow),
+                    "!" => Ok((value == 0) as i64),
+                    "~" => Ok(!value),
+                    _ => Ok(value),
+                }
+            }
+            Expr::Increment(name, amount, prefix) => {
+                let old = self.read(name)?;
+                let new = old.checked_add(*amount).ok_or(Overflow)?;
+                self.write(name, new);
+                Ok(if *prefix { new } else { old })
+            }
+            Expr::Binary("&&", a, b) => Ok((self.eval(a)? != 0 && self.eval(b)? != 0) as i64),
+            Expr::Binary("||", a, b) => Ok((self.eval(a)? != 0 || self.eval(b)? != 0) as i64),
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                apply_binary(op, a, b)
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+            Expr::Assign(name, compound, value) => {
+                let value = self.eval(value)?;
+                let value = match compound {
+                    Some(op) => apply_binary(op, self.read(name)?, value)?,
+                    None => value,
+                };
+                Ok(self.write(name, value))
+            }
+        }
+    }
+}
+
+fn parse(input: &str) -> Result<Expr, ArithError> {
+    let mut parser = ArithParser {
+        tokens: tokenize(input)?,
+        position: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Ok(Expr::Number(0));
+    }
+    let expr = parser.parse_expr(1)?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(token) => Err(UnexpectedToken(describe(&token))),
+    }
+}
+
+/// Evaluates an arithmetic expression, reading and assigning shell variables in `state`.
+pub fn evaluate(state: &mut State, input: &str) -> Result<i64, ArithError> {
+    let expr = parse(input)?;
+    Evaluator { state, depth: 0 }.eval(&expr)
+}
+
+#[cfg(test)]
+fn eval(input: &str) -> Result<i64, ArithError> {
+    evaluate(&mut State::new(), input)
+}
+
+#[test]
+fn evaluates_with_c_precedence() {
+    assert_eq!(eval("1 + 2 * 3"), Ok(7));
+    assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+    assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+    assert_eq!(eval("-2 ** 2"), Ok(4));
+    assert_eq!(eval("1 << 4 | 1"), Ok(17));
+    assert_eq!(eval("7 % 4 == 3 && !0"), Ok(1));
+    assert_eq!(eval("~5 ^ 3"), Ok(-7));
+    assert_eq!(eval("1 < 2 ? 10 : 20"), Ok(10));
+    assert_eq!(eval("0 ? 1 : 0 ? 2 : 3"), Ok(3));
+    assert_eq!(eval("1, 2, 3"), Ok(3));
+    assert_eq!(eval(""), Ok(0));
+}
+
+#[test]
+fn parses_number_literals() {
+    assert_eq!(eval("0x1F"), Ok(31));
+    assert_eq!(eval("017"), Ok(15));
+    assert_eq!(eval("2#101"), Ok(5));
+    assert_eq!(eval("36#z"), Ok(35));
+    assert_eq!(eval("64#_"), Ok(63));
+    assert_eq!(eval("08"), Err(InvalidNumber("08".to_string())));
+    assert_eq!(eval("65#1"), Err(InvalidNumber("65#1".to_string())));
+}
+
+#[test]
+fn reads_and_assigns_variables() {
+    let mut state = State::new();
+    state.set_var("x", "5");
+    state.set_var("y", "x * 2");
+
+    assert_eq!(evaluate(&mut state, "y + unset"), Ok(10));
+    assert_eq!(evaluate(&mut state, "x += 3"), Ok(8));
+    assert_eq!(evaluate(&mut state, "x++ + ++x"), Ok(18));
+    assert_eq!(state.get_var("x"), Some("10"));
+    assert_eq!(evaluate(&mut state, "z = x-- , z"), Ok(10));
+    assert_eq!(state.get_var("x"), Some("9"));
+}
+
+#[test]
+fn short_circuits_side_effects() {
+    let mut state = State::new();
+
+    assert_eq!(evaluate(&mut state, "0 && (a = 1)"), Ok(0));
+    assert_eq!(evaluate(&mut state, "1 || (b = 1)"), Ok(1));
+    assert_eq!(evaluate(&mut state, "1 ? 2 : (c = 1)"), Ok(2));
+
+    assert_eq!(state.get_var("a"), None);
+    assert_eq!(state.get_var("b"), None);
+    assert_eq!(state.get_var("c"), None);
+}
+
+#[test]
+fn reports_errors_instead_of_panicking() {
+    assert_eq!(eval("1 / 0"), Err(DivisionByZero));
+    assert_eq!(eval("1 % 0"), Err(DivisionByZero));
+    assert_eq!(eval("9223372036854775807 + 1"), Err(Overflow));
+    assert_eq!(eval("-9223372036854775807 - 1 / -1"), Ok(i64::MIN + 2));
+    assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Err(Overflow));
+    assert_eq!(eval("1 << 64"), Err(Overflow));
+    assert_eq!(eval("99999999999999999999"), Err(Overflow));
+    assert_eq!(eval("1 +"), Err(UnexpectedEnd));
+    assert_eq!(eval("(1"), Err(UnexpectedEnd));
+    assert_eq!(eval("1 = 2"), Err(NotAssignable));
+
+    let mut state = State::new();
+    state.set_var("loop", "loop + 1");
+    assert_eq!(
+        evaluate(&mut state, "loop"),
+        Err(RecursionLimit("loop".to_string()))
+    );
+}
@@ -1,19 +1,26 @@
+use std::fmt;
+
+use crate::lexer::escape_delimiters;
 use crate::tokens::Token;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SingleCommand {
-    pub(crate) args: Vec<String>,
+    pub args: Vec<String>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BinaryOp {
     Seq,
-    Fork,
     Pipe,
     LogAnd,
     LogOr,
 }
 
+/// The precedence of `&`, which ends a list element, on the scale of `BinaryOp::precedence`.
+pub(crate) const BACKGROUND_PRECEDENCE: u8 = 1;
+/// The precedence of `!`, which negates a pipeline.
+pub(crate) const NOT_PRECEDENCE: u8 = 3;
+
 impl BinaryOp {
     pub fn from(token: &Token) -> Option<BinaryOp> {
         match token {
@@ -21,10 +28,29 @@ impl BinaryOp {
             Token::LogOr => Some(BinaryOp::LogOr),
             Token::Pipe => Some(BinaryOp::Pipe),
             Token::Semicolon => Some(BinaryOp::Seq),
-            Token::Fork => Some(BinaryOp::Fork),
             _ => None,
         }
     }
+
+    /// How tightly the operator binds, following POSIX: pipelines are joined by `&&` and `||`
+    /// into lists, which `;` joins. The numbers in between are for `&`, which ends a list
+    /// element, and `!`, which negates a pipeline.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Seq => 0,
+            BinaryOp::LogAnd | BinaryOp::LogOr => 2,
+            BinaryOp::Pipe => 4,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Seq => ";",
+            BinaryOp::Pipe => "|",
+            BinaryOp::LogAnd => "&&",
+            BinaryOp::LogOr => "||",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -34,17 +60,154 @@ pub struct BinaryExpr {
     pub second: Box<Command>,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RedirectKind {
+    /// `<`
+    Read,
+    /// `>`
+    Write,
+    /// `>>`
+    Append,
+    /// `<&`
+    DupInput,
+    /// `>&`
+    DupOutput,
+}
+
+impl RedirectKind {
+    pub fn from(token: &Token) -> Option<RedirectKind> {
+        match token {
+            Token::ReadFile => Some(RedirectKind::Read),
+            Token::WriteFile => Some(RedirectKind::Write),
+            Token::AppendFile => Some(RedirectKind::Append),
+            Token::DupInput => Some(RedirectKind::DupInput),
+            Token::DupOutput => Some(RedirectKind::DupOutput),
+            _ => None,
+        }
+    }
+
+    /// The file descriptor redirected when the operator has no number in front of it.
+    pub fn default_fd(self) -> i32 {
+        match self {
+            RedirectKind::Read | RedirectKind::DupInput => 0,
+            _ => 1,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            RedirectKind::Read => "<",
+            RedirectKind::Write => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::DupInput => "<&",
+            RedirectKind::DupOutput => ">&",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Redirection {
+    pub fd: i32,
+    pub kind: RedirectKind,
+    pub target: String,
+}
+
+/// Prints the redirection as `2> file` or `2>&1`, leaving out the default descriptor.
+impl fmt::Display for Redirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.fd != self.kind.default_fd() {
+            write!(f, "{}", self.fd)?;
+        }
+        match self.kind {
+            RedirectKind::DupInput | RedirectKind::DupOutput => {
+                write!(
+                    f,
+                    "{}{}",
+                    self.kind.symbol(),
+                    escape_delimiters(&self.target)
+                )
+            }
+            _ => write!(
+                f,
+                "{} {}",
+                self.kind.symbol(),
+                escape_delimiters(&self.target)
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct FileInput {
-    src: String,
-    dst: Box<Command>,
+pub struct Redirect {
+    pub command: Box<Command>,
+    pub redirections: Vec<Redirection>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct FileOutput {
-    src: Box<Command>,
-    dst: String,
-    append: bool,
+pub struct FunctionDef {
+    pub name: String,
+    pub body: Box<Command>,
+}
+
+/// An expression inside `[[ ... ]]`. Operands are unexpanded words.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CondExpr {
+    /// A lone word, true when it expands to a non-empty string.
+    Word(String),
+    /// A unary test such as `-f file`.
+    Unary(String, String),
+    /// A binary test such as `a == b`, with the operator in the middle.
+    Binary(String, String, String),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+impl CondExpr {
+    /// Writes `self`, in parentheses if it is an `&&` or `||` that binds more loosely than the
+    /// context allows.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, loosest: u8) -> fmt::Result {
+        let precedence = match self {
+            CondExpr::Or(..) => 0,
+            CondExpr::And(..) => 1,
+            _ => 2,
+        };
+        match precedence < loosest {
+            true => write!(f, "( {} )", self),
+            false => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for CondExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CondExpr::Word(word) => write!(f, "{}", escape_delimiters(word)),
+            CondExpr::Unary(op, word) => write!(f, "{} {}", op, escape_delimiters(word)),
+            // The pattern after `=~` is read as it is written.
+            CondExpr::Binary(left, op, right) if op == "=~" => {
+                write!(f, "{} {} {}", escape_delimiters(left), op, right)
+            }
+            CondExpr::Binary(left, op, right) => {
+                let (left, right) = (escape_delimiters(left), escape_delimiters(right));
+                write!(f, "{} {} {}", left, op, right)
+            }
+            CondExpr::Not(expr) => {
+                write!(f, "! ")?;
+                expr.fmt_operand(f, 2)
+            }
+            CondExpr::And(left, right) => {
+                left.fmt_operand(f, 1)?;
+                write!(f, " && ")?;
+                right.fmt_operand(f, 2)
+            }
+            CondExpr::Or(left, right) => {
+                left.fmt_operand(f, 0)?;
+                write!(f, " || ")?;
+                right.fmt_operand(f, 1)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -52,8 +215,148 @@ pub enum Command {
     Nil,
     Single(SingleCommand),
     BinaryExpr(BinaryExpr),
-    FileInput(FileInput),
-    FileOutput(FileOutput),
+    /// A list element ended by `&`, which runs in the background.
+    Background(Box<Command>),
+    /// `! pipeline`, which succeeds when the pipeline fails.
+    Not(Box<Command>),
+    Redirect(Redirect),
+    FunctionDef(FunctionDef),
+    Subshell(Box<Command>),
+    Group(Box<Command>),
+    /// `(( expr ))`, with the unexpanded expression.
+    Arith(String),
+    /// `[[ expr ]]`
+    Conditional(CondExpr),
+    /// A command with a syntax error in it, in a tree parsed past its errors.
+    Error,
+}
+
+impl Command {
+    /// How tightly the command holds together as an operand, on the scale of
+    /// `BinaryOp::precedence`.
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            Command::BinaryExpr(b) => b.op.precedence(),
+            Command::Background(_) => BACKGROUND_PRECEDENCE,
+            Command::Not(_) => NOT_PRECEDENCE,
+            _ => u8::MAX,
+        }
+    }
+
+    /// Whether a `}` after this command would be an argument rather than close a group, so that
+    /// a `;` is needed in between.
+    pub(crate) fn ends_with_word(&self) -> bool {
+        match self {
+            Command::BinaryExpr(b) => b.second.ends_with_word(),
+            Command::Not(command) => command.ends_with_word(),
+            Command::Single(_) | Command::Redirect(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the command ends with `&`, which separates it from the next one without a `;`.
+    fn ends_with_background(&self) -> bool {
+        match self {
+            Command::Background(_) => true,
+            Command::BinaryExpr(b) if b.op == BinaryOp::Seq => b.second.ends_with_background(),
+            _ => false,
+        }
+    }
+
+    /// Writes `body` in braces, which group commands without changing what they do.
+    fn fmt_group(f: &mut fmt::Formatter, body: &Command) -> fmt::Result {
+        match body {
+            Command::Nil => write!(f, "{{ }}"),
+            body if body.ends_with_word() => write!(f, "{{ {}; }}", body),
+            body => write!(f, "{{ {} }}", body),
+        }
+    }
+
+    /// Writes `self` as an operand, grouped in braces if it binds more loosely than the context
+    /// allows.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, loosest: u8) -> fmt::Result {
+        match self.precedence() < loosest {
+            true => Command::fmt_group(f, self),
+            false => write!(f, "{}", self),
+        }
+    }
+}
+
+/// A command printed as an operand that binds at least as tightly as the precedence given.
+struct Operand<'a>(&'a Command, u8);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_operand(f, self.1)
+    }
+}
+
+/// Prints the command as shell source that parses back to the same tree, on one line and with
+/// as few groups as the precedence of its operators allows.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Nil => Ok(()),
+            Command::Single(c) => {
+                let args = c.args.iter().map(|arg| escape_delimiters(arg));
+                write!(f, "{}", args.collect::<Vec<_>>().join(" "))
+            }
+            Command::BinaryExpr(b) => {
+                let precedence = b.op.precedence();
+                let first = Operand(&b.first, precedence).to_string();
+                write!(f, "{}", first)?;
+                let background = b.first.ends_with_background();
+                let op = match &b.op {
+                    // `&` separates commands by itself.
+                    BinaryOp::Seq if background && *b.second != Command::Nil => "".to_string(),
+                    // `;;` and `&;` would be read as one operator.
+                    BinaryOp::Seq if background || first.ends_with(';') => " ;".to_string(),
+                    BinaryOp::Seq => ";".to_string(),
+                    op => format!(" {}", op.symbol()),
+                };
+                match &*b.second {
+                    Command::Nil => return write!(f, "{}", op),
+                    _ => write!(f, "{} ", op)?,
+                }
+                // Operators group from the left, so an operand on the right binds tighter.
+                b.second.fmt_operand(f, precedence + 1)
+            }
+            Command::Background(command) => {
+                command.fmt_operand(f, BACKGROUND_PRECEDENCE + 1)?;
+                write!(f, " &")
+            }
+            Command::Not(command) => {
+                write!(f, "! ")?;
+                command.fmt_operand(f, NOT_PRECEDENCE)
+            }
+            Command::Redirect(r) => {
+                let redirections = r.redirections.iter().map(|r| r.to_string());
+                let redirections = redirections.collect::<Vec<_>>().join(" ");
+                match &*r.command {
+                    Command::Nil => write!(f, "{}", redirections),
+                    // Braces and `!` after a redirection are words rather than keywords.
+                    Command::Single(c) if ["{", "}", "!"].contains(&c.args[0].as_str()) => {
+                        write!(f, "{} {}", redirections, r.command)
+                    }
+                    command => {
+                        command.fmt_operand(f, u8::MAX)?;
+                        write!(f, " {}", redirections)
+                    }
+                }
+            }
+            Command::FunctionDef(def) => write!(f, "{}() {}", def.name, def.body),
+            // `((` would start an arithmetic command.
+            Command::Subshell(body) if body.to_string().starts_with('(') => {
+                write!(f, "( {})", body)
+            }
+            Command::Subshell(body) => write!(f, "({})", body),
+            Command::Group(body) => Command::fmt_group(f, body),
+            Command::Arith(expr) => write!(f, "(({}))", expr),
+            Command::Conditional(expr) => write!(f, "[[ {} ]]", expr),
+            // There is no source to print for a command that didn't parse.
+            Command::Error => Ok(()),
+        }
+    }
 }
 
 pub fn single(args: Vec<String>) -> Command {
@@ -72,8 +375,12 @@ pub fn sequential(a: Command, b: Command) -> Command {
     binary(BinaryOp::Seq, a, b)
 }
 
-pub fn fork(a: Command, b: Command) -> Command {
-    binary(BinaryOp::Fork, a, b)
+pub fn background(command: Command) -> Command {
+    Command::Background(Box::new(command))
+}
+
+pub fn not(command: Command) -> Command {
+    Command::Not(Box::new(command))
 }
 
 pub fn log_and(a: Command, b: Command) -> Command {
@@ -87,3 +394,25 @@ pub fn log_or(a: Command, b: Command) -> Command {
 pub fn pipe(a: Command, b: Command) -> Command {
     binary(BinaryOp::Pipe, a, b)
 }
+
+pub fn function_def(name: &str, body: Command) -> Command {
+    Command::FunctionDef(FunctionDef {
+        name: name.to_string(),
+        body: Box::new(body),
+    })
+}
+
+pub fn subshell(body: Command) -> Command {
+    Command::Subshell(Box::new(body))
+}
+
+pub fn group(body: Command) -> Command {
+    Command::Group(Box::new(body))
+}
+
+pub fn redirect(command: Command, redirections: Vec<Redirection>) -> Command {
+    Command::Redirect(Redirect {
+        command: Box::new(command),
+        redirections,
+    })
+}
@@ -0,0 +1,698 @@
+use std::convert::TryFrom;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::{chdir, getcwd, Pid};
+use nix::Error;
+
+use crate::alias;
+use crate::completion;
+use crate::conditional;
+use crate::executor::{self, source_file, StreamSet};
+use crate::expand::{self, is_name, split_assignment};
+use crate::history;
+use crate::state::State;
+
+/// A command implemented inside the shell. It receives the expanded arguments, including its own
+/// name as `args[0]`, and returns an exit status.
+pub type Builtin = fn(&mut State, &[String], &StreamSet) -> i32;
+
+/// The names `find` knows, for completion.
+pub const NAMES: &[&str] = &[
+    ".", ":", "[", "alias", "cd", "compgen", "complete", "echo", "exec", "exit", "export", "false",
+    "history", "kill", "local", "pwd", "return", "set", "shift", "source", "test", "true",
+    "unalias", "unset", "wait",
+];
+
+pub fn find(name: &str) -> Option<Builtin> {
+    let builtin: Builtin = match name {
+        "." | "source" => source,
+        ":" | "true" => |_, _, _| 0,
+        "false" => |_, _, _| 1,
+        "alias" => alias,
+        "cd" => cd,
+        "compgen" => compgen,
+        "complete" => complete,
+        "echo" => echo,
+        "exec" => exec,
+        "exit" => exit,
+        "export" => export,
+        "history" => history,
+        "kill" => kill,
+        "local" => local,
+        "pwd" => pwd,
+        "return" => return_,
+        "set" => set,
+        "shift" => shift,
+        "test" | "[" => test,
+        "unalias" => unalias,
+        "unset" => unset,
+        "wait" => wait,
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+/// Describes an error the way `strerror` would, without nix's errno name prefix.
+fn describe(err: nix::Error) -> String {
+    match err {
+        nix::Error::Sys(errno) => errno.desc().to_string(),
+        _ => err.to_string(),
+    }
+}
+
+fn parse_status(streams: &StreamSet, name: &str, arg: Option<&String>, default: i32) -> i32 {
+    match arg {
+        None => default,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                streams.write_err(&format!(
+                    "traash: {}: {}: numeric argument required\n",
+                    name, arg
+                ));
+                2
+            }
+        },
+    }
+}
+
+fn alias(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let print = |name: &str, value: &str| {
+        streams.write_out(&format!("alias {}={}\n", name, expand::quote(value)));
+    };
+    let names = match args.get(1).map(|a| a.as_str()) {
+        Some("-p") => &args[2..],
+        _ => &args[1..],
+    };
+    if names.is_empty() {
+        let mut aliases: Vec<_> = state.aliases.iter().collect();
+        aliases.sort();
+        for (name, value) in aliases {
+            print(name, value);
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in names {
+        match arg.split_once('=') {
+            Some((name, value)) if alias::is_valid_name(name) => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            Some((name, _)) => {
+                streams.write_err(&format!("traash: alias: `{}': invalid alias name\n", name));
+                status = 1;
+            }
+            None => match state.aliases.get(arg) {
+                Some(value) => print(arg, value),
+                None => {
+                    streams.write_err(&format!("traash: alias: {}: not found\n", arg));
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+fn cd(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let target = match args.get(1).map(|s| s.as_str()) {
+        None => match state.get_var("HOME") {
+            Some(home) => home.to_string(),
+            None => {
+                streams.write_err("traash: cd: HOME not set\n");
+                return 1;
+            }
+        },
+        Some("-") => match state.get_var("OLDPWD") {
+            Some(old) => {
+                streams.write_out(&format!("{}\n", old));
+                old.to_string()
+            }
+            None => {
+                streams.write_err("traash: cd: OLDPWD not set\n");
+                return 1;
+            }
+        },
+        Some(dir) => dir.to_string(),
+    };
+    if let Err(e) = chdir(target.as_str()) {
+        streams.write_err(&format!("traash: cd: {}: {}\n", target, describe(e)));
+        return 1;
+    }
+    if let Some(old) = state.get_var("PWD").map(|s| s.to_string()) {
+        state.set_var("OLDPWD", &old);
+    }
+    if let Ok(cwd) = getcwd() {
+        state.set_var("PWD", &cwd.to_string_lossy());
+    }
+    0
+}
+
+fn compgen(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let options = match completion::parse_options(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            streams.write_err(&format!("traash: compgen: {}\n", e));
+            return 2;
+        }
+    };
+    let word = options.operands.first().map_or("", |w| w.as_str());
+    let words = completion::compgen(state, &options.spec, word);
+    for word in &words {
+        streams.write_out(&format!("{}\n", word));
+    }
+    if words.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+fn complete(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let options = match completion::parse_options(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            streams.write_err(&format!("traash: complete: {}\n", e));
+            return 2;
+        }
+    };
+    if options.remove {
+        if options.operands.is_empty() {
+            state.completions.clear();
+        }
+        for name in &options.operands {
+            state.completions.remove(name);
+        }
+        return 0;
+    }
+    if options.print || options.operands.is_empty() {
+        let mut names = match options.operands.is_empty() {
+            true => state.completions.keys().cloned().collect(),
+            false => options.operands,
+        };
+        names.sort();
+        let mut status = 0;
+        for name in names {
+            match state.completions.get(&name).map(|spec| spec.to_string()) {
+                Some(spec) if spec.is_empty() => {
+                    streams.write_out(&format!("complete {}\n", name));
+                }
+                Some(spec) => streams.write_out(&format!("complete {} {}\n", spec, name)),
+                None => {
+                    streams.write_err(&format!(
+                        "traash: complete: {}: no completion specification\n",
+                        name
+                    ));
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    }
+    for name in options.operands {
+        state.completions.insert(name, options.spec.clone());
+    }
+    0
+}
+
+fn echo(_: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let (newline, words) = match args.get(1).map(|s| s.as_str()) {
+        Some("-n") => (false, &args[2..]),
+        _ => (true, &args[1..]),
+    };
+    let mut out = words.join(" ");
+    if newline {
+        out.push('\n');
+    }
+    streams.write_out(&out);
+    0
+}
+
+/// `exec [command [arg...]]`: replaces the shell with the command. Without one, the executor
+/// has already left the redirections on `exec` in place in the shell, and there's nothing to do.
+fn exec(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    match args.len() {
+        1 => 0,
+        _ => executor::exec(state, &args[1..], *streams),
+    }
+}
+
+fn exit(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let status = parse_status(streams, "exit", args.get(1), state.last_status);
+    history::save(state);
+    std::process::exit(status)
+}
+
+fn export(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let mut status = 0;
+    for arg in &args[1..] {
+        match split_assignment(arg) {
+            Some((name, value)) => {
+                state.set_var(name, value);
+                state.export_var(name);
+            }
+            None if is_name(arg) => state.export_var(arg),
+            None => {
+                streams.write_err(&format!(
+                    "traash: export: `{}': not a valid identifier\n",
+                    arg
+                ));
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+fn history(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let option = args.get(1).map(|s| s.as_str());
+    let path = match args.get(2).map(|p| p.to_string()) {
+        Some(path) => Some(path),
+        None => state.get_var("HISTFILE").map(|p| p.to_string()),
+    };
+    let result = match (option, path) {
+        (Some("-c"), _) => {
+            state.history.clear();
+            Ok(())
+        }
+        (Some("-d"), _) => return history_delete(state, args.get(2), streams),
+        (Some("-s"), _) => {
+            let line = args[2..].join(" ");
+            let limit = history::size_limit(state);
+            state.history.push(&line, limit);
+            Ok(())
+        }
+        (Some("-a"), Some(path)) => {
+            let limit = history::file_size_limit(state);
+            state.history.append_to_file(&path, limit)
+        }
+        (Some("-w"), Some(path)) => {
+            let limit = history::file_size_limit(state);
+            state.history.write_file(&path, limit)
+        }
+        (Some("-r"), Some(path)) => {
+            let limit = history::size_limit(state);
+            state.history.read_file(&path, limit)
+        }
+        (Some("-a"), None) | (Some("-w"), None) | (Some("-r"), None) => {
+            streams.write_err("traash: history: HISTFILE not set\n");
+            return 1;
+        }
+        (Some(arg), _) if arg.starts_with('-') => {
+            streams.write_err(&format!("traash: history: {}: invalid option\n", arg));
+            return 2;
+        }
+        (count, _) => return history_list(state, count, streams),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            streams.write_err(&format!("traash: history: {}\n", e));
+            1
+        }
+    }
+}
+
+/// Lists the history, or its last `count` entries.
+fn history_list(state: &State, count: Option<&str>, streams: &StreamSet) -> i32 {
+    let entries = state.history.entries();
+    let count = match count.map(|c| c.parse::<usize>()) {
+        None => entries.len(),
+        Some(Ok(n)) => n.min(entries.len()),
+        Some(Err(_)) => {
+            streams.write_err(&format!(
+                "traash: history: {}: numeric argument required\n",
+                count.unwrap_or_default()
+            ));
+            return 1;
+        }
+    };
+    let skip = entries.len() - count;
+    let first = state.history.first_number() + skip;
+    let out: String = entries[skip..]
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| format!("{:5}  {}\n", first + i, entry))
+        .collect();
+    streams.write_out(&out);
+    0
+}
+
+/// Deletes the entry with the given number. Negative numbers count back from the end.
+fn history_delete(state: &mut State, arg: Option<&String>, streams: &StreamSet) -> i32 {
+    let end = state.history.first_number() + state.history.entries().len();
+    let number = match arg.map(|a| a.parse::<i64>()) {
+        Some(Ok(n)) if n < 0 => (end as i64 + n) as usize,
+        Some(Ok(n)) => n as usize,
+        _ => 0,
+    };
+    if !state.history.delete(number) {
+        streams.write_err(&format!(
+            "traash: history: {}: history position out of range\n",
+            arg.map_or("", |a| a.as_str())
+        ));
+        return 1;
+    }
+    0
+}
+
+/// Parses a signal given by number or by name, with or without the `SIG` prefix.
+fn parse_signal(spec: &str) -> Option<Signal> {
+    if let Ok(n) = spec.parse::<i32>() {
+        return Signal::try_from(n).ok();
+    }
+    let name = spec.to_ascii_uppercase();
+    match name.starts_with("SIG") {
+        true => name.parse().ok(),
+        false => format!("SIG{}", name).parse().ok(),
+    }
+}
+
+fn kill(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let (spec, pids) = match args.get(1).map(|s| s.as_str()) {
+        Some("-s") | Some("-n") => (args.get(2).map(|s| s.as_str()), args.get(3..)),
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => (Some(&arg[1..]), args.get(2..)),
+        _ => (Some("TERM"), args.get(1..)),
+    };
+    let pids = pids.unwrap_or_default();
+    let signal = match spec.map(|spec| (spec, parse_signal(spec))) {
+        Some((_, Some(signal))) => signal,
+        Some((spec, None)) => {
+            streams.write_err(&format!(
+                "traash: kill: {}: invalid signal specification\n",
+                spec
+            ));
+            return 1;
+        }
+        None => {
+            streams.write_err(&format!(
+                "traash: kill: {}: option requires an argument\n",
+                args[1]
+            ));
+            return 2;
+        }
+    };
+    if pids.is_empty() {
+        streams
+            .write_err("traash: kill: usage: kill [-s sigspec | -n signum | -sigspec] pid ...\n");
+        return 2;
+    }
+    let mut status = 0;
+    for arg in pids {
+        let pid = match arg.parse::<i32>() {
+            Ok(pid) => Pid::from_raw(pid),
+            Err(_) => {
+                streams.write_err(&format!(
+                    "traash: kill: {}: arguments must be process IDs\n",
+                    arg
+                ));
+                status = 1;
+                continue;
+            }
+        };
+        // A job that has been reaped may have had its pid reused, so it is not signalled.
+        let result = match state.jobs.iter().find(|job| job.pid() == pid) {
+            Some(job) => job.kill(signal),
+            None if state.finished_jobs.iter().any(|(p, _)| *p == pid) => {
+                Err(Error::Sys(Errno::ESRCH))
+            }
+            None => state.sys.kill(pid, signal),
+        };
+        if let Err(e) = result {
+            streams.write_err(&format!("traash: kill: ({}) - {}\n", pid, describe(e)));
+            status = 1;
+        }
+    }
+    status
+}
+
+fn local(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let mut status = 0;
+    for arg in &args[1..] {
+        let (name, value) = match split_assignment(arg) {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_name(name) {
+            streams.write_err(&format!(
+                "traash: local: `{}': not a valid identifier\n",
+                arg
+            ));
+            status = 1;
+        } else if !state.declare_local(name, value) {
+            streams.write_err("traash: local: can only be used in a function\n");
+            return 1;
+        }
+    }
+    status
+}
+
+fn pwd(state: &mut State, _: &[String], streams: &StreamSet) -> i32 {
+    match getcwd() {
+        Ok(cwd) => {
+            streams.write_out(&format!("{}\n", cwd.to_string_lossy()));
+            0
+        }
+        Err(e) => {
+            let pwd = state.get_var("PWD").unwrap_or_default().to_string();
+            streams.write_err(&format!("traash: pwd: {}: {}\n", pwd, describe(e)));
+            1
+        }
+    }
+}
+
+fn return_(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    if state.function_depth() == 0 && state.source_depth == 0 {
+        streams.write_err("traash: return: can only `return' from a function or sourced script\n");
+        return 1;
+    }
+    let status = parse_status(streams, "return", args.get(1), state.last_status);
+    state.returning = true;
+    status
+}
+
+/// `set [-+xv] [-+o option] [--] [arg...]`: turns shell options on with `-` and off with `+`,
+/// and makes the arguments after them, if any, the positional parameters.
+fn set(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        if arg == "--" {
+            i += 1;
+            state.positional.clear();
+            break;
+        }
+        let on = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => break,
+        };
+        if arg.len() < 2 {
+            break;
+        }
+        i += 1;
+        for flag in arg.chars().skip(1) {
+            let name = match flag {
+                'x' => "xtrace",
+                'v' => "verbose",
+                'o' => match args.get(i) {
+                    Some(name) => {
+                        i += 1;
+                        name.as_str()
+                    }
+                    None => {
+                        streams.write_err("traash: set: -o: option requires an argument\n");
+                        return 2;
+                    }
+                },
+                _ => {
+                    streams.write_err(&format!("traash: set: -{}: invalid option\n", flag));
+                    return 2;
+                }
+            };
+            match name {
+                "xtrace" => state.xtrace = on,
+                "verbose" => state.verbose = on,
+                _ => {
+                    streams.write_err(&format!("traash: set: {}: invalid option name\n", name));
+                    return 2;
+                }
+            }
+        }
+    }
+    if i < args.len() {
+        state.positional = args[i..].to_vec();
+    }
+    0
+}
+
+fn shift(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let n = match args.get(1).map(|n| n.parse::<usize>()) {
+        None => 1,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            streams.write_err(&format!(
+                "traash: shift: {}: numeric argument required\n",
+                args[1]
+            ));
+            return 1;
+        }
+    };
+    if n > state.positional.len() {
+        return 1;
+    }
+    state.positional.drain(..n);
+    0
+}
+
+/// Finds the file for `.`: a name without a slash is looked up in `PATH`, then in the current
+/// directory.
+fn find_source(state: &State, name: &str) -> Option<String> {
+    if name.contains('/') {
+        return Some(name.to_string());
+    }
+    let path = state.get_var("PATH").unwrap_or_default();
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| format!("{}/{}", dir, name))
+        .find(|file| Path::new(file).is_file())
+        .or_else(|| Some(name.to_string()).filter(|name| Path::new(name).is_file()))
+}
+
+fn source(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let name = match args.get(1) {
+        Some(name) => name,
+        None => {
+            streams.write_err(&format!(
+                "traash: {}: filename argument required\n",
+                args[0]
+            ));
+            return 2;
+        }
+    };
+    let path = match find_source(state, name) {
+        Some(path) => path,
+        None => {
+            streams.write_err(&format!("traash: {}: {}: file not found\n", args[0], name));
+            return 1;
+        }
+    };
+    let positional = match args.len() > 2 {
+        true => Some(std::mem::replace(&mut state.positional, args[2..].to_vec())),
+        false => None,
+    };
+    let status = match source_file(state, &path, *streams) {
+        Ok(status) => status,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}: {}: {}\n", args[0], name, e));
+            1
+        }
+    };
+    if let Some(positional) = positional {
+        state.positional = positional;
+    }
+    status
+}
+
+fn test(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    let name = args[0].as_str();
+    let operands = match (name, args.last().map(|a| a.as_str())) {
+        ("[", Some("]")) => &args[1..args.len() - 1],
+        ("[", _) => {
+            streams.write_err("traash: [: missing `]'\n");
+            return 2;
+        }
+        _ => &args[1..],
+    };
+    match conditional::test(state, operands) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}: {}\n", name, e));
+            2
+        }
+    }
+}
+
+fn unalias(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    if args.get(1).map(|a| a.as_str()) == Some("-a") {
+        state.aliases.clear();
+        return 0;
+    }
+    if args.len() < 2 {
+        streams.write_err("traash: unalias: usage: unalias [-a] name [name ...]\n");
+        return 2;
+    }
+    let mut status = 0;
+    for name in &args[1..] {
+        if state.aliases.remove(name).is_none() {
+            streams.write_err(&format!("traash: unalias: {}: not found\n", name));
+            status = 1;
+        }
+    }
+    status
+}
+
+fn unset(state: &mut State, args: &[String], _: &StreamSet) -> i32 {
+    let (functions, names) = match args.get(1).map(|s| s.as_str()) {
+        Some("-f") => (true, &args[2..]),
+        Some("-v") => (false, &args[2..]),
+        _ => (false, &args[1..]),
+    };
+    for name in names {
+        if functions {
+            state.functions.remove(name);
+        } else {
+            state.unset_var(name);
+        }
+    }
+    0
+}
+
+/// Waits for the given background jobs, or all of them, and returns the status of the last.
+fn wait(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+    if args.len() < 2 {
+        for mut job in state.jobs.drain(..) {
+            job.wait();
+        }
+        state.finished_jobs.clear();
+        return 0;
+    }
+    let mut status = 0;
+    for arg in &args[1..] {
+        let pid = match arg.parse::<i32>() {
+            Ok(pid) => Pid::from_raw(pid),
+            Err(_) => {
+                streams.write_err(&format!("traash: wait: `{}': not a pid\n", arg));
+                status = 2;
+                continue;
+            }
+        };
+        let job = state.jobs.iter().position(|job| job.pid() == pid);
+        let finished = state.finished_jobs.iter().position(|(p, _)| *p == pid);
+        status = match (job, finished) {
+            (Some(i), _) => state.jobs.remove(i).wait(),
+            (None, Some(i)) => {
+                let (_, status) = state.finished_jobs[i];
+                state.finished_jobs.remove(i);
+                status
+            }
+            (None, None) => {
+                streams.write_err(&format!(
+                    "traash: wait: pid {} is not a child of this shell\n",
+                    pid
+                ));
+                127
+            }
+        };
+    }
+    status
+}
+
+#[test]
+fn every_listed_name_is_a_builtin() {
+    for name in NAMES {
+        assert!(find(name).is_some(), "{} is not a builtin", name);
+    }
+}
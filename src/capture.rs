@@ -0,0 +1,354 @@
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, read, setpgid, write, ForkResult, Pid};
+
+use crate::executor::{close_shell_fds, run_string, StreamSet};
+use crate::state::State;
+use crate::sys::Sys;
+
+/// How long to keep reading from a command after killing it for its timeout. Processes that
+/// left its group can hold the pipes open indefinitely, so they are closed after this.
+const DRAIN_AFTER_KILL: Duration = Duration::from_millis(500);
+
+/// What to run a captured command with.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureOptions {
+    /// Bytes to write to the command's stdin. Without them, stdin is `/dev/null`.
+    pub stdin: Option<Vec<u8>>,
+    /// How long the command may run before its process group is killed.
+    pub timeout: Option<Duration>,
+    /// Variables to set and export for the command only.
+    pub env: Vec<(String, String)>,
+}
+
+/// What a captured command wrote and how it ended.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus,
+    /// Whether the command was killed for running past its timeout.
+    pub timed_out: bool,
+}
+
+/// A pipe end being drained or fed, until it is closed.
+struct Channel {
+    fd: Option<RawFd>,
+    /// What has been read, or what is left to write.
+    data: Vec<u8>,
+    writes: bool,
+}
+
+impl Channel {
+    fn new(fd: Option<RawFd>, writes: bool) -> Channel {
+        Channel {
+            fd,
+            data: vec![],
+            writes,
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            let _ = close(fd);
+        }
+    }
+}
+
+/// Runs `input` in a subshell in its own process group, collecting its stdout and stderr. Both
+/// pipes are drained while stdin is fed, so a command that fills one pipe while another is full
+/// can't deadlock. Like command substitution, the subshell's changes to variables are lost.
+///
+/// The subshell is a forked copy of the shell running the interpreter, which is not
+/// async-signal-safe, so the calling program must not have threads that could be holding a lock
+/// the interpreter needs, such as the allocator's.
+pub fn capture(state: &mut State, input: &str, options: &CaptureOptions) -> nix::Result<Output> {
+    let sys = state.sys.clone();
+    let (feeder, streams) = StreamSet::new(None, None, None).pipe(&*sys)?;
+    let (mut streams, collector) = match streams.pipe(&*sys) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            close_all(&*sys, &[feeder.get(1), streams.get(0)]);
+            return Err(e);
+        }
+    };
+    let (err_r, err_w) = match sys.pipe() {
+        Ok(fds) => fds,
+        Err(e) => {
+            let fds = [
+                feeder.get(1),
+                streams.get(0),
+                streams.get(1),
+                collector.get(0),
+            ];
+            close_all(&*sys, &fds);
+            return Err(e);
+        }
+    };
+    streams.set(2, Some(err_w));
+    let child_fds = [streams.get(0), streams.get(1), streams.get(2)];
+    let mut stdin = Channel::new(feeder.get(1), true);
+    let mut stdout = Channel::new(collector.get(0), false);
+    let mut stderr = Channel::new(Some(err_r), false);
+
+    let child = match sys.fork() {
+        Ok(ForkResult::Child) => {
+            let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+            if options.stdin.is_none() {
+                streams.set(0, None);
+            }
+            streams.install(&*sys);
+            close_shell_fds();
+            for (name, value) in &options.env {
+                state.set_var(name, value);
+                state.export_var(name);
+            }
+            let status = run_string(state, input, StreamSet::std());
+            unsafe { libc::_exit(status) }
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            for fd in child_fds.iter().flatten() {
+                let _ = sys.close(*fd);
+            }
+            for channel in [&mut stdin, &mut stdout, &mut stderr] {
+                channel.close();
+            }
+            return Err(e);
+        }
+    };
+    // Set the group here too, so it exists before any timeout could kill it.
+    let _ = setpgid(child, child);
+    for fd in child_fds.iter().flatten() {
+        let _ = sys.close(*fd);
+    }
+
+    match &options.stdin {
+        Some(input) if !input.is_empty() => stdin.data = input.clone(),
+        _ => stdin.close(),
+    }
+    if let Some(fd) = stdin.fd {
+        // A full pipe must not block the loop that drains the others.
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    }
+    let mut deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut timed_out = false;
+    while stdin.fd.is_some() || stdout.fd.is_some() || stderr.fd.is_some() {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        let channels = [&mut stdin, &mut stdout, &mut stderr];
+        if poll_channels(channels, timeout)? > 0 {
+            continue;
+        }
+        if timed_out {
+            for channel in [&mut stdin, &mut stdout, &mut stderr] {
+                channel.close();
+            }
+        } else {
+            let _ = killpg(child, Signal::SIGKILL);
+            timed_out = true;
+            deadline = Some(Instant::now() + DRAIN_AFTER_KILL);
+        }
+    }
+
+    let status = match deadline {
+        Some(deadline) if !timed_out => {
+            let (status, killed) = wait_until(child, deadline)?;
+            timed_out = killed;
+            status
+        }
+        _ => wait_for(child)?,
+    };
+    Ok(Output {
+        stdout: stdout.data,
+        stderr: stderr.data,
+        status,
+        timed_out,
+    })
+}
+
+/// Closes the pipe ends that were opened before setting up a capture failed.
+fn close_all(sys: &dyn Sys, fds: &[Option<RawFd>]) {
+    for fd in fds.iter().flatten() {
+        let _ = sys.close(*fd);
+    }
+}
+
+/// Blocks until `child` exits and returns how it ended.
+fn wait_for(child: Pid) -> nix::Result<ExitStatus> {
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(ExitStatus::from_raw(code << 8)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                return Ok(ExitStatus::from_raw(signal as i32))
+            }
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Blocks until `child` exits, killing its process group if it is still running at `deadline`.
+/// Returns how it ended and whether it was killed.
+///
+/// `waitpid` can't time out, so another thread blocks in it. That thread is only started after
+/// the fork, and is joined before returning.
+fn wait_until(child: Pid, deadline: Instant) -> nix::Result<(ExitStatus, bool)> {
+    let (sender, receiver) = mpsc::channel();
+    let waiter = thread::spawn(move || sender.send(wait_for(child)));
+    let left = deadline.saturating_duration_since(Instant::now());
+    let result = match receiver.recv_timeout(left) {
+        Ok(status) => status.map(|status| (status, false)),
+        Err(_) => {
+            let _ = killpg(child, Signal::SIGKILL);
+            let status = receiver
+                .recv()
+                .unwrap_or(Err(nix::Error::Sys(Errno::ECHILD)));
+            status.map(|status| (status, true))
+        }
+    };
+    let _ = waiter.join();
+    result
+}
+
+/// Waits up to `timeout` milliseconds for the open channels, then feeds and drains the ready
+/// ones. Returns how many were ready, which is 0 on a timeout.
+fn poll_channels(all: [&mut Channel; 3], timeout: libc::c_int) -> nix::Result<libc::c_int> {
+    let mut channels = vec![];
+    let mut fds = vec![];
+    for channel in all {
+        if let Some(fd) = channel.fd {
+            let events = match channel.writes {
+                true => PollFlags::POLLOUT,
+                false => PollFlags::POLLIN,
+            };
+            fds.push(PollFd::new(fd, events));
+            channels.push(channel);
+        }
+    }
+    let ready = match poll(&mut fds, timeout) {
+        Ok(ready) => ready,
+        Err(nix::Error::Sys(Errno::EINTR)) => return Ok(1),
+        Err(e) => return Err(e),
+    };
+    for (channel, fd) in channels.into_iter().zip(fds) {
+        if fd.revents().is_some_and(|events| !events.is_empty()) {
+            transfer(channel);
+        }
+    }
+    Ok(ready)
+}
+
+/// Writes what is left to a writable channel, or reads what is available from a readable one.
+/// Closes the channel when it is done or broken.
+fn transfer(channel: &mut Channel) {
+    let fd = match channel.fd {
+        Some(fd) => fd,
+        None => return,
+    };
+    let mut buf = [0u8; 4096];
+    let result = match channel.writes {
+        true => write(fd, &channel.data),
+        false => read(fd, &mut buf),
+    };
+    match result {
+        Ok(0) if !channel.writes => channel.close(),
+        Ok(n) if channel.writes => {
+            channel.data.drain(..n);
+            if channel.data.is_empty() {
+                channel.close();
+            }
+        }
+        Ok(n) => channel.data.extend_from_slice(&buf[..n]),
+        Err(nix::Error::Sys(Errno::EINTR)) | Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+        Err(_) => channel.close(),
+    }
+}
+
+#[test]
+fn captures_both_streams_and_the_status() {
+    let mut state = State::new();
+    let options = CaptureOptions {
+        env: vec![("GREETING".to_string(), "hi".to_string())],
+        ..CaptureOptions::default()
+    };
+    let output = capture(&mut state, "echo $GREETING; echo oops >&2; false", &options).unwrap();
+    assert_eq!(output.stdout, b"hi\n");
+    assert_eq!(output.stderr, b"oops\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!output.timed_out);
+    assert_eq!(state.get_var("GREETING"), None);
+}
+
+#[test]
+fn feeds_stdin_without_deadlocking() {
+    let mut state = State::new();
+    // More than a pipe holds, so feeding and draining have to interleave.
+    let input = "line\n".repeat(100_000);
+    let options = CaptureOptions {
+        stdin: Some(input.clone().into_bytes()),
+        ..CaptureOptions::default()
+    };
+    let output = capture(&mut state, "cat; cat /dev/null >&2", &options).unwrap();
+    assert_eq!(output.stdout.len(), input.len());
+    assert!(output.status.success());
+}
+
+#[test]
+fn kills_commands_that_time_out() {
+    let mut state = State::new();
+    let options = CaptureOptions {
+        timeout: Some(Duration::from_millis(100)),
+        ..CaptureOptions::default()
+    };
+    let started = Instant::now();
+    let output = capture(&mut state, "echo started; sleep 5 | cat", &options).unwrap();
+    assert!(output.timed_out);
+    assert_eq!(output.stdout, b"started\n");
+    assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn times_out_commands_that_close_their_output() {
+    let mut state = State::new();
+    let options = CaptureOptions {
+        timeout: Some(Duration::from_millis(100)),
+        ..CaptureOptions::default()
+    };
+    let started = Instant::now();
+    let output = capture(&mut state, "exec >&- 2>&-; sleep 5", &options).unwrap();
+    assert!(output.timed_out);
+    assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn stops_draining_pipes_held_outside_the_group() {
+    let mut state = State::new();
+    let options = CaptureOptions {
+        timeout: Some(Duration::from_millis(100)),
+        ..CaptureOptions::default()
+    };
+    let started = Instant::now();
+    // The setsid'd sleep survives the kill and keeps stdout open.
+    let output = capture(&mut state, "setsid sleep 5 & sleep 5", &options).unwrap();
+    assert!(output.timed_out);
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
@@ -0,0 +1,263 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::IntoRawFd;
+
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::isatty;
+
+use crate::completion::{CommandCache, Completer};
+use crate::editor::{Editor, ReadLine};
+use crate::executor::{self, StreamSet};
+use crate::startup::{self, Dump, Options};
+use crate::state::State;
+use crate::suggest::Suggester;
+use crate::{dump, format, history, parser, prompt, trace};
+
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+/// Keeps an interactive shell alive when C-c or C-\ is meant for the command it is running.
+/// Catching the signals instead of ignoring them means commands get the default handling back
+/// when they exec.
+fn catch_interrupts() {
+    let action = SigAction::new(
+        SigHandler::Handler(ignore_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in &[Signal::SIGINT, Signal::SIGQUIT] {
+        let _ = unsafe { sigaction(*signal, &action) };
+    }
+}
+
+/// Sets up history for an interactive shell, defaulting `HISTFILE` to `~/.traash_history`.
+fn start_history(state: &mut State) {
+    if state.get_var("HISTFILE").is_none() {
+        if let Some(home) = state.get_var("HOME").map(|h| h.to_string()) {
+            state.set_var("HISTFILE", &format!("{}/.traash_history", home));
+        }
+    }
+    history::load(state);
+}
+
+/// Applies history expansion to an interactive line and records it. Returns `None` if expansion
+/// failed and the line should not run.
+fn accept_line(state: &mut State, line: String) -> Option<String> {
+    let streams = StreamSet::std();
+    let line = match history::expand(&line, &state.history) {
+        Ok(Some(expanded)) => {
+            streams.write_err(&format!("{}\n", expanded));
+            expanded
+        }
+        Ok(None) => line,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}\n", e));
+            state.last_status = 1;
+            return None;
+        }
+    };
+    history::record(state, &line);
+    Some(line)
+}
+
+/// Reads a whole command, prompting with `PS1` and then with `PS2` for as long as the input is
+/// incomplete. Input that ends in the middle of a command is returned as it is, for the parser to
+/// report.
+fn read_command(
+    editor: &mut Editor,
+    state: &mut State,
+    commands: &mut CommandCache,
+    suggester: &Suggester,
+    interactive: bool,
+) -> nix::Result<ReadLine> {
+    let mut command = String::new();
+    let mut prompt_name = "PS1";
+    loop {
+        let prompt = match interactive {
+            true => prompt::render(state, prompt_name),
+            false => String::new(),
+        };
+        // Completion functions need the state mutably, so the editor gets the history on its own
+        // while it reads.
+        let history = std::mem::take(&mut state.history);
+        let mut completer = Completer {
+            state,
+            cache: commands,
+            suggester,
+        };
+        let read = editor.read_line(&prompt, history.entries(), &mut completer);
+        state.history = history;
+        match read? {
+            ReadLine::Line(line) => {
+                if prompt_name == "PS2" {
+                    command.push('\n');
+                }
+                command.push_str(&line);
+                if !parser::is_incomplete(&command) {
+                    return Ok(ReadLine::Line(command));
+                }
+                prompt_name = "PS2";
+            }
+            ReadLine::Eof if !command.is_empty() => return Ok(ReadLine::Line(command)),
+            other => return Ok(other),
+        }
+    }
+}
+
+/// Reads the commands the shell would run: `-c`'s, the script's or standard input's. Returns the
+/// name to report errors with and the text, or the status to exit with if it can't be read.
+fn read_source(options: &Options, streams: StreamSet) -> Result<(&str, String), i32> {
+    let (name, input) = match (&options.command, &options.script) {
+        (Some(command), _) => return Ok(("-c", command.clone())),
+        (None, Some(script)) => (script.as_str(), fs::read_to_string(script)),
+        (None, None) => {
+            let mut input = String::new();
+            (
+                "stdin",
+                io::stdin().read_to_string(&mut input).map(|_| input),
+            )
+        }
+    };
+    match input {
+        Ok(input) => Ok((name, input)),
+        Err(e) => {
+            streams.write_err(&format!("traash: {}: {}\n", name, e));
+            Err(127)
+        }
+    }
+}
+
+/// Checks the syntax of the commands without running them, for `-n`, `--fmt` and the dumps, and
+/// prints what was asked for. Every syntax error is reported with its line and column. The
+/// token and tree dumps show what was read in spite of them, and nothing is formatted.
+fn check_source(options: &Options, streams: StreamSet) -> i32 {
+    let (name, input) = match read_source(options, streams) {
+        Ok(source) => source,
+        Err(status) => return status,
+    };
+    let (dumped, errors) = match options.dump {
+        Some(Dump::Tokens) => dump::tokens(&input),
+        Some(Dump::Tree) => {
+            let (command, errors) = dump::parse_checked(&input);
+            (dump::tree(&command), errors)
+        }
+        Some(Dump::Json) => {
+            let (command, errors) = dump::parse_checked(&input);
+            (format!("{}\n", dump::json(&command)), errors)
+        }
+        None => (String::new(), dump::parse_checked(&input).1),
+    };
+    streams.write_out(&dumped);
+    for e in &errors {
+        streams.write_err(&format!("traash: {}:{}\n", name, e));
+    }
+    if !errors.is_empty() {
+        return 2;
+    }
+    if options.format {
+        match format::format(&input) {
+            Ok(formatted) => streams.write_out(&formatted),
+            Err(e) => {
+                streams.write_err(&format!("traash: {}: syntax error: {}\n", name, e));
+                return 2;
+            }
+        }
+    }
+    0
+}
+
+/// Runs the shell the way the `traash` binary does, with `argv` as its command line, and returns
+/// the status to exit with.
+pub fn run(argv: &[String]) -> i32 {
+    let mut state = State::from_env();
+    let streams = StreamSet::std();
+    let mut options = match Options::parse(argv) {
+        Ok(options) => options,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}\n", e));
+            return 2;
+        }
+    };
+    if options.check || options.format || options.dump.is_some() {
+        return check_source(&options, streams);
+    }
+    options.posix |= state.get_var("POSIXLY_CORRECT").is_some();
+    state.name = options.name.clone();
+    state.positional = options.args.clone();
+    state.xtrace = options.xtrace;
+    state.verbose = options.verbose;
+    let reads_stdin = options.command.is_none() && options.script.is_none();
+    let interactive = options.interactive || (reads_stdin && isatty(0).unwrap_or(false));
+    prompt::set_defaults(&mut state, interactive);
+    if interactive {
+        catch_interrupts();
+    }
+    startup::source_startup_files(&mut state, &options, interactive);
+    let _ = executor::watch_children();
+    if let Some(command) = &options.command {
+        trace::echo_input(&state, command, &streams);
+        return executor::run_string(&mut state, command, streams);
+    }
+    let input = match &options.script {
+        // Moved above the descriptors scripts redirect, so `exec 3<file` can't replace it.
+        Some(script) => match File::open(script) {
+            Ok(file) => {
+                let fd = file.into_raw_fd();
+                match state.sys.dup(fd) {
+                    Ok(moved) => {
+                        let _ = state.sys.close(fd);
+                        moved
+                    }
+                    Err(_) => fd,
+                }
+            }
+            Err(e) => {
+                streams.write_err(&format!("traash: {}: {}\n", script, e));
+                return 127;
+            }
+        },
+        None => 0,
+    };
+    if interactive {
+        start_history(&mut state);
+    }
+    // A script is the shell's alone, but commands share standard input and read on from where
+    // the shell stopped.
+    let mut editor = match options.script {
+        Some(_) => Editor::buffered(input, 2),
+        None => Editor::new(input, 2),
+    };
+    let mut commands = CommandCache::new();
+    let mut suggester = Suggester::new();
+    loop {
+        executor::reap_jobs(&mut state);
+        if interactive {
+            prompt::run_prompt_command(&mut state);
+        }
+        let read = read_command(
+            &mut editor,
+            &mut state,
+            &mut commands,
+            &suggester,
+            interactive,
+        );
+        match read {
+            Ok(ReadLine::Line(line)) => {
+                trace::echo_input(&state, &line, &streams);
+                let line = match interactive {
+                    true => accept_line(&mut state, line),
+                    false => Some(line),
+                };
+                if let Some(line) = line {
+                    let pwd = state.get_var("PWD").unwrap_or_default();
+                    suggester.record(&line, pwd);
+                    executor::run_string(&mut state, &line, StreamSet::std());
+                }
+            }
+            Ok(ReadLine::Interrupted) => state.last_status = 130,
+            Ok(ReadLine::Eof) | Err(_) => break,
+        }
+    }
+    history::save(&mut state);
+    state.last_status
+}
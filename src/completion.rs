@@ -0,0 +1,904 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::builtins;
+use crate::editor::{Completion, Helper};
+use crate::executor::{capture_output, run_string, StreamSet};
+use crate::expand::{expand_string, quote};
+use crate::highlight;
+use crate::lexer::{lex, lex_spans};
+use crate::parser::{next_word_position, WordPosition};
+use crate::state::State;
+use crate::suggest::Suggester;
+use crate::tokens::Token;
+
+/// Characters that need a backslash to appear literally in an unquoted word.
+const SPECIAL: &str = " \t\n\\'\"$`&|;<>()*?[]#!{}~";
+
+/// Characters that end a word when unquoted.
+const DELIMITERS: &str = ";&|<>()";
+
+/// The executables on `PATH`, rescanned when `PATH` or one of its directories changes.
+#[derive(Debug, Default)]
+pub struct CommandCache {
+    path: String,
+    /// Each directory with its modification time when it was scanned.
+    dirs: Vec<(String, Option<SystemTime>)>,
+    commands: Vec<String>,
+}
+
+fn modified(dir: &str) -> Option<SystemTime> {
+    fs::metadata(dir).and_then(|m| m.modified()).ok()
+}
+
+pub(crate) fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+impl CommandCache {
+    pub fn new() -> CommandCache {
+        CommandCache::default()
+    }
+
+    fn is_stale(&self, path: &str) -> bool {
+        path != self.path || self.dirs.iter().any(|(dir, time)| modified(dir) != *time)
+    }
+
+    /// The names of the executables on `path`, sorted.
+    pub fn commands(&mut self, path: &str) -> &[String] {
+        if self.is_stale(path) || self.dirs.is_empty() {
+            self.path = path.to_string();
+            self.dirs = path
+                .split(':')
+                .filter(|d| !d.is_empty())
+                .map(|d| (d.to_string(), modified(d)))
+                .collect();
+            self.commands = self
+                .dirs
+                .iter()
+                .filter_map(|(dir, _)| fs::read_dir(dir).ok())
+                .flatten()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| is_executable(&entry.path()))
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect();
+            self.commands.sort();
+            self.commands.dedup();
+        }
+        &self.commands
+    }
+}
+
+/// A possible completion of a word.
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
+struct Candidate {
+    /// The whole word, unquoted.
+    text: String,
+    /// What to show when listing candidates.
+    display: String,
+    /// Whether the word is unfinished when complete, like a directory that may be followed by
+    /// more of a path.
+    partial: bool,
+}
+
+/// Finds where the word before the cursor starts, and the quote it is inside of at the cursor.
+fn current_word(before: &str) -> (usize, Option<char>) {
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = before.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, '\\') | (Some('"'), '\\') => {
+                chars.next();
+            }
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, _) if c.is_whitespace() || DELIMITERS.contains(c) => start = i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    (start, quote)
+}
+
+/// Removes quotes and backslashes from a partly typed word, without expanding anything.
+pub(crate) fn unquote(word: &str) -> String {
+    let mut out = String::new();
+    let mut quote = None;
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\\') => out.extend(chars.next()),
+            (Some('"'), '\\') => match chars.peek() {
+                Some('$') | Some('`') | Some('"') | Some('\\') => out.extend(chars.next()),
+                _ => out.push('\\'),
+            },
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Quotes a word the way its first part was quoted, leaving the quote open.
+fn quote_word(text: &str, quote: Option<char>) -> String {
+    match quote {
+        Some('\'') => format!("'{}", text.replace('\'', "'\\''")),
+        Some(q) => {
+            let mut out = q.to_string();
+            for c in text.chars() {
+                if "$`\"\\".contains(c) {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out
+        }
+        None => {
+            let mut out = String::new();
+            for (i, c) in text.char_indices() {
+                let tilde = i == 0 && c == '~';
+                if SPECIAL.contains(c) && !tilde {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out
+        }
+    }
+}
+
+fn longest_common_prefix(candidates: &[Candidate]) -> &str {
+    let first = &candidates[0].text;
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first
+            .char_indices()
+            .zip(candidate.text.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.text.len()), |((i, _), _)| i.min(len));
+    }
+    &first[..len]
+}
+
+/// Turns the candidates for a word into what Tab does: complete a unique candidate, extend the
+/// word to the candidates' common prefix, or list them.
+fn finish(
+    start: usize,
+    prefix: &str,
+    mut candidates: Vec<Candidate>,
+    quote: &dyn Fn(&str) -> String,
+    end: &str,
+) -> Completion {
+    candidates.sort();
+    candidates.dedup();
+    let replacement = match candidates.len() {
+        0 => None,
+        1 if candidates[0].partial => Some(quote(&candidates[0].text)),
+        1 => Some(format!("{}{}", quote(&candidates[0].text), end)),
+        _ => {
+            let common = longest_common_prefix(&candidates);
+            match common.len() > prefix.len() {
+                true => Some(quote(common)),
+                false => {
+                    return Completion {
+                        start,
+                        replacement: None,
+                        matches: candidates.into_iter().map(|c| c.display).collect(),
+                    }
+                }
+            }
+        }
+    };
+    Completion {
+        start,
+        replacement,
+        matches: vec![],
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Completes a variable name if the word ends in `$name` or `${name`.
+fn complete_variable(state: &State, word: &str, start: usize) -> Option<Completion> {
+    let dollar = word.rfind('$')?;
+    if word[..dollar].ends_with('\\') {
+        return None;
+    }
+    let after = &word[dollar + 1..];
+    let braced = after.starts_with('{');
+    let name = if braced { &after[1..] } else { after };
+    if !name.chars().all(is_name_char) {
+        return None;
+    }
+    let candidates = state
+        .var_names()
+        .filter(|v| v.starts_with(name))
+        .map(|v| Candidate {
+            text: v.to_string(),
+            display: v.to_string(),
+            partial: false,
+        })
+        .collect();
+    let name_start = start + dollar + 1 + braced as usize;
+    let end = if braced { "}" } else { " " };
+    Some(finish(
+        name_start,
+        name,
+        candidates,
+        &|t| t.to_string(),
+        end,
+    ))
+}
+
+/// Candidates for the names that start with `prefix`.
+fn named(names: impl Iterator<Item = String>, prefix: &str) -> Vec<Candidate> {
+    names
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| Candidate {
+            display: name.clone(),
+            text: name,
+            partial: false,
+        })
+        .collect()
+}
+
+fn command_candidates(state: &State, cache: &mut CommandCache, prefix: &str) -> Vec<Candidate> {
+    let path = state.get_var("PATH").unwrap_or_default().to_string();
+    let builtins = builtins::NAMES.iter().map(|b| b.to_string());
+    let registered = state.builtins.keys().cloned();
+    let aliases = state.aliases.keys().cloned();
+    let functions = state.functions.keys().cloned();
+    let commands = cache.commands(&path).iter().cloned();
+    named(
+        aliases
+            .chain(builtins)
+            .chain(registered)
+            .chain(functions)
+            .chain(commands),
+        prefix,
+    )
+}
+
+/// Completes a path. Directories end in `/` and are partial, so the path can go on.
+fn file_candidates(state: &State, prefix: &str, executables_only: bool) -> Vec<Candidate> {
+    let (dir, base) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+    let search = match dir.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", state.get_var("HOME").unwrap_or_default(), rest),
+        None if dir.is_empty() => ".".to_string(),
+        None => dir.to_string(),
+    };
+    let entries = match fs::read_dir(&search) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(base) || (name.starts_with('.') && !base.starts_with('.')) {
+                return None;
+            }
+            let path = entry.path();
+            let is_dir = fs::metadata(&path).is_ok_and(|m| m.is_dir());
+            if executables_only && !is_dir && !is_executable(&path) {
+                return None;
+            }
+            let slash = if is_dir { "/" } else { "" };
+            Some(Candidate {
+                text: format!("{}{}{}", dir, name, slash),
+                display: format!("{}{}", name, slash),
+                partial: is_dir,
+            })
+        })
+        .collect()
+}
+
+/// A kind of word that `complete` and `compgen` can generate, named as for their `-A` option.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    Alias,
+    Builtin,
+    Command,
+    Directory,
+    File,
+    Function,
+    Variable,
+}
+
+impl Action {
+    fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "alias" => Some(Action::Alias),
+            "builtin" => Some(Action::Builtin),
+            "command" => Some(Action::Command),
+            "directory" => Some(Action::Directory),
+            "file" => Some(Action::File),
+            "function" => Some(Action::Function),
+            "variable" => Some(Action::Variable),
+            _ => None,
+        }
+    }
+
+    fn from_flag(flag: char) -> Option<Action> {
+        match flag {
+            'a' => Some(Action::Alias),
+            'b' => Some(Action::Builtin),
+            'c' => Some(Action::Command),
+            'd' => Some(Action::Directory),
+            'f' => Some(Action::File),
+            'v' => Some(Action::Variable),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::Alias => "alias",
+            Action::Builtin => "builtin",
+            Action::Command => "command",
+            Action::Directory => "directory",
+            Action::File => "file",
+            Action::Function => "function",
+            Action::Variable => "variable",
+        }
+    }
+
+    fn candidates(self, state: &State, cache: &mut CommandCache, prefix: &str) -> Vec<Candidate> {
+        match self {
+            Action::Alias => named(state.aliases.keys().cloned(), prefix),
+            Action::Builtin => named(builtins::NAMES.iter().map(|b| b.to_string()), prefix),
+            Action::Command => command_candidates(state, cache, prefix),
+            Action::Directory => file_candidates(state, prefix, false)
+                .into_iter()
+                .filter(|c| c.partial)
+                .collect(),
+            Action::File => file_candidates(state, prefix, false),
+            Action::Function => named(state.functions.keys().cloned(), prefix),
+            Action::Variable => named(state.var_names().map(|v| v.to_string()), prefix),
+        }
+    }
+}
+
+/// How to complete the arguments of a command, as set up by `complete`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CompSpec {
+    pub actions: Vec<Action>,
+    /// `-W`: a word list, expanded and split into candidates when completing.
+    pub words: Option<String>,
+    /// `-F`: a function that leaves its candidates in `COMPREPLY`.
+    pub function: Option<String>,
+    /// `-C`: a command whose output lines are the candidates.
+    pub command: Option<String>,
+    /// `-o default`: complete file names when the spec generates nothing.
+    pub default: bool,
+    /// `-o nospace`: don't add a space after a unique completion.
+    pub nospace: bool,
+}
+
+impl fmt::Display for CompSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut options = vec![];
+        if self.default {
+            options.push("-o default".to_string());
+        }
+        if self.nospace {
+            options.push("-o nospace".to_string());
+        }
+        for action in &self.actions {
+            options.push(format!("-A {}", action.name()));
+        }
+        if let Some(words) = &self.words {
+            options.push(format!("-W {}", quote(words)));
+        }
+        if let Some(function) = &self.function {
+            options.push(format!("-F {}", function));
+        }
+        if let Some(command) = &self.command {
+            options.push(format!("-C {}", quote(command)));
+        }
+        write!(f, "{}", options.join(" "))
+    }
+}
+
+/// The options and operands of a `complete` or `compgen` command.
+#[derive(Debug, Default)]
+pub struct SpecOptions {
+    pub spec: CompSpec,
+    /// `-p`: print specs instead of setting them.
+    pub print: bool,
+    /// `-r`: remove specs.
+    pub remove: bool,
+    pub operands: Vec<String>,
+}
+
+/// Parses the options shared by `complete` and `compgen`. Options that take an argument accept
+/// it in the same word, as in `-Wwords`, or in the next one.
+pub fn parse_options(args: &[String]) -> Result<SpecOptions, String> {
+    let mut options = SpecOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            options.operands.push(arg.clone());
+            break;
+        }
+        for (i, flag) in arg.char_indices().skip(1) {
+            if let Some(action) = Action::from_flag(flag) {
+                options.spec.actions.push(action);
+                continue;
+            }
+            match flag {
+                'p' => options.print = true,
+                'r' => options.remove = true,
+                'A' | 'C' | 'F' | 'W' | 'o' => {
+                    let value = match &arg[i + 1..] {
+                        "" => args
+                            .next()
+                            .ok_or(format!("-{}: option requires an argument", flag))?
+                            .clone(),
+                        rest => rest.to_string(),
+                    };
+                    match flag {
+                        'A' => options.spec.actions.push(
+                            Action::from_name(&value)
+                                .ok_or(format!("{}: invalid action name", value))?,
+                        ),
+                        'C' => options.spec.command = Some(value),
+                        'F' => options.spec.function = Some(value),
+                        'W' => options.spec.words = Some(value),
+                        _ => match value.as_str() {
+                            "default" => options.spec.default = true,
+                            "nospace" => options.spec.nospace = true,
+                            _ => return Err(format!("{}: invalid option name", value)),
+                        },
+                    }
+                    break;
+                }
+                _ => return Err(format!("-{}: invalid option", flag)),
+            }
+        }
+    }
+    options.operands.extend(args.cloned());
+    Ok(options)
+}
+
+/// The command line around the word being completed, which `-F` functions see in `COMP_WORDS`,
+/// `COMP_CWORD`, `COMP_LINE` and `COMP_POINT`, and `-C` commands in their environment.
+#[derive(Debug, Default)]
+pub struct Context {
+    /// The words of the command being completed, in source form, including those after the
+    /// cursor.
+    pub words: Vec<String>,
+    /// The index in `words` of the word at the cursor.
+    pub cword: usize,
+    pub line: String,
+    pub point: usize,
+}
+
+impl Context {
+    /// The command name, the word being completed and the word before it, which are the
+    /// arguments of `-F` functions and `-C` commands.
+    fn arguments(&self, word: &str) -> String {
+        let at = |i: usize| self.words.get(i).map_or("", |w| w.as_str());
+        let prev = if self.cword == 0 {
+            ""
+        } else {
+            at(self.cword - 1)
+        };
+        format!(
+            "{} {} {}",
+            quote(&unquote(at(0))),
+            quote(word),
+            quote(&unquote(prev))
+        )
+    }
+
+    fn set_vars(&self, state: &mut State) {
+        state.set_array("COMP_WORDS", self.words.clone());
+        state.set_var("COMP_CWORD", &self.cword.to_string());
+        state.set_var("COMP_LINE", &self.line);
+        state.set_var("COMP_POINT", &self.point.to_string());
+    }
+}
+
+const CONTEXT_VARS: &[&str] = &["COMP_WORDS", "COMP_CWORD", "COMP_LINE", "COMP_POINT"];
+
+/// Calls a `-F` function and returns what it left in `COMPREPLY`. The function runs without
+/// standard streams, since the line being edited is on the terminal.
+fn call_function(state: &mut State, function: &str, word: &str, context: &Context) -> Vec<String> {
+    context.set_vars(state);
+    state.unset_var("COMPREPLY");
+    let input = format!("{} {}", function, context.arguments(word));
+    run_string(state, &input, StreamSet::new(None, None, None));
+    for name in CONTEXT_VARS {
+        state.unset_var(name);
+    }
+    state.elements("COMPREPLY")
+}
+
+/// Runs a `-C` command and returns the lines it printed.
+fn run_command(state: &mut State, command: &str, word: &str, context: &Context) -> Vec<String> {
+    context.set_vars(state);
+    state.export_var("COMP_LINE");
+    state.export_var("COMP_POINT");
+    let output = capture_output(state, &format!("{} {}", command, context.arguments(word)));
+    for name in CONTEXT_VARS {
+        state.unset_var(name);
+    }
+    output.lines().map(|line| line.to_string()).collect()
+}
+
+/// The candidates that `spec` generates for `word`, the unquoted word being completed. Actions
+/// and `-W` words are filtered by `word`; what `-F` functions and `-C` commands produce is
+/// taken as is. The exit status of the last command is left alone.
+fn generate(
+    state: &mut State,
+    cache: &mut CommandCache,
+    spec: &CompSpec,
+    word: &str,
+    context: &Context,
+) -> Vec<Candidate> {
+    let status = state.last_status;
+    let mut candidates = vec![];
+    for action in &spec.actions {
+        candidates.extend(action.candidates(state, cache, word));
+    }
+    if let Some(words) = &spec.words {
+        let words = expand_string(state, words).unwrap_or_default();
+        candidates.extend(named(words.split_whitespace().map(String::from), word));
+    }
+    let mut generated = vec![];
+    if let Some(function) = &spec.function {
+        generated.extend(call_function(state, function, word, context));
+    }
+    if let Some(command) = &spec.command {
+        generated.extend(run_command(state, command, word, context));
+    }
+    candidates.extend(named(generated.into_iter(), ""));
+    state.last_status = status;
+    candidates
+}
+
+/// The words `compgen` prints for `spec` and `word`.
+pub fn compgen(state: &mut State, spec: &CompSpec, word: &str) -> Vec<String> {
+    let context = Context {
+        words: vec![String::new(), word.to_string()],
+        cword: 1,
+        ..Context::default()
+    };
+    let mut candidates = generate(state, &mut CommandCache::new(), spec, word, &context);
+    candidates.sort();
+    candidates.dedup();
+    candidates.into_iter().map(|c| c.text).collect()
+}
+
+/// Whether `token` ends the command before it, so that the words of the command being completed
+/// start after it.
+fn separates_commands(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Semicolon
+            | Token::LogAnd
+            | Token::LogOr
+            | Token::Pipe
+            | Token::Fork
+            | Token::LParen
+            | Token::RParen
+            | Token::LBrace
+            | Token::RBrace
+    )
+}
+
+/// Runs the completion spec for the command at `start`, if it has one. Returns the candidates
+/// and whether to leave out the space after a unique one, or `None` to complete as usual.
+fn complete_programmable(
+    state: &mut State,
+    cache: &mut CommandCache,
+    line: &str,
+    cursor: usize,
+    start: usize,
+) -> Option<(Vec<Candidate>, bool)> {
+    let tokens = lex(&line[..start]).ok()?;
+    let command_start = tokens
+        .iter()
+        .rposition(separates_commands)
+        .map_or(0, |i| i + 1);
+    let mut words: Vec<String> = tokens[command_start..]
+        .iter()
+        .filter_map(|t| match t {
+            Token::Text(word) => Some(word.clone()),
+            _ => None,
+        })
+        .collect();
+    let cword = words.len();
+    let name = unquote(words.first()?);
+    let basename = name.rsplit('/').next().unwrap_or_default();
+    let spec = state
+        .completions
+        .get(&name)
+        .or_else(|| state.completions.get(basename))?
+        .clone();
+    // The word at the cursor may go on past it, and the command may have more words after it.
+    words.push(String::new());
+    for (token, span) in lex_spans(&line[start..]).0 {
+        match token {
+            token if separates_commands(&token) => break,
+            Token::Text(word) if span.start == 0 => words[cword] = word,
+            Token::Text(word) => words.push(word),
+            _ => {}
+        }
+    }
+    let word = &line[start..cursor];
+    let context = Context {
+        words,
+        cword,
+        line: line.to_string(),
+        point: cursor,
+    };
+    let candidates = generate(state, cache, &spec, &unquote(word), &context);
+    if candidates.is_empty() && spec.default {
+        return None;
+    }
+    Some((candidates, spec.nospace))
+}
+
+/// Completes the word before `cursor`. The tokens before the word tell whether it names a
+/// command, or is an argument or redirection target, which complete as file names unless the
+/// command has a completion spec. A word ending in `$name` completes as a variable.
+pub fn complete(
+    state: &mut State,
+    cache: &mut CommandCache,
+    line: &str,
+    cursor: usize,
+) -> Completion {
+    let before = &line[..cursor];
+    let (start, quote) = current_word(before);
+    let word = &before[start..];
+    if quote != Some('\'') {
+        if let Some(completion) = complete_variable(state, word, start) {
+            return completion;
+        }
+    }
+    let position = match lex(&before[..start]) {
+        Ok(tokens) => next_word_position(&tokens),
+        Err(_) => WordPosition::Argument,
+    };
+    let prefix = unquote(word);
+    let mut nospace = false;
+    let candidates = match position {
+        WordPosition::Command if !prefix.contains('/') => command_candidates(state, cache, &prefix),
+        WordPosition::Command => file_candidates(state, &prefix, true),
+        WordPosition::Argument => match complete_programmable(state, cache, line, cursor, start) {
+            Some((candidates, no_space)) => {
+                nospace = no_space;
+                candidates
+            }
+            None => file_candidates(state, &prefix, false),
+        },
+        WordPosition::RedirectTarget => file_candidates(state, &prefix, false),
+    };
+    let space = if nospace { "" } else { " " };
+    let end = match quote {
+        Some(q) => format!("{}{}", q, space),
+        None => space.to_string(),
+    };
+    finish(
+        start,
+        &prefix,
+        candidates,
+        &|text| quote_word(text, quote),
+        &end,
+    )
+}
+
+/// Completes words for the line editor against the shell's current state.
+pub struct Completer<'a> {
+    pub state: &'a mut State,
+    pub cache: &'a mut CommandCache,
+    pub suggester: &'a Suggester,
+}
+
+impl Helper for Completer<'_> {
+    fn complete(&mut self, line: &str, cursor: usize) -> Completion {
+        complete(self.state, self.cache, line, cursor)
+    }
+
+    fn highlight(&mut self, line: &str) -> String {
+        highlight::highlight(self.state, self.cache, line)
+    }
+
+    fn suggest(&mut self, line: &str, history: &[String]) -> Option<String> {
+        self.suggester
+            .suggest(self.state, self.cache, history, line)
+    }
+}
+
+#[cfg(test)]
+fn complete_line(state: &mut State, line: &str) -> Completion {
+    complete(state, &mut CommandCache::new(), line, line.len())
+}
+
+#[cfg(test)]
+fn replacement(state: &mut State, line: &str) -> Option<String> {
+    complete_line(state, line).replacement
+}
+
+#[cfg(test)]
+fn scratch_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("traash-{}-{}", name, nix::unistd::getpid()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+#[test]
+fn completes_commands_in_command_position() {
+    let dir = scratch_dir("commands");
+    fs::write(format!("{}/traash-tool", dir), "").unwrap();
+    fs::set_permissions(
+        format!("{}/traash-tool", dir),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    fs::write(format!("{}/traash-data", dir), "").unwrap();
+    let mut state = State::new();
+    state.set_var("PATH", &dir);
+    state
+        .functions
+        .insert("my_func".to_string(), crate::ast::Command::Nil);
+    state
+        .aliases
+        .insert("my_alias".to_string(), "ls".to_string());
+
+    assert_eq!(
+        replacement(&mut state, "my_f"),
+        Some("my_func ".to_string())
+    );
+    assert_eq!(
+        replacement(&mut state, "my_a"),
+        Some("my_alias ".to_string())
+    );
+    assert_eq!(
+        replacement(&mut state, "ls | hist"),
+        Some("history ".to_string())
+    );
+    assert_eq!(
+        replacement(&mut state, "traash-"),
+        Some("traash-tool ".to_string())
+    );
+    assert_eq!(replacement(&mut state, "echo traash-"), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completes_and_escapes_file_names() {
+    let dir = scratch_dir("files");
+    fs::write(format!("{}/my file.txt", dir), "").unwrap();
+    fs::write(format!("{}/my other", dir), "").unwrap();
+    fs::create_dir(format!("{}/sub", dir)).unwrap();
+    let mut state = State::new();
+
+    let line = format!("cat {}/my\\ f", dir);
+    let expected = format!("{}/my\\ file.txt ", dir);
+    assert_eq!(replacement(&mut state, &line), Some(expected));
+    let line = format!("cat \"{}/my f", dir);
+    let expected = format!("\"{}/my file.txt\" ", dir);
+    assert_eq!(replacement(&mut state, &line), Some(expected));
+    let line = format!("cat {}/m", dir);
+    let expected = format!("{}/my\\ ", dir);
+    assert_eq!(replacement(&mut state, &line), Some(expected));
+    let completion = complete_line(&mut state, &format!("cat {}/my\\ ", dir));
+    assert_eq!(completion.matches, vec!["my file.txt", "my other"]);
+    let line = format!("echo hi >{}/s", dir);
+    let expected = format!("{}/sub/", dir);
+    assert_eq!(replacement(&mut state, &line), Some(expected));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completes_variable_names() {
+    let mut state = State::new();
+    state.set_var("HOME", "/home/me");
+    state.set_var("HOSTNAME", "box");
+    state.set_var("PATH", "/bin");
+
+    let completion = complete_line(&mut state, "echo $HOM");
+    assert_eq!(completion.start, 6);
+    assert_eq!(completion.replacement, Some("HOME ".to_string()));
+    assert_eq!(
+        replacement(&mut state, "echo \"${PA"),
+        Some("PATH}".to_string())
+    );
+    assert_eq!(replacement(&mut state, "echo $H"), Some("HO".to_string()));
+    assert_eq!(
+        complete_line(&mut state, "echo $HO").matches,
+        vec!["HOME", "HOSTNAME"]
+    );
+}
+
+#[test]
+fn parses_and_prints_specs() {
+    let args: Vec<String> = ["-o", "nospace", "-W", "a b", "-Fhelper", "-d", "git", "tig"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let options = parse_options(&args).unwrap();
+    assert_eq!(options.operands, vec!["git", "tig"]);
+    assert_eq!(
+        options.spec.to_string(),
+        "-o nospace -A directory -W 'a b' -F helper"
+    );
+    assert!(parse_options(&["-x".to_string()]).is_err());
+    assert!(parse_options(&["-W".to_string()]).is_err());
+}
+
+#[test]
+fn compgen_filters_words_and_actions() {
+    let mut state = State::new();
+    state.set_var("alpha_var", "1");
+    let output = capture_output(&mut state, "compgen -W 'alpha beta alps' al");
+    assert_eq!(output, "alpha\nalps");
+    assert_eq!(capture_output(&mut state, "compgen -v alpha"), "alpha_var");
+    assert_eq!(
+        capture_output(&mut state, "compgen -b comp"),
+        "compgen\ncomplete"
+    );
+}
+
+#[test]
+fn completes_arguments_with_specs() {
+    let mut state = State::new();
+    let streams = StreamSet::std();
+    run_string(
+        &mut state,
+        "_tool() { COMPREPLY=(\"$COMP_CWORD:${COMP_WORDS[0]}:$2:$3\"); }",
+        streams,
+    );
+    run_string(&mut state, "complete -F _tool tool", streams);
+    run_string(&mut state, "complete -W 'start stop status' svc", streams);
+    run_string(&mut state, "complete -C echo echoer", streams);
+    run_string(&mut state, "complete -o nospace -W key= cfg", streams);
+
+    let result = replacement(&mut state, "tool x ab");
+    assert_eq!(result, Some("2:tool:ab:x ".to_string()));
+    assert_eq!(state.get_var("COMP_LINE"), None);
+    assert_eq!(
+        replacement(&mut state, "svc sto"),
+        Some("stop ".to_string())
+    );
+    let completion = complete_line(&mut state, "ls; svc st");
+    assert_eq!(completion.matches, vec!["start", "status", "stop"]);
+    let result = replacement(&mut state, "echoer a b");
+    assert_eq!(result, Some("echoer\\ b\\ a ".to_string()));
+    assert_eq!(replacement(&mut state, "cfg k"), Some("key=".to_string()));
+}
+
+#[test]
+fn functions_see_the_whole_command_line() {
+    let mut state = State::new();
+    let streams = StreamSet::std();
+    run_string(
+        &mut state,
+        "_tool() { echo noise; COMPREPLY=(\"$COMP_CWORD-${COMP_WORDS[*]}-$2\"); }",
+        streams,
+    );
+    run_string(&mut state, "complete -F _tool tool", streams);
+
+    let line = "tool ab cd; ls";
+    let completion = complete(&mut state, &mut CommandCache::new(), line, 6);
+    assert_eq!(
+        completion.replacement,
+        Some("1-tool\\ ab\\ cd-a ".to_string())
+    );
+}
@@ -0,0 +1,459 @@
+use std::ffi::CString;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::{fs, io};
+
+use nix::libc;
+use nix::unistd::{access, isatty, AccessFlags, Gid, Uid};
+
+use crate::arith;
+use crate::ast::CondExpr;
+use crate::expand::{expand_pattern, expand_regex, expand_string, is_name};
+use crate::pattern;
+use crate::state::State;
+
+/// A malformed test expression, or an operand of the wrong kind. Either makes the test exit
+/// with status 2.
+#[derive(Debug, Eq, PartialEq)]
+pub struct TestError(pub String);
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub fn is_unary_operator(op: &str) -> bool {
+    matches!(
+        op,
+        "-a" | "-b"
+            | "-c"
+            | "-d"
+            | "-e"
+            | "-f"
+            | "-g"
+            | "-h"
+            | "-k"
+            | "-L"
+            | "-n"
+            | "-O"
+            | "-G"
+            | "-p"
+            | "-r"
+            | "-s"
+            | "-S"
+            | "-t"
+            | "-u"
+            | "-v"
+            | "-w"
+            | "-x"
+            | "-z"
+    )
+}
+
+pub fn is_binary_operator(op: &str) -> bool {
+    matches!(
+        op,
+        "=" | "=="
+            | "!="
+            | "=~"
+            | "<"
+            | ">"
+            | "-eq"
+            | "-ne"
+            | "-lt"
+            | "-le"
+            | "-gt"
+            | "-ge"
+            | "-nt"
+            | "-ot"
+            | "-ef"
+    )
+}
+
+fn metadata(path: &str, follow: bool) -> io::Result<fs::Metadata> {
+    match follow {
+        true => fs::metadata(path),
+        false => fs::symlink_metadata(path),
+    }
+}
+
+/// Evaluates a unary operator on an expanded operand.
+fn unary_test(state: &State, op: &str, operand: &str) -> bool {
+    let permission = |flag| access(operand, flag).is_ok();
+    let file = |test: fn(&fs::Metadata) -> bool| metadata(operand, true).is_ok_and(|m| test(&m));
+    match op {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-v" => is_name(operand) && state.get_var(operand).is_some(),
+        "-t" => operand
+            .parse::<i32>()
+            .is_ok_and(|fd| isatty(fd).unwrap_or(false)),
+        "-r" => permission(AccessFlags::R_OK),
+        "-w" => permission(AccessFlags::W_OK),
+        "-x" => permission(AccessFlags::X_OK),
+        "-h" | "-L" => metadata(operand, false).is_ok_and(|m| m.file_type().is_symlink()),
+        "-a" | "-e" => file(|_| true),
+        "-f" => file(|m| m.is_file()),
+        "-d" => file(|m| m.is_dir()),
+        "-s" => file(|m| m.len() > 0),
+        "-b" => file(|m| m.file_type().is_block_device()),
+        "-c" => file(|m| m.file_type().is_char_device()),
+        "-p" => file(|m| m.file_type().is_fifo()),
+        "-S" => file(|m| m.file_type().is_socket()),
+        "-u" => file(|m| m.permissions().mode() & 0o4000 != 0),
+        "-g" => file(|m| m.permissions().mode() & 0o2000 != 0),
+        "-k" => file(|m| m.permissions().mode() & 0o1000 != 0),
+        "-O" => file(|m| m.uid() == Uid::effective().as_raw()),
+        "-G" => file(|m| m.gid() == Gid::effective().as_raw()),
+        _ => false,
+    }
+}
+
+/// Evaluates `-nt`, `-ot` or `-ef` on two paths.
+fn file_comparison(left: &str, op: &str, right: &str) -> bool {
+    let (left, right) = (fs::metadata(left).ok(), fs::metadata(right).ok());
+    let mtime = |m: &fs::Metadata| (m.mtime(), m.mtime_nsec());
+    match (op, left, right) {
+        ("-nt", Some(l), Some(r)) => mtime(&l) > mtime(&r),
+        ("-nt", Some(_), None) => true,
+        ("-ot", Some(l), Some(r)) => mtime(&l) < mtime(&r),
+        ("-ot", None, Some(_)) => true,
+        ("-ef", Some(l), Some(r)) => l.dev() == r.dev() && l.ino() == r.ino(),
+        _ => false,
+    }
+}
+
+fn compare_integers(left: i64, op: &str, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn parse_integer(operand: &str) -> Result<i64, TestError> {
+    operand
+        .trim()
+        .parse::<i64>()
+        .map_err(|_| TestError(format!("{}: integer expression expected", operand)))
+}
+
+/// Evaluates a binary operator the way `test` does, where every operand is a plain string.
+fn binary_test(left: &str, op: &str, right: &str) -> Result<bool, TestError> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-nt" | "-ot" | "-ef" => file_comparison(left, op, right),
+        _ => compare_integers(parse_integer(left)?, op, parse_integer(right)?),
+    })
+}
+
+/// Evaluates the arguments of `test` or `[`, following POSIX's rules for up to four arguments
+/// and the usual precedence (`!`, then `-a`, then `-o`) beyond that.
+pub fn test(state: &State, args: &[String]) -> Result<bool, TestError> {
+    let mut parser = TestParser { state, args };
+    let result = parser.by_count(args.len())?;
+    match parser.args.first() {
+        None => Ok(result),
+        Some(extra) if args.len() > 4 => Err(TestError(format!("{}: unexpected argument", extra))),
+        Some(_) => Err(TestError("too many arguments".to_string())),
+    }
+}
+
+struct TestParser<'a> {
+    state: &'a State,
+    args: &'a [String],
+}
+
+impl<'a> TestParser<'a> {
+    fn peek(&self, i: usize) -> Option<&'a str> {
+        self.args.get(i).map(|a| a.as_str())
+    }
+
+    fn take(&mut self, n: usize) {
+        self.args = &self.args[n..];
+    }
+
+    /// Applies the POSIX rules that decide how to read `n` arguments from their count alone.
+    fn by_count(&mut self, n: usize) -> Result<bool, TestError> {
+        match (n, self.peek(0), self.peek(1)) {
+            (0, _, _) => Ok(false),
+            (1, Some(arg), _) => {
+                self.take(1);
+                Ok(!arg.is_empty())
+            }
+            (2, Some("!"), _) | (3, Some("!"), _) | (4, Some("!"), _)
+                if !(n == 3 && self.peek(1).is_some_and(is_binary_operator)) =>
+            {
+                self.take(1);
+                Ok(!self.by_count(n - 1)?)
+            }
+            (2, Some(op), Some(operand)) => {
+                if !is_unary_operator(op) {
+                    return Err(TestError(format!("{}: unary operator expected", op)));
+                }
+                self.take(2);
+                Ok(unary_test(self.state, op, operand))
+            }
+            (3, _, Some(op)) if is_binary_operator(op) && op != "=~" => self.binary(),
+            (3, Some(left), Some(op)) if op == "-a" || op == "-o" => {
+                let (left, right) = (!left.is_empty(), !self.args[2].is_empty());
+                self.take(3);
+                Ok(if op == "-a" {
+                    left && right
+                } else {
+                    left || right
+                })
+            }
+            (3, Some("("), _) | (4, Some("("), _) if self.peek(n - 1) == Some(")") => {
+                self.take(1);
+                let result = self.by_count(n - 2)?;
+                self.take(1);
+                Ok(result)
+            }
+            _ => self.or(),
+        }
+    }
+
+    fn binary(&mut self) -> Result<bool, TestError> {
+        let args = self.args;
+        let (left, op, right) = (&args[0], &args[1], &args[2]);
+        self.take(3);
+        binary_test(left, op, right)
+    }
+
+    fn or(&mut self) -> Result<bool, TestError> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.take(1);
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, TestError> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.take(1);
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, TestError> {
+        match self.peek(0) {
+            Some("!") => {
+                self.take(1);
+                Ok(!self.not()?)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<bool, TestError> {
+        match (self.peek(0), self.peek(1), self.peek(2)) {
+            (None, _, _) => Err(TestError("argument expected".to_string())),
+            (Some(_), Some(op), Some(_)) if is_binary_operator(op) && op != "=~" => self.binary(),
+            (Some("("), _, _) => {
+                self.take(1);
+                let result = self.or()?;
+                match self.peek(0) {
+                    Some(")") => {
+                        self.take(1);
+                        Ok(result)
+                    }
+                    _ => Err(TestError("`)' expected".to_string())),
+                }
+            }
+            (Some(op), Some(operand), _) if is_unary_operator(op) => {
+                let result = unary_test(self.state, op, operand);
+                self.take(2);
+                Ok(result)
+            }
+            (Some(arg), _, _) => {
+                self.take(1);
+                Ok(!arg.is_empty())
+            }
+        }
+    }
+}
+
+/// Matches `text` against a POSIX extended regular expression, returning the whole match and
+/// each parenthesized group if it matches.
+fn regex_match(regex: &str, text: &str) -> Result<Option<Vec<String>>, TestError> {
+    let invalid = || TestError(format!("{}: invalid regular expression", regex));
+    let c_regex = CString::new(regex).map_err(|_| invalid())?;
+    let c_text = match CString::new(text) {
+        Ok(text) => text,
+        Err(_) => return Ok(None),
+    };
+    // The number of groups isn't exposed by libc's regex_t, but can't exceed the number of `(`.
+    let groups = regex.matches('(').count() + 1;
+    let mut matches = vec![
+        libc::regmatch_t {
+            rm_so: -1,
+            rm_eo: -1
+        };
+        groups
+    ];
+    unsafe {
+        let mut compiled = MaybeUninit::<libc::regex_t>::zeroed();
+        if libc::regcomp(compiled.as_mut_ptr(), c_regex.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(invalid());
+        }
+        let status = libc::regexec(
+            compiled.as_ptr(),
+            c_text.as_ptr(),
+            groups,
+            matches.as_mut_ptr(),
+            0,
+        );
+        libc::regfree(compiled.as_mut_ptr());
+        if status != 0 {
+            return Ok(None);
+        }
+    }
+    let groups = matches
+        .iter()
+        .map(|m| match m.rm_so {
+            -1 => String::new(),
+            _ => String::from_utf8_lossy(&text.as_bytes()[m.rm_so as usize..m.rm_eo as usize])
+                .into_owned(),
+        })
+        .collect();
+    Ok(Some(groups))
+}
+
+fn expand(state: &mut State, word: &str) -> Result<String, TestError> {
+    expand_string(state, word).map_err(|e| TestError(e.to_string()))
+}
+
+fn arithmetic(state: &mut State, word: &str) -> Result<i64, TestError> {
+    let expr = expand(state, word)?;
+    arith::evaluate(state, &expr).map_err(|e| TestError(format!("{}: {}", expr.trim(), e)))
+}
+
+/// Evaluates a `[[ ... ]]` expression. Operands are expanded without field splitting, the right
+/// side of `==` and `!=` is a pattern, `=~` matches an extended regular expression and fills in
+/// `BASH_REMATCH`, and integer comparisons take arithmetic expressions.
+pub fn evaluate(state: &mut State, expr: &CondExpr) -> Result<bool, TestError> {
+    match expr {
+        CondExpr::Word(word) => Ok(!expand(state, word)?.is_empty()),
+        CondExpr::Unary(op, operand) => {
+            let operand = expand(state, operand)?;
+            Ok(unary_test(state, op, &operand))
+        }
+        CondExpr::Binary(left, op, right) => {
+            let left_value = expand(state, left)?;
+            match op.as_str() {
+                "=" | "==" | "!=" => {
+                    let pattern =
+                        expand_pattern(state, right).map_err(|e| TestError(e.to_string()))?;
+                    Ok(pattern::matches(&pattern, &left_value) == (op != "!="))
+                }
+                "=~" => {
+                    let regex = expand_regex(state, right).map_err(|e| TestError(e.to_string()))?;
+                    let matched = regex_match(&regex, &left_value)?;
+                    let found = matched.is_some();
+                    state.set_array("BASH_REMATCH", matched.unwrap_or_default());
+                    Ok(found)
+                }
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let left = arithmetic(state, left)?;
+                    let right = arithmetic(state, right)?;
+                    Ok(compare_integers(left, op, right))
+                }
+                _ => binary_test(&left_value, op, &expand(state, right)?),
+            }
+        }
+        CondExpr::Not(inner) => Ok(!evaluate(state, inner)?),
+        CondExpr::And(left, right) => Ok(evaluate(state, left)? && evaluate(state, right)?),
+        CondExpr::Or(left, right) => Ok(evaluate(state, left)? || evaluate(state, right)?),
+    }
+}
+
+#[cfg(test)]
+fn test_words(state: &State, args: &[&str]) -> Result<bool, TestError> {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    test(state, &args)
+}
+
+#[test]
+fn test_follows_posix_argument_rules() {
+    let state = State::new();
+
+    assert_eq!(test_words(&state, &[]), Ok(false));
+    assert_eq!(test_words(&state, &["-n"]), Ok(true));
+    assert_eq!(test_words(&state, &["!", ""]), Ok(true));
+    assert_eq!(test_words(&state, &["-z", ""]), Ok(true));
+    assert_eq!(test_words(&state, &["!", "=", "!"]), Ok(true));
+    assert_eq!(test_words(&state, &["(", "-z", ")"]), Ok(true));
+    assert_eq!(test_words(&state, &["-f", "-a", ""]), Ok(false));
+    assert_eq!(test_words(&state, &["!", "a", "=", "b"]), Ok(true));
+    assert!(test_words(&state, &["x", "y"]).is_err());
+}
+
+#[test]
+fn test_compares_strings_and_integers() {
+    let state = State::new();
+
+    assert_eq!(test_words(&state, &["abc", "<", "abd"]), Ok(true));
+    assert_eq!(test_words(&state, &["10", "-gt", "9"]), Ok(true));
+    assert_eq!(test_words(&state, &[" 3", "-eq", "3"]), Ok(true));
+    assert_eq!(
+        test_words(&state, &["x", "-eq", "1"]),
+        Err(TestError("x: integer expression expected".to_string()))
+    );
+}
+
+#[test]
+fn test_combines_with_and_or_and_parentheses() {
+    let state = State::new();
+
+    let args = ["a", "=", "b", "-o", "1", "-lt", "2", "-a", "!", "-z", "x"];
+    assert_eq!(test_words(&state, &args), Ok(true));
+    let args = ["(", "a", "=", "b", "-o", "c", ")", "-a", ""];
+    assert_eq!(test_words(&state, &args), Ok(false));
+}
+
+#[test]
+fn test_checks_files() {
+    let state = State::new();
+
+    assert_eq!(test_words(&state, &["-d", "/"]), Ok(true));
+    assert_eq!(test_words(&state, &["-f", "/"]), Ok(false));
+    assert_eq!(test_words(&state, &["-e", "/nonexistent/file"]), Ok(false));
+    assert_eq!(
+        test_words(&state, &["-f", "Cargo.toml", "-a", "-s", "Cargo.toml"]),
+        Ok(true)
+    );
+    assert_eq!(
+        test_words(&state, &["Cargo.toml", "-nt", "/nonexistent"]),
+        Ok(true)
+    );
+    assert_eq!(
+        test_words(&state, &["Cargo.toml", "-ef", "./Cargo.toml"]),
+        Ok(true)
+    );
+}
+
+#[test]
+fn regex_captures_groups() {
+    assert_eq!(
+        regex_match("^([a-z]+)-([0-9]+)?$", "abc-"),
+        Ok(Some(vec![
+            "abc-".to_string(),
+            "abc".to_string(),
+            String::new()
+        ]))
+    );
+    assert_eq!(regex_match("^x", "abc"), Ok(None));
+    assert!(regex_match("(", "abc").is_err());
+}
@@ -0,0 +1,380 @@
+use std::fmt::{self, Write as _};
+
+use crate::ast::{Command, CondExpr};
+use crate::lexer::lex_recovering;
+use crate::parser::parse_recovering;
+
+const INDENT: &str = "  ";
+
+/// A syntax error found by the lexer or the parser, with the line and column it was found at,
+/// both counting from 1.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    /// The error at byte `offset` of `input`, or past the blanks and comment there, which the
+    /// lexer counts as part of the next token.
+    fn at(input: &str, offset: usize, message: String) -> SyntaxError {
+        let mut rest = input[offset..].trim_start_matches([' ', '\t'].as_ref());
+        if rest.starts_with('#') {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        }
+        let offset = input.len() - rest.len();
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+        SyntaxError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+/// Prints the error as `line:column: syntax error: message`, to follow the name of the script.
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: syntax error: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+/// Lexes and parses `input` without running it, past any syntax errors in it: the tree has
+/// `Command::Error` for each command with an error in it, and the errors are in the order found.
+/// Input that ends too soon is reported at the end of its last line. The parser reads the tokens
+/// the lexer could make sense of, so lexer errors are reported along with its own.
+pub fn parse_checked(input: &str) -> (Command, Vec<SyntaxError>) {
+    let (spans, lexer_errors) = lex_recovering(input);
+    let tokens: Vec<_> = spans.iter().map(|(token, _)| token.clone()).collect();
+    let (command, parser_errors) = parse_recovering(&tokens);
+    let mut errors: Vec<_> = lexer_errors
+        .into_iter()
+        .map(|(e, offset)| SyntaxError::at(input, offset, e.to_string()))
+        .collect();
+    errors.extend(parser_errors.into_iter().map(|(e, range)| {
+        let offset = spans
+            .get(range.start)
+            .map_or(input.trim_end().len(), |(_, span)| span.start);
+        SyntaxError::at(input, offset, e.to_string())
+    }));
+    errors.sort_by_key(|e| (e.line, e.column));
+    (command, errors)
+}
+
+/// The tokens of `input` one to a line, each after the line and column it starts at, as far as
+/// it lexes. Also gives the errors the lexer found.
+pub fn tokens(input: &str) -> (String, Vec<SyntaxError>) {
+    let (tokens, errors) = lex_recovering(input);
+    let mut out = String::new();
+    for (token, span) in tokens {
+        let at = SyntaxError::at(input, span.start, String::new());
+        let _ = writeln!(out, "{}:{} {:?}", at.line, at.column, token);
+    }
+    let errors = errors
+        .into_iter()
+        .map(|(e, offset)| SyntaxError::at(input, offset, e.to_string()))
+        .collect();
+    (out, errors)
+}
+
+/// The syntax tree with a node to a line, each indented under its parent.
+pub fn tree(command: &Command) -> String {
+    let mut out = String::new();
+    tree_node(command, 0, &mut out);
+    out
+}
+
+fn line(out: &mut String, depth: usize, text: &str) {
+    for _ in 0..depth {
+        out.push_str(INDENT);
+    }
+    out.push_str(text);
+    out.push('\n');
+}
+
+fn tree_node(command: &Command, depth: usize, out: &mut String) {
+    match command {
+        Command::Nil => line(out, depth, "Nil"),
+        Command::Single(c) => line(out, depth, &format!("Single {:?}", c.args)),
+        Command::BinaryExpr(b) => {
+            line(out, depth, &format!("{:?}", b.op));
+            tree_node(&b.first, depth + 1, out);
+            tree_node(&b.second, depth + 1, out);
+        }
+        Command::Background(command) => {
+            line(out, depth, "Background");
+            tree_node(command, depth + 1, out);
+        }
+        Command::Not(command) => {
+            line(out, depth, "Not");
+            tree_node(command, depth + 1, out);
+        }
+        Command::Redirect(r) => {
+            let redirections: Vec<String> = r.redirections.iter().map(|r| r.to_string()).collect();
+            line(out, depth, &format!("Redirect {:?}", redirections));
+            tree_node(&r.command, depth + 1, out);
+        }
+        Command::FunctionDef(def) => {
+            line(out, depth, &format!("FunctionDef {:?}", def.name));
+            tree_node(&def.body, depth + 1, out);
+        }
+        Command::Subshell(body) => {
+            line(out, depth, "Subshell");
+            tree_node(body, depth + 1, out);
+        }
+        Command::Group(body) => {
+            line(out, depth, "Group");
+            tree_node(body, depth + 1, out);
+        }
+        Command::Arith(expr) => line(out, depth, &format!("Arith {:?}", expr)),
+        Command::Conditional(expr) => {
+            line(out, depth, "Conditional");
+            tree_cond(expr, depth + 1, out);
+        }
+        Command::Error => line(out, depth, "Error"),
+    }
+}
+
+fn tree_cond(expr: &CondExpr, depth: usize, out: &mut String) {
+    match expr {
+        CondExpr::Word(word) => line(out, depth, &format!("Word {:?}", word)),
+        CondExpr::Unary(op, operand) => line(out, depth, &format!("Unary {:?} {:?}", op, operand)),
+        CondExpr::Binary(left, op, right) => line(
+            out,
+            depth,
+            &format!("Binary {:?} {:?} {:?}", left, op, right),
+        ),
+        CondExpr::Not(expr) => {
+            line(out, depth, "Not");
+            tree_cond(expr, depth + 1, out);
+        }
+        CondExpr::And(left, right) | CondExpr::Or(left, right) => {
+            let name = match expr {
+                CondExpr::And(..) => "And",
+                _ => "Or",
+            };
+            line(out, depth, name);
+            tree_cond(left, depth + 1, out);
+            tree_cond(right, depth + 1, out);
+        }
+    }
+}
+
+/// `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON object with a `type` and then `fields`, whose values are JSON already.
+fn object(kind: &str, fields: &[(&str, String)]) -> String {
+    let mut out = format!("{{\"type\":{}", json_string(kind));
+    for (name, value) in fields {
+        let _ = write!(out, ",{}:{}", json_string(name), value);
+    }
+    out.push('}');
+    out
+}
+
+/// The syntax tree as JSON on one line. Each command is an object whose `type` is its variant,
+/// words are unexpanded strings, and `Nil` is `null`.
+pub fn json(command: &Command) -> String {
+    match command {
+        Command::Nil => "null".to_string(),
+        Command::Single(c) => {
+            let args: Vec<String> = c.args.iter().map(|a| json_string(a)).collect();
+            object("Single", &[("args", format!("[{}]", args.join(",")))])
+        }
+        Command::BinaryExpr(b) => object(
+            "BinaryExpr",
+            &[
+                ("op", json_string(b.op.symbol())),
+                ("first", json(&b.first)),
+                ("second", json(&b.second)),
+            ],
+        ),
+        Command::Background(command) => object("Background", &[("command", json(command))]),
+        Command::Not(command) => object("Not", &[("command", json(command))]),
+        Command::Redirect(r) => {
+            let redirections: Vec<String> = r
+                .redirections
+                .iter()
+                .map(|r| {
+                    format!(
+                        "{{\"fd\":{},\"kind\":{},\"target\":{}}}",
+                        r.fd,
+                        json_string(r.kind.symbol()),
+                        json_string(&r.target)
+                    )
+                })
+                .collect();
+            object(
+                "Redirect",
+                &[
+                    ("command", json(&r.command)),
+                    ("redirections", format!("[{}]", redirections.join(","))),
+                ],
+            )
+        }
+        Command::FunctionDef(def) => object(
+            "FunctionDef",
+            &[("name", json_string(&def.name)), ("body", json(&def.body))],
+        ),
+        Command::Subshell(body) => object("Subshell", &[("body", json(body))]),
+        Command::Group(body) => object("Group", &[("body", json(body))]),
+        Command::Arith(expr) => object("Arith", &[("expr", json_string(expr))]),
+        Command::Conditional(expr) => object("Conditional", &[("expr", json_cond(expr))]),
+        Command::Error => object("Error", &[]),
+    }
+}
+
+fn json_cond(expr: &CondExpr) -> String {
+    match expr {
+        CondExpr::Word(word) => object("Word", &[("word", json_string(word))]),
+        CondExpr::Unary(op, operand) => object(
+            "Unary",
+            &[("op", json_string(op)), ("operand", json_string(operand))],
+        ),
+        CondExpr::Binary(left, op, right) => object(
+            "Binary",
+            &[
+                ("left", json_string(left)),
+                ("op", json_string(op)),
+                ("right", json_string(right)),
+            ],
+        ),
+        CondExpr::Not(expr) => object("Not", &[("expr", json_cond(expr))]),
+        CondExpr::And(left, right) => object(
+            "And",
+            &[("left", json_cond(left)), ("right", json_cond(right))],
+        ),
+        CondExpr::Or(left, right) => object(
+            "Or",
+            &[("left", json_cond(left)), ("right", json_cond(right))],
+        ),
+    }
+}
+
+#[cfg(test)]
+fn errors(input: &str) -> Vec<String> {
+    let (_, errors) = parse_checked(input);
+    errors.iter().map(|e| e.to_string()).collect()
+}
+
+#[test]
+fn reports_where_syntax_errors_are() {
+    assert_eq!(
+        errors("echo a\n  echo 'b\n"),
+        ["2:8: syntax error: unterminated quote '"]
+    );
+    assert_eq!(
+        errors("a\nb; ) c"),
+        ["2:4: syntax error: there was an extra right parenthesis"]
+    );
+    assert_eq!(
+        parse_checked("{ a\n\n").1,
+        [SyntaxError {
+            line: 1,
+            column: 4,
+            message: "there was a missing right brace".to_string()
+        }]
+    );
+}
+
+#[test]
+fn reports_every_syntax_error() {
+    assert_eq!(
+        errors("a &&\n(b) c\nd >\n{ e; ) }\nf |"),
+        [
+            "2:5: syntax error: unexpected token `c'",
+            "3:3: syntax error: redirection was not followed by a file name",
+            "4:6: syntax error: there was an extra right parenthesis",
+            "5:4: syntax error: `|' was not followed by a command",
+        ]
+    );
+    assert_eq!(
+        errors("a &&& b\nc ; ; d\ne 'f"),
+        [
+            "1:3: syntax error: unknown operator &&&",
+            "2:5: syntax error: unexpected token `;'",
+            "3:3: syntax error: unterminated quote '",
+        ]
+    );
+}
+
+#[test]
+fn dumps_tokens_with_positions() {
+    assert_eq!(
+        tokens("a >f\n(b)"),
+        (
+            "1:1 Text(\"a\")\n1:3 WriteFile\n1:4 Text(\"f\")\n1:5 Semicolon\n\
+             2:1 LParen\n2:2 Text(\"b\")\n2:3 RParen\n"
+                .to_string(),
+            vec![]
+        )
+    );
+    let (out, errors) = tokens("a 'b");
+    assert_eq!(out, "1:1 Text(\"a\")\n");
+    let positions: Vec<_> = errors.iter().map(|e| (e.line, e.column)).collect();
+    assert_eq!(positions, [(1, 3)]);
+}
+
+#[test]
+fn dumps_indented_trees() {
+    let command = parse_checked("! a 'b c' | d && { e > f; } &\n[[ -n x || ! y ]]").0;
+    assert_eq!(
+        tree(&command),
+        "\
+Seq
+  Background
+    LogAnd
+      Not
+        Pipe
+          Single [\"a\", \"'b c'\"]
+          Single [\"d\"]
+      Group
+        Seq
+          Redirect [\"> f\"]
+            Single [\"e\"]
+          Nil
+  Conditional
+    Or
+      Unary \"-n\" \"x\"
+      Not
+        Word \"y\"
+"
+    );
+}
+
+#[test]
+fn dumps_json() {
+    let command = parse_checked("f() (echo \"a\\\\b\" 2>&1); ((x))").0;
+    assert_eq!(
+        json(&command),
+        "{\"type\":\"BinaryExpr\",\"op\":\";\",\"first\":\
+         {\"type\":\"FunctionDef\",\"name\":\"f\",\"body\":{\"type\":\"Subshell\",\"body\":\
+         {\"type\":\"Redirect\",\"command\":{\"type\":\"Single\",\"args\":[\"echo\",\"\\\"a\\\\\\\\b\\\"\"]},\
+         \"redirections\":[{\"fd\":2,\"kind\":\">&\",\"target\":\"1\"}]}}},\
+         \"second\":{\"type\":\"Arith\",\"expr\":\"x\"}}"
+    );
+    assert_eq!(json_string("a\n\u{1}"), "\"a\\n\\u0001\"");
+}
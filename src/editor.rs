@@ -0,0 +1,867 @@
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+
+use nix::errno::Errno;
+use nix::unistd::isatty;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::terminal::{self, Key, RawMode};
+
+/// How many kills the kill ring remembers.
+const KILL_RING_SIZE: usize = 16;
+
+/// Marks the start of prompt text that takes no space on screen, such as a terminal title
+/// sequence. The markers themselves are not written.
+pub const START_IGNORE: char = '\x01';
+/// Marks the end of text started by `START_IGNORE`.
+pub const END_IGNORE: char = '\x02';
+
+/// The result of reading a line.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ReadLine {
+    Line(String),
+    /// The line was abandoned with C-c.
+    Interrupted,
+    /// Input ended, or C-d was pressed on an empty line.
+    Eof,
+}
+
+/// What Tab does to the word before the cursor.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Completion {
+    /// Where the word starts in the line.
+    pub start: usize,
+    /// Text to replace the word with, if completion made progress.
+    pub replacement: Option<String>,
+    /// Candidates to list when completion is ambiguous.
+    pub matches: Vec<String>,
+}
+
+/// The shell's side of line editing.
+pub trait Helper {
+    /// Completes the word before `cursor` in `line`.
+    fn complete(&mut self, line: &str, cursor: usize) -> Completion;
+
+    /// `line` as it should be shown, with escape sequences for colour. Shown as typed by default.
+    fn highlight(&mut self, line: &str) -> String {
+        line.to_string()
+    }
+
+    /// Text to suggest after `line`, which the user can accept with Right or End.
+    fn suggest(&mut self, _line: &str, _history: &[String]) -> Option<String> {
+        None
+    }
+}
+
+/// What the editor should do after a key press.
+#[derive(Debug, Eq, PartialEq)]
+enum Action {
+    Continue,
+    Accept,
+    Interrupt,
+    Eof,
+    ClearScreen,
+    Complete,
+}
+
+/// The text being edited and the cursor, as a byte offset on a character boundary.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct Line {
+    text: String,
+    cursor: usize,
+}
+
+impl Line {
+    fn from(text: &str) -> Line {
+        Line {
+            text: text.to_string(),
+            cursor: text.len(),
+        }
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn before(&self) -> std::str::CharIndices<'_> {
+        self.text[..self.cursor].char_indices()
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.before().next_back().map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .chars()
+            .next()
+            .map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    /// The start of the word before the cursor, where words are runs of alphanumerics.
+    fn word_start(&self) -> usize {
+        let mut start = self.cursor;
+        let mut in_word = false;
+        for (i, c) in self.before().rev() {
+            if c.is_alphanumeric() {
+                in_word = true;
+            } else if in_word {
+                break;
+            }
+            start = i;
+        }
+        start
+    }
+
+    /// The end of the word after the cursor, where words are runs of alphanumerics.
+    fn word_end(&self) -> usize {
+        let mut in_word = false;
+        for (i, c) in self.text[self.cursor..].char_indices() {
+            if c.is_alphanumeric() {
+                in_word = true;
+            } else if in_word {
+                return self.cursor + i;
+            }
+        }
+        self.text.len()
+    }
+
+    /// The start of the whitespace-delimited word before the cursor, which C-w kills.
+    fn rubout_start(&self) -> usize {
+        let mut start = self.cursor;
+        let mut in_word = false;
+        for (i, c) in self.before().rev() {
+            if !c.is_whitespace() {
+                in_word = true;
+            } else if in_word {
+                break;
+            }
+            start = i;
+        }
+        start
+    }
+
+    fn replace(&mut self, range: Range<usize>, text: &str) {
+        self.remove(range);
+        self.insert(text);
+    }
+
+    /// Removes a range of text, leaving the cursor where it was.
+    fn remove(&mut self, range: Range<usize>) -> String {
+        let start = range.start;
+        let removed: String = self.text.drain(range).collect();
+        self.cursor = start;
+        removed
+    }
+}
+
+#[derive(Debug)]
+struct Search {
+    query: String,
+    /// The history entry that currently matches.
+    found: Option<usize>,
+    failed: bool,
+    /// The line from before the search, restored if it is cancelled.
+    original: Line,
+}
+
+/// The state of reading one line.
+struct Session<'a> {
+    line: Line,
+    history: &'a [String],
+    /// The history entry being shown, or `history.len()` for the new line.
+    history_index: usize,
+    /// The new line, kept while browsing history.
+    draft: String,
+    search: Option<Search>,
+    kill_ring: &'a mut Vec<String>,
+    /// Whether the previous key killed text, so that another kill adds to the same entry.
+    last_kill: bool,
+    /// Where the previous key yanked text, and from which kill ring entry, for M-y.
+    last_yank: Option<(usize, usize)>,
+    /// Text suggested after the end of the line, shown dimmed.
+    suggestion: String,
+}
+
+impl<'a> Session<'a> {
+    fn new(history: &'a [String], kill_ring: &'a mut Vec<String>) -> Session<'a> {
+        Session {
+            line: Line::default(),
+            history,
+            history_index: history.len(),
+            draft: String::new(),
+            search: None,
+            kill_ring,
+            last_kill: false,
+            last_yank: None,
+            suggestion: String::new(),
+        }
+    }
+
+    /// Asks `helper` what to suggest after the line. Suggestions are only offered with the cursor
+    /// at the end of the line.
+    fn suggest(&mut self, helper: &mut dyn Helper) {
+        let at_end = self.search.is_none() && self.line.cursor == self.line.text.len();
+        self.suggestion = match at_end {
+            true => helper.suggest(&self.line.text, self.history),
+            false => None,
+        }
+        .unwrap_or_default();
+    }
+
+    fn prompt(&self, prompt: &str) -> String {
+        match &self.search {
+            None => prompt.to_string(),
+            Some(search) => format!(
+                "({}reverse-i-search)`{}': ",
+                if search.failed { "failed " } else { "" },
+                search.query
+            ),
+        }
+    }
+
+    fn handle(&mut self, key: Key) -> Action {
+        if self.search.is_some() {
+            return self.handle_search(key);
+        }
+        let last_kill = std::mem::replace(&mut self.last_kill, false);
+        let last_yank = self.last_yank.take();
+        let line = &mut self.line;
+        let suggested = !self.suggestion.is_empty() && line.cursor == line.text.len();
+        match key {
+            Key::Ctrl('e') | Key::End | Key::Ctrl('f') | Key::Right if suggested => {
+                line.insert(&self.suggestion)
+            }
+            Key::Meta('f') | Key::WordRight if suggested => {
+                let end = Line {
+                    text: self.suggestion.clone(),
+                    cursor: 0,
+                }
+                .word_end();
+                line.insert(&self.suggestion[..end]);
+            }
+            Key::Char(c) => line.insert(c.encode_utf8(&mut [0; 4])),
+            Key::Enter => return Action::Accept,
+            Key::Ctrl('c') => return Action::Interrupt,
+            Key::Ctrl('d') if line.text.is_empty() => return Action::Eof,
+            Key::Ctrl('d') | Key::Delete => {
+                line.remove(line.cursor..line.next_boundary());
+            }
+            Key::Backspace | Key::Ctrl('h') => {
+                line.remove(line.prev_boundary()..line.cursor);
+            }
+            Key::Ctrl('a') | Key::Home => line.cursor = 0,
+            Key::Ctrl('e') | Key::End => line.cursor = line.text.len(),
+            Key::Ctrl('b') | Key::Left => line.cursor = line.prev_boundary(),
+            Key::Ctrl('f') | Key::Right => line.cursor = line.next_boundary(),
+            Key::Meta('b') | Key::WordLeft => line.cursor = line.word_start(),
+            Key::Meta('f') | Key::WordRight => line.cursor = line.word_end(),
+            Key::Ctrl('k') => {
+                let range = line.cursor..line.text.len();
+                self.kill(range, last_kill, true);
+            }
+            Key::Ctrl('u') => {
+                let range = 0..line.cursor;
+                self.kill(range, last_kill, false);
+            }
+            Key::Ctrl('w') => {
+                let range = line.rubout_start()..line.cursor;
+                self.kill(range, last_kill, false);
+            }
+            Key::Meta('d') => {
+                let range = line.cursor..line.word_end();
+                self.kill(range, last_kill, true);
+            }
+            Key::MetaBackspace => {
+                let range = line.word_start()..line.cursor;
+                self.kill(range, last_kill, false);
+            }
+            Key::Ctrl('y') => self.yank(),
+            Key::Meta('y') => self.yank_pop(last_yank),
+            Key::Up | Key::Ctrl('p') => self.history_prev(),
+            Key::Down | Key::Ctrl('n') => self.history_next(),
+            Key::Ctrl('r') => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                    original: self.line.clone(),
+                })
+            }
+            Key::Ctrl('l') => return Action::ClearScreen,
+            Key::Tab => return Action::Complete,
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    /// Removes text into the kill ring. Consecutive kills collect into one entry, in the order the
+    /// text appeared in the line.
+    fn kill(&mut self, range: Range<usize>, last_kill: bool, forward: bool) {
+        let text = self.line.remove(range);
+        self.last_kill = true;
+        if text.is_empty() {
+            return;
+        }
+        match self.kill_ring.last_mut() {
+            Some(entry) if last_kill && forward => entry.push_str(&text),
+            Some(entry) if last_kill => entry.insert_str(0, &text),
+            _ => {
+                if self.kill_ring.len() == KILL_RING_SIZE {
+                    self.kill_ring.remove(0);
+                }
+                self.kill_ring.push(text);
+            }
+        }
+    }
+
+    fn yank(&mut self) {
+        if let Some(text) = self.kill_ring.last() {
+            let start = self.line.cursor;
+            self.line.insert(text);
+            self.last_yank = Some((start, self.kill_ring.len() - 1));
+        }
+    }
+
+    /// Replaces the text just yanked with the previous kill ring entry.
+    fn yank_pop(&mut self, last_yank: Option<(usize, usize)>) {
+        if let Some((start, index)) = last_yank {
+            let len = self.kill_ring.len();
+            let index = (index + len - 1) % len;
+            self.line.remove(start..self.line.cursor);
+            self.line.insert(&self.kill_ring[index]);
+            self.last_yank = Some((start, index));
+        }
+    }
+
+    fn history_prev(&mut self) {
+        if self.history_index == 0 {
+            return;
+        }
+        if self.history_index == self.history.len() {
+            self.draft = self.line.text.clone();
+        }
+        self.history_index -= 1;
+        self.line = Line::from(&self.history[self.history_index]);
+    }
+
+    fn history_next(&mut self) {
+        if self.history_index >= self.history.len() {
+            return;
+        }
+        self.history_index += 1;
+        self.line = match self.history.get(self.history_index) {
+            Some(entry) => Line::from(entry),
+            None => Line::from(&self.draft),
+        };
+    }
+
+    /// Finds the newest history entry before `before` that contains the query, and shows it.
+    fn search_from(&mut self, before: usize) {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return,
+        };
+        let found = self.history[..before]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, entry)| entry.find(&search.query).map(|pos| (i, pos)));
+        match found {
+            Some((i, pos)) => {
+                search.found = Some(i);
+                search.failed = false;
+                self.line = Line::from(&self.history[i]);
+                self.line.cursor = pos;
+            }
+            None => search.failed = true,
+        }
+    }
+
+    fn handle_search(&mut self, key: Key) -> Action {
+        let (found, original) = match &mut self.search {
+            Some(search) => match key {
+                Key::Char(c) => {
+                    search.query.push(c);
+                    let from = search.found.map_or(self.history.len(), |i| i + 1);
+                    self.search_from(from);
+                    return Action::Continue;
+                }
+                Key::Backspace | Key::Ctrl('h') => {
+                    search.query.pop();
+                    self.search_from(self.history.len());
+                    return Action::Continue;
+                }
+                Key::Ctrl('r') => {
+                    let from = search.found.unwrap_or(self.history.len());
+                    self.search_from(from);
+                    return Action::Continue;
+                }
+                _ => (search.found, search.original.clone()),
+            },
+            None => return Action::Continue,
+        };
+        self.search = None;
+        match key {
+            Key::Ctrl('g') | Key::Escape => {
+                self.line = original;
+                Action::Continue
+            }
+            Key::Ctrl('c') => Action::Interrupt,
+            _ => {
+                if let Some(i) = found {
+                    self.history_index = i;
+                }
+                self.handle(key)
+            }
+        }
+    }
+}
+
+/// Where the cursor ends up after writing `text` at the start of a line `width` columns wide, as
+/// a row and column. ANSI escape sequences and text between ignore markers take no space.
+fn position(text: &str, width: usize) -> (usize, usize) {
+    let (mut row, mut col) = (0, 0);
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == START_IGNORE {
+            chars.by_ref().find(|&c| c == END_IGNORE);
+            continue;
+        }
+        if c == '\x1b' {
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        if c == '\n' {
+            row += 1;
+            col = 0;
+            continue;
+        }
+        let w = c.width().unwrap_or(0);
+        if col + w > width {
+            row += 1;
+            col = 0;
+        }
+        col += w;
+    }
+    if col >= width {
+        (row + 1, 0)
+    } else {
+        (row, col)
+    }
+}
+
+/// Reads lines from a terminal with emacs-style editing, history and reverse search. Input that
+/// is not a terminal is read a line at a time without editing.
+pub struct Editor {
+    input: RawFd,
+    output: RawFd,
+    kill_ring: Vec<String>,
+    /// Whether input that is not a terminal may be read ahead, as it can when no other process
+    /// reads from it.
+    buffered: bool,
+    /// Input read ahead but not yet returned.
+    pending: Vec<u8>,
+}
+
+impl Editor {
+    pub fn new(input: RawFd, output: RawFd) -> Editor {
+        let _ = terminal::watch_window_size();
+        Editor {
+            input,
+            output,
+            kill_ring: vec![],
+            buffered: false,
+            pending: vec![],
+        }
+    }
+
+    /// An editor for input that is only the shell's, such as a script, which is read a block at
+    /// a time instead of a byte at a time when it isn't a terminal.
+    pub fn buffered(input: RawFd, output: RawFd) -> Editor {
+        Editor {
+            buffered: true,
+            ..Editor::new(input, output)
+        }
+    }
+
+    /// Reads a line, with `history` available to Up, Down and C-r.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        history: &[String],
+        helper: &mut dyn Helper,
+    ) -> nix::Result<ReadLine> {
+        if !isatty(self.input).unwrap_or(false) {
+            return self.read_plain_line();
+        }
+        let _raw = RawMode::enable(self.input)?;
+        let (input, output) = (self.input, self.output);
+        let mut cursor_row = 0;
+        let mut session = Session::new(history, &mut self.kill_ring);
+        redraw(
+            output,
+            &mut cursor_row,
+            &session.prompt(prompt),
+            &session.line,
+            "",
+            helper,
+        )?;
+        loop {
+            let key = match terminal::read_key(input) {
+                Ok(Some(key)) => key,
+                Ok(None) => return Ok(ReadLine::Eof),
+                Err(nix::Error::Sys(Errno::EINTR)) => {
+                    if terminal::take_resized() {
+                        redraw(
+                            output,
+                            &mut cursor_row,
+                            &session.prompt(prompt),
+                            &session.line,
+                            &session.suggestion,
+                            helper,
+                        )?;
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let action = session.handle(key);
+            if action == Action::ClearScreen {
+                write_str(output, "\x1b[H\x1b[2J")?;
+                cursor_row = 0;
+            }
+            if action == Action::Complete {
+                let line = &mut session.line;
+                let completion = helper.complete(&line.text, line.cursor);
+                if let Some(replacement) = completion.replacement {
+                    line.replace(completion.start..line.cursor, &replacement);
+                } else if !completion.matches.is_empty() {
+                    let end = Line::from(&line.text);
+                    redraw(output, &mut cursor_row, prompt, &end, "", helper)?;
+                    let width = terminal::window_width(output);
+                    write_str(
+                        output,
+                        &format!("\n{}", columns(&completion.matches, width)),
+                    )?;
+                    cursor_row = 0;
+                }
+            }
+            if action != Action::Accept && action != Action::Interrupt && action != Action::Eof {
+                session.suggest(helper);
+                redraw(
+                    output,
+                    &mut cursor_row,
+                    &session.prompt(prompt),
+                    &session.line,
+                    &session.suggestion,
+                    helper,
+                )?;
+                continue;
+            }
+            session.line.cursor = session.line.text.len();
+            redraw(output, &mut cursor_row, prompt, &session.line, "", helper)?;
+            return match action {
+                Action::Accept => {
+                    write_str(output, "\n")?;
+                    Ok(ReadLine::Line(session.line.text))
+                }
+                Action::Interrupt => {
+                    write_str(output, "^C\n")?;
+                    Ok(ReadLine::Interrupted)
+                }
+                _ => {
+                    write_str(output, "\n")?;
+                    Ok(ReadLine::Eof)
+                }
+            };
+        }
+    }
+
+    /// Reads up to a newline. Unless the input is buffered, this reads one byte at a time, so
+    /// that no input meant for later commands is consumed.
+    fn read_plain_line(&mut self) -> nix::Result<ReadLine> {
+        if self.buffered {
+            return self.read_buffered_line();
+        }
+        let mut bytes = vec![];
+        loop {
+            match terminal::read_byte(self.input) {
+                Ok(Some(b'\n')) => break,
+                Ok(Some(b)) => bytes.push(b),
+                Ok(None) if bytes.is_empty() => return Ok(ReadLine::Eof),
+                Ok(None) => break,
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ReadLine::Line(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    fn read_buffered_line(&mut self) -> nix::Result<ReadLine> {
+        let mut block = [0u8; 4096];
+        loop {
+            if let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line[..end]).into_owned();
+                return Ok(ReadLine::Line(line));
+            }
+            match nix::unistd::read(self.input, &mut block) {
+                Ok(0) if self.pending.is_empty() => return Ok(ReadLine::Eof),
+                Ok(0) => {
+                    let line = String::from_utf8_lossy(&self.pending).into_owned();
+                    self.pending.clear();
+                    return Ok(ReadLine::Line(line));
+                }
+                Ok(n) => self.pending.extend_from_slice(&block[..n]),
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Lays out items in as many columns as fit in `width`, filling each column top to bottom.
+fn columns(items: &[String], width: usize) -> String {
+    let column_width = items.iter().map(|i| i.width()).max().unwrap_or(0) + 2;
+    let count = (width / column_width).max(1);
+    let rows = items.len().div_ceil(count);
+    let mut out = String::new();
+    for row in 0..rows {
+        let mut line = String::new();
+        for item in items.iter().skip(row).step_by(rows) {
+            line.push_str(item);
+            line.push_str(&" ".repeat(column_width - item.width()));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn write_str(fd: RawFd, text: &str) -> nix::Result<()> {
+    let mut bytes = text.as_bytes();
+    while !bytes.is_empty() {
+        match nix::unistd::write(fd, bytes) {
+            Ok(n) => bytes = &bytes[n..],
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Redraws the prompt and line in place. `cursor_row` is the cursor's row below the start of the
+/// prompt from the previous draw, and is updated for this one.
+fn redraw(
+    output: RawFd,
+    cursor_row: &mut usize,
+    prompt: &str,
+    line: &Line,
+    suggestion: &str,
+    helper: &mut dyn Helper,
+) -> nix::Result<()> {
+    let width = terminal::window_width(output);
+    let mut out = String::new();
+    if *cursor_row > 0 {
+        out.push_str(&format!("\x1b[{}A", cursor_row));
+    }
+    out.push_str("\r\x1b[J");
+    out.extend(
+        prompt
+            .chars()
+            .filter(|&c| c != START_IGNORE && c != END_IGNORE),
+    );
+    // Escape sequences in the highlighted text take no room, so positions come from the text.
+    out.push_str(&helper.highlight(&line.text));
+    if !suggestion.is_empty() {
+        out.push_str(&format!("\x1b[2m{}\x1b[0m", suggestion));
+    }
+    let (end_row, end_col) = position(&format!("{}{}{}", prompt, line.text, suggestion), width);
+    let (row, col) = position(&format!("{}{}", prompt, &line.text[..line.cursor]), width);
+    if end_col == 0 && end_row > 0 {
+        // The text exactly fills its last row; move to the next one so the cursor can go there.
+        out.push_str("\r\n");
+    }
+    if end_row > row {
+        out.push_str(&format!("\x1b[{}A", end_row - row));
+    }
+    out.push('\r');
+    if col > 0 {
+        out.push_str(&format!("\x1b[{}C", col));
+    }
+    *cursor_row = row;
+    write_str(output, &out)
+}
+
+#[cfg(test)]
+fn type_keys(session: &mut Session, keys: &[Key]) -> Action {
+    let mut action = Action::Continue;
+    for key in keys {
+        action = session.handle(*key);
+    }
+    action
+}
+
+#[cfg(test)]
+fn type_text(session: &mut Session, text: &str) {
+    for c in text.chars() {
+        session.handle(Key::Char(c));
+    }
+}
+
+#[test]
+fn moves_by_words_and_kills_into_ring() {
+    let mut ring = vec![];
+    let mut session = Session::new(&[], &mut ring);
+    type_text(&mut session, "echo hello, world");
+
+    type_keys(
+        &mut session,
+        &[Key::Ctrl('a'), Key::Meta('f'), Key::Meta('f')],
+    );
+    assert_eq!(session.line.cursor, 10);
+    type_keys(
+        &mut session,
+        &[Key::Ctrl('k'), Key::Meta('b'), Key::Ctrl('w')],
+    );
+    assert_eq!(session.line.text, "hello");
+    type_keys(&mut session, &[Key::Ctrl('e'), Key::Ctrl('y')]);
+    assert_eq!(session.line.text, "helloecho ");
+    type_keys(&mut session, &[Key::Meta('y')]);
+    assert_eq!(session.line.text, "hello, world");
+}
+
+#[test]
+fn consecutive_kills_join() {
+    let mut ring = vec![];
+    let mut session = Session::new(&[], &mut ring);
+    type_text(&mut session, "a b c");
+
+    type_keys(&mut session, &[Key::Ctrl('w'), Key::Ctrl('w'), Key::Left]);
+    assert_eq!(session.line.text, "a ");
+    type_keys(&mut session, &[Key::Ctrl('k')]);
+    assert_eq!(ring, vec!["b c", " "]);
+}
+
+#[test]
+fn edits_multibyte_characters() {
+    let mut ring = vec![];
+    let mut session = Session::new(&[], &mut ring);
+    type_text(&mut session, "aé漢b");
+
+    type_keys(&mut session, &[Key::Left, Key::Left, Key::Backspace]);
+    assert_eq!(session.line.text, "a漢b");
+    type_keys(&mut session, &[Key::Delete]);
+    assert_eq!(session.line.text, "ab");
+}
+
+#[test]
+fn browses_history_keeping_the_draft() {
+    let history = vec!["one".to_string(), "two".to_string()];
+    let mut ring = vec![];
+    let mut session = Session::new(&history, &mut ring);
+    type_text(&mut session, "new");
+
+    type_keys(&mut session, &[Key::Up, Key::Up, Key::Up]);
+    assert_eq!(session.line.text, "one");
+    type_keys(&mut session, &[Key::Down]);
+    assert_eq!(session.line.text, "two");
+    type_keys(&mut session, &[Key::Down, Key::Down]);
+    assert_eq!(session.line.text, "new");
+}
+
+#[test]
+fn reverse_search_finds_older_matches() {
+    let history = vec!["echo a".to_string(), "ls".to_string(), "echo b".to_string()];
+    let mut ring = vec![];
+    let mut session = Session::new(&history, &mut ring);
+
+    type_keys(&mut session, &[Key::Ctrl('r')]);
+    type_text(&mut session, "ec");
+    assert_eq!(session.line.text, "echo b");
+    type_keys(&mut session, &[Key::Ctrl('r')]);
+    assert_eq!(session.line.text, "echo a");
+    type_text(&mut session, "x");
+    assert_eq!(session.prompt("$ "), "(failed reverse-i-search)`ecx': ");
+    let action = type_keys(&mut session, &[Key::Ctrl('e'), Key::Char('!'), Key::Enter]);
+    assert_eq!(action, Action::Accept);
+    assert_eq!(session.line.text, "echo a!");
+}
+
+#[test]
+fn cancelled_search_restores_line() {
+    let history = vec!["echo a".to_string()];
+    let mut ring = vec![];
+    let mut session = Session::new(&history, &mut ring);
+    type_text(&mut session, "draft");
+
+    type_keys(
+        &mut session,
+        &[Key::Ctrl('r'), Key::Char('e'), Key::Ctrl('g')],
+    );
+    assert_eq!(session.line.text, "draft");
+}
+
+#[test]
+fn accepts_suggestions_whole_or_by_word() {
+    let mut ring = vec![];
+    let mut session = Session::new(&[], &mut ring);
+    type_text(&mut session, "git");
+    session.suggestion = " commit -m x".to_string();
+
+    type_keys(&mut session, &[Key::WordRight]);
+    assert_eq!(session.line.text, "git commit");
+    session.suggestion = " -m x".to_string();
+    type_keys(&mut session, &[Key::End]);
+    assert_eq!(session.line.text, "git commit -m x");
+
+    session.suggestion = "y".to_string();
+    type_keys(&mut session, &[Key::Left, Key::Right]);
+    assert_eq!(session.line.text, "git commit -m x");
+}
+
+#[test]
+fn lays_out_columns() {
+    let items: Vec<String> = ["a", "bb", "c", "dd", "漢"]
+        .iter()
+        .map(|i| i.to_string())
+        .collect();
+
+    assert_eq!(columns(&items, 12), "a   c   漢\nbb  dd\n");
+    assert_eq!(columns(&items[..2], 3), "a\nbb\n");
+}
+
+#[test]
+fn positions_account_for_wide_characters_and_escapes() {
+    assert_eq!(position("abc", 10), (0, 3));
+    assert_eq!(position("abc漢", 4), (1, 2));
+    assert_eq!(position("abcd", 4), (1, 0));
+    assert_eq!(position("\x1b[1;32m$\x1b[0m x", 10), (0, 3));
+    assert_eq!(position("\x01\x1b]0;title\x07\x02$ x", 10), (0, 3));
+}
+
+#[test]
+fn buffered_input_reads_ahead_and_splits_lines() {
+    let (read, write) = nix::unistd::pipe().unwrap();
+    write_str(write, "a\n\nb\nc").unwrap();
+    nix::unistd::close(write).unwrap();
+    let mut editor = Editor::buffered(read, 2);
+
+    let mut lines = vec![];
+    while let ReadLine::Line(line) = editor.read_plain_line().unwrap() {
+        lines.push(line);
+        if lines.len() == 1 {
+            assert_eq!(editor.pending, b"\nb\nc");
+        }
+    }
+    nix::unistd::close(read).unwrap();
+    assert_eq!(lines, ["a", "", "b", "c"]);
+}
@@ -1,15 +1,30 @@
-use std::ffi::{CStr, CString, NulError};
+use std::ffi::NulError;
 use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use libc::WEXITED;
-use nix::sys::signal::Signal::{SIGKILL, SIGTERM};
-use nix::sys::signal::{kill, Signal};
-use nix::sys::wait::{waitpid, WaitPidFlag};
-use nix::unistd::{dup2, execv, fork, pipe, ForkResult, Pid};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::{close, read, write, ForkResult, Pid};
 use nix::{libc, Error};
 
-use crate::ast::{BinaryExpr, BinaryOp, Command, SingleCommand};
+use crate::alias;
+use crate::arith;
+use crate::ast::{
+    BinaryExpr, BinaryOp, Command, Redirect, RedirectKind, Redirection, SingleCommand,
+};
+use crate::builtins;
+use crate::conditional;
+use crate::expand::{expand_string, expand_words, split_array, split_assignment, ExpansionError};
+use crate::lexer::lex;
+use crate::parser::parse;
+use crate::state::State;
+use crate::sys::{Spawn, Sys, SysRef};
+use crate::trace::{echo_input, quote_word, trace};
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct StreamSet {
     stdin: Option<RawFd>,
     stdout: Option<RawFd>,
@@ -17,6 +32,16 @@ pub struct StreamSet {
 }
 
 impl StreamSet {
+    /// Streams on the given file descriptors. A stream that is `None` is closed for builtins and
+    /// connected to `/dev/null` for other commands.
+    pub fn new(stdin: Option<RawFd>, stdout: Option<RawFd>, stderr: Option<RawFd>) -> StreamSet {
+        StreamSet {
+            stdin,
+            stdout,
+            stderr,
+        }
+    }
+
     pub fn std() -> StreamSet {
         StreamSet {
             stdin: Some(0),
@@ -25,9 +50,9 @@ impl StreamSet {
         }
     }
 
-    pub fn pipe(self: StreamSet) -> (StreamSet, StreamSet) {
-        let (r_stdin, l_stdout) = pipe().unwrap();
-        (
+    pub fn pipe(self: StreamSet, sys: &dyn Sys) -> nix::Result<(StreamSet, StreamSet)> {
+        let (r_stdin, l_stdout) = sys.pipe()?;
+        Ok((
             StreamSet {
                 stdin: self.stdin,
                 stdout: Some(l_stdout),
@@ -36,9 +61,9 @@ impl StreamSet {
             StreamSet {
                 stdin: Some(r_stdin),
                 stdout: self.stdout,
-                stderr: self.stderr.clone(),
+                stderr: self.stderr,
             },
-        )
+        ))
     }
 
     pub fn fork(self: StreamSet) -> (StreamSet, StreamSet) {
@@ -50,11 +75,109 @@ impl StreamSet {
             },
             StreamSet {
                 stdin: self.stdin,
-                stdout: self.stdout.clone(),
-                stderr: self.stderr.clone(),
+                stdout: self.stdout,
+                stderr: self.stderr,
             },
         )
     }
+
+    /// The file descriptor that `fd` refers to in these streams. Descriptors above 2 are not
+    /// tracked and refer to the shell's own, which redirections of them change in the shell
+    /// itself for the length of the command.
+    pub(crate) fn get(&self, fd: i32) -> Option<RawFd> {
+        match fd {
+            0 => self.stdin,
+            1 => self.stdout,
+            2 => self.stderr,
+            _ => Some(fd),
+        }
+    }
+
+    /// Points `fd` at `target`. Returns false for descriptors above 2, which the streams don't
+    /// track.
+    pub(crate) fn set(&mut self, fd: i32, target: Option<RawFd>) -> bool {
+        match fd {
+            0 => self.stdin = target,
+            1 => self.stdout = target,
+            2 => self.stderr = target,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Writes all of `text` to stdout, ignoring errors like a closed pipe.
+    pub fn write_out(&self, text: &str) {
+        if let Some(fd) = self.stdout {
+            write_all(fd, text.as_bytes());
+        }
+    }
+
+    /// Writes all of `text` to stderr.
+    pub fn write_err(&self, text: &str) {
+        if let Some(fd) = self.stderr {
+            write_all(fd, text.as_bytes());
+        }
+    }
+
+    /// Makes these streams the standard streams of the current process. Streams that are `None`
+    /// are connected to `/dev/null`. Only used in forked children.
+    pub(crate) fn install(self: StreamSet, sys: &dyn Sys) {
+        let mut targets = [(self.stdin, 0), (self.stdout, 1), (self.stderr, 2)];
+        // A standard stream that is the source of another is copied before any is replaced, so
+        // that swapping two of them works. The copies are closed on exec.
+        for (fd, target) in targets.iter_mut() {
+            if let Some(source) = *fd {
+                if source != *target && source <= 2 {
+                    *fd = Some(sys.dup(source).unwrap_or(source));
+                }
+            }
+        }
+        for (fd, target) in targets.iter() {
+            let fd = match fd {
+                Some(fd) => *fd,
+                None => {
+                    let flags = if *target == 0 {
+                        OFlag::O_RDONLY
+                    } else {
+                        OFlag::O_WRONLY
+                    };
+                    sys.open("/dev/null", flags, Mode::empty())
+                        .unwrap_or(*target)
+                }
+            };
+            if fd != *target {
+                let _ = sys.dup2(fd, *target);
+            }
+        }
+    }
+}
+
+fn write_all(fd: RawFd, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match write(fd, bytes) {
+            Ok(n) => bytes = &bytes[n..],
+            Err(Error::Sys(nix::errno::Errno::EINTR)) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+/// Closes the file descriptors the shell opened for itself. A forked subshell does this after
+/// installing its streams, the way `execve` would, so it doesn't hold pipes open.
+pub(crate) fn close_shell_fds() {
+    let fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => (3..256).collect(),
+    };
+    for fd in fds.into_iter().filter(|fd| *fd > 2) {
+        if let Ok(flags) = fcntl(fd, FcntlArg::F_GETFD) {
+            if FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC) {
+                let _ = close(fd);
+            }
+        }
+    }
 }
 
 pub enum ProcessSpawnError {
@@ -72,91 +195,1191 @@ impl From<NulError> for ProcessSpawnError {
     }
 }
 
+/// A child process of the shell. Dropping a handle to a child that is still running waits for
+/// it unless it was detached, so a command is never killed or left a zombie just because its
+/// handle went out of scope.
+#[derive(Debug)]
 pub struct Process {
     pid: Pid,
-    attached: bool,
-    streams: StreamSet,
+    sys: SysRef,
+    /// The exit status, once the child has been waited for.
+    status: Option<i32>,
+    detached: bool,
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        if self.attached {
-            kill(self.pid, SIGTERM);
+        if !self.detached {
+            self.wait();
         }
     }
 }
 
 impl Process {
+    fn new(sys: &SysRef, pid: Pid) -> Process {
+        Process {
+            pid,
+            sys: sys.clone(),
+            status: None,
+            detached: false,
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// The exit status, if the child has been waited for.
+    pub fn status(&self) -> Option<i32> {
+        self.status
+    }
+
+    /// Waits for the child to terminate and returns its exit status, 128 + the signal number if
+    /// it was killed.
+    pub fn wait(&mut self) -> i32 {
+        match self.status {
+            Some(status) => status,
+            None => *self.status.insert(wait_for(&*self.sys, self.pid)),
+        }
+    }
+
+    /// Returns the exit status if the child has terminated, without blocking. Fails if the
+    /// child can't be waited for, such as with `ECHILD` when something else reaped it.
+    pub fn try_wait(&mut self) -> nix::Result<Option<i32>> {
+        if self.status.is_none() {
+            self.status = match self.sys.waitpid(self.pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(_, code)) => Some(code),
+                Ok(WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as i32),
+                Ok(_) | Err(Error::Sys(Errno::EINTR)) => None,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(self.status)
+    }
+
+    /// Sends `signal` to the child. Once it has been waited for, its pid may belong to another
+    /// process, so this fails with `ESRCH` instead.
+    pub fn kill(&self, signal: Signal) -> nix::Result<()> {
+        match self.status {
+            Some(_) => Err(Error::Sys(Errno::ESRCH)),
+            None => self.sys.kill(self.pid, signal),
+        }
+    }
+
+    /// Lets the child outlive this handle, as background jobs do.
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    /// Starts an external command. Everything the child needs is prepared here, so nothing of
+    /// the shell runs in it.
     fn spawn(
-        cmd: SingleCommand,
-        attached: bool,
+        sys: &SysRef,
+        args: &[String],
+        env: &[String],
         streams: StreamSet,
     ) -> Result<Process, ProcessSpawnError> {
+        let fds = [streams.stdin, streams.stdout, streams.stderr];
+        let spawn = Spawn::new(&find_executable(&args[0], env), args, env, fds)?;
+        Ok(Process::new(sys, sys.spawn(&spawn)?))
+    }
+
+    /// Forks a subshell that executes `cmd` with its own copy of the shell state.
+    ///
+    /// Unlike `spawn`, the child runs the interpreter, which allocates. That is only sound
+    /// because the shell has no other threads when it forks; a subshell can't be prepared
+    /// beforehand.
+    fn subshell(
+        state: &mut State,
+        cmd: &Command,
+        streams: StreamSet,
+    ) -> Result<Process, ProcessSpawnError> {
+        let sys = state.sys.clone();
         unsafe {
-            match fork()? {
-                ForkResult::Parent { child } => {
-                    return Ok(Process {
-                        pid: child,
-                        attached,
-                        streams,
-                    })
-                }
+            match sys.fork()? {
+                ForkResult::Parent { child } => Ok(Process::new(&sys, child)),
                 ForkResult::Child => {
-                    let path = CString::new(cmd.args[0].as_str())?;
-                    /* let argv = cmd
-                    .args
-                    .into_iter()
-                    .skip(1)
-                    .map(|x| CString::new(x.as_str()) as Result<CString, NulError>)
-                    .collect();*/
-                    execv(path.as_c_str(), &[]);
-                    panic!("code after execv() should never happen!");
+                    streams.install(&*sys);
+                    close_shell_fds();
+                    state.subshell_depth += 1;
+                    let status = execute(state, cmd, StreamSet::std());
+                    libc::_exit(status);
                 }
             }
         }
     }
 }
 
-unsafe fn execute_single(
-    command: SingleCommand,
-    streams: StreamSet,
-) -> Result<i32, ProcessSpawnError> {
-    let p = Process::spawn(command, true, streams)?;
-    waitpid(p, WaitPidFlag::from_bits(WEXITED));
+/// Resolves a command name against the `PATH` in `env`. Names containing a slash are used as-is.
+fn find_executable(name: &str, env: &[String]) -> String {
+    if name.contains('/') {
+        return name.to_string();
+    }
+    let path = env
+        .iter()
+        .find_map(|v| v.strip_prefix("PATH="))
+        .unwrap_or("/usr/local/bin:/usr/bin:/bin");
+    for dir in path.split(':') {
+        let dir = if dir.is_empty() { "." } else { dir };
+        let candidate = format!("{}/{}", dir, name);
+        if nix::unistd::access(candidate.as_str(), nix::unistd::AccessFlags::X_OK).is_ok() {
+            return candidate;
+        }
+    }
+    name.to_string()
 }
 
-unsafe fn execute_binary(binary: BinaryExpr, streams: StreamSet) -> Result<i32, ProcessSpawnError> {
-    match binary.op {
-        BinaryOp::Fork => {
-            let (l, r) = streams.fork();
-            execute(*binary.first, l);
-            Ok(execute(*binary.second, r))
+/// Waits for a child to terminate and returns its exit status, 128 + the signal number if it was
+/// killed.
+fn wait_for(sys: &dyn Sys, pid: Pid) -> i32 {
+    loop {
+        match sys.waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => return code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => return 128 + signal as i32,
+            Ok(_) | Err(Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => return 127,
         }
+    }
+}
+
+/// Whether `watch_children` has installed its handler.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+/// Whether a child has terminated since `reap_jobs` last looked.
+static CHILD_EXITED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_child(_: libc::c_int) {
+    CHILD_EXITED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGCHLD handler, so that `reap_jobs` only checks on background jobs after a child
+/// has terminated.
+pub fn watch_children() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_child),
+        SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGCHLD, &action) }?;
+    WATCHING.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// How many statuses of reaped background jobs are kept for `wait`.
+const FINISHED_JOBS_LIMIT: usize = 64;
+
+/// Removes background jobs that have finished, keeping their statuses for `wait`. Jobs that
+/// can't be waited for at all are dropped without a status. Without `watch_children`, every job
+/// is checked each time.
+pub fn reap_jobs(state: &mut State) {
+    if WATCHING.load(Ordering::SeqCst) && !CHILD_EXITED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let finished = &mut state.finished_jobs;
+    state.jobs.retain_mut(|job| match job.try_wait() {
+        Ok(None) => true,
+        Ok(Some(status)) => {
+            if finished.len() == FINISHED_JOBS_LIMIT {
+                finished.pop_front();
+            }
+            finished.push_back((job.pid(), status));
+            false
+        }
+        Err(_) => false,
+    });
+}
+
+/// Reports a command that could not be executed, with 127 if it wasn't found and 126 otherwise,
+/// as bash does.
+fn report_exec_error(name: &str, err: ProcessSpawnError, streams: &StreamSet) -> i32 {
+    let errno = match err {
+        ProcessSpawnError::NixError(Error::Sys(errno)) => errno,
+        other => return report_spawn_error(other, streams),
+    };
+    let (message, status) = match errno {
+        Errno::ENOENT if !name.contains('/') => ("command not found", 127),
+        Errno::ENOENT => (errno.desc(), 127),
+        _ => (errno.desc(), 126),
+    };
+    streams.write_err(&format!("traash: {}: {}\n", name, message));
+    status
+}
+
+fn report_spawn_error(err: ProcessSpawnError, streams: &StreamSet) -> i32 {
+    match err {
+        ProcessSpawnError::NixError(e) => streams.write_err(&format!("traash: {}\n", e)),
+        ProcessSpawnError::NulError(e) => streams.write_err(&format!("traash: {}\n", e)),
+    }
+    126
+}
+
+fn call_function(state: &mut State, body: &Command, args: &[String], streams: StreamSet) -> i32 {
+    let limit = state.function_limit();
+    if state.function_depth() >= limit {
+        streams.write_err(&format!(
+            "traash: {}: maximum function nesting level exceeded ({})\n",
+            args[0], limit
+        ));
+        return 1;
+    }
+    let positional = std::mem::replace(&mut state.positional, args[1..].to_vec());
+    state.push_frame();
+    let status = execute(state, body, streams);
+    state.pop_frame();
+    state.positional = positional;
+    state.returning = false;
+    status
+}
+
+/// The expanded value of a `NAME=value` prefix.
+enum Value {
+    Scalar(String),
+    /// The elements of an array assignment like `NAME=(a b)`.
+    Array(Vec<String>),
+}
+
+impl Value {
+    /// The value as a plain variable, which for an array is its first element.
+    fn scalar(&self) -> &str {
+        match self {
+            Value::Scalar(value) => value,
+            Value::Array(elements) => elements.first().map_or("", |e| e.as_str()),
+        }
+    }
+}
+
+/// The expanded `NAME=value` prefixes and words of a simple command.
+type ExpandedCommand = (Vec<(String, Value)>, Vec<String>);
+
+/// Expands the assignments and words of a simple command.
+fn expand_single(
+    state: &mut State,
+    command: &SingleCommand,
+) -> Result<ExpandedCommand, ExpansionError> {
+    let split = command
+        .args
+        .iter()
+        .position(|w| split_assignment(w).is_none())
+        .unwrap_or(command.args.len());
+    let (assignments, words) = command.args.split_at(split);
+    let mut expanded = vec![];
+    for (name, value) in assignments.iter().filter_map(|w| split_assignment(w)) {
+        let value = match split_array(value) {
+            Some(words) => Value::Array(expand_words(state, &words)?),
+            None => Value::Scalar(expand_string(state, value)?),
+        };
+        expanded.push((name.to_string(), value));
+    }
+    Ok((expanded, expand_words(state, words)?))
+}
+
+/// Traces an expanded simple command for `set -x`: each assignment on a line of its own, then
+/// the words.
+fn trace_single(state: &mut State, expanded: &ExpandedCommand, streams: &StreamSet) {
+    let (assignments, args) = expanded;
+    for (name, value) in assignments {
+        let value = match value {
+            Value::Scalar(value) => quote_word(value).into_owned(),
+            Value::Array(elements) => {
+                let elements: Vec<_> = elements.iter().map(|e| quote_word(e)).collect();
+                format!("({})", elements.join(" "))
+            }
+        };
+        trace(state, &format!("{}={}", name, value), streams);
+    }
+    if !args.is_empty() {
+        let words: Vec<_> = args.iter().map(|a| quote_word(a)).collect();
+        trace(state, &words.join(" "), streams);
+    }
+}
+
+fn execute_single(state: &mut State, command: &SingleCommand, streams: StreamSet) -> i32 {
+    reap_jobs(state);
+    state.substitution_status = None;
+    let (assignments, args) = match expand_single(state, command) {
+        Ok(expanded) if state.xtrace => {
+            trace_single(state, &expanded, &streams);
+            expanded
+        }
+        Ok(expanded) => expanded,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}\n", e));
+            return 1;
+        }
+    };
+
+    if args.is_empty() {
+        for (name, value) in assignments {
+            match value {
+                Value::Scalar(value) => state.set_var(&name, &value),
+                Value::Array(elements) => state.set_array(&name, elements),
+            }
+        }
+        return state.substitution_status.unwrap_or(0);
+    }
+
+    if let Some(body) = state.functions.get(&args[0]).cloned() {
+        return with_temporary_vars(state, &assignments, |state| {
+            call_function(state, &body, &args, streams)
+        });
+    }
+
+    let builtin = state.builtins.get(&args[0]).copied();
+    if let Some(builtin) = builtin.or_else(|| builtins::find(&args[0])) {
+        return with_temporary_vars(state, &assignments, |state| builtin(state, &args, &streams));
+    }
+
+    let mut env = state.environment();
+    env.extend(
+        assignments
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value.scalar())),
+    );
+    match Process::spawn(&state.sys, &args, &env, streams) {
+        Ok(mut p) => p.wait(),
+        Err(e) => report_exec_error(&args[0], e, &streams),
+    }
+}
+
+/// Replaces the shell with the command `args`, for `exec`. Returns a status only if the command
+/// can't be executed.
+pub(crate) fn exec(state: &State, args: &[String], streams: StreamSet) -> i32 {
+    let env = state.environment();
+    let fds = [streams.stdin, streams.stdout, streams.stderr];
+    match Spawn::new(&find_executable(&args[0], &env), args, &env, fds) {
+        Ok(spawn) => report_exec_error(&args[0], state.sys.exec(&spawn).into(), &streams),
+        Err(e) => report_spawn_error(e.into(), &streams),
+    }
+}
+
+/// Runs `f` with `assignments` applied to the shell variables, restoring them afterwards.
+///
+/// This is not a function call: `local` and `return` behave as they would without the
+/// assignments.
+fn with_temporary_vars<F>(state: &mut State, assignments: &[(String, Value)], f: F) -> i32
+where
+    F: FnOnce(&mut State) -> i32,
+{
+    let mut saved = Vec::with_capacity(assignments.len());
+    for (name, value) in assignments {
+        saved.push((name, state.save_var(name)));
+        state.set_var(name, value.scalar());
+        state.export_var(name);
+    }
+    let status = f(state);
+    // In reverse, so a name assigned twice gets back the value from before the first.
+    for (name, var) in saved.into_iter().rev() {
+        state.restore_var(name, var);
+    }
+    status
+}
+
+/// Starts `cmd` in a subshell without waiting for it, keeping it as a job.
+fn execute_background(state: &mut State, cmd: &Command, streams: StreamSet) -> i32 {
+    let (background, _) = streams.fork();
+    match Process::subshell(state, cmd, background) {
+        Ok(mut p) => {
+            p.detach();
+            // A status kept for an earlier job with the same pid no longer applies.
+            state.finished_jobs.retain(|(pid, _)| *pid != p.pid);
+            state.last_background = Some(p.pid);
+            state.jobs.push(p);
+        }
+        Err(e) => {
+            report_spawn_error(e, &streams);
+        }
+    }
+    0
+}
+
+fn execute_binary(state: &mut State, binary: &BinaryExpr, streams: StreamSet) -> i32 {
+    match binary.op {
         BinaryOp::Seq => {
-            let first = execute(*binary.first, streams);
-            waitpid(first, WaitPidFlag::from_bits(WEXITED));
-            let second = execute(*binary.second, streams.clone());
-            waitpid(second, WaitPidFlag::from_bits(WEXITED));
+            execute(state, &binary.first, streams);
+            if state.returning {
+                return state.last_status;
+            }
+            execute(state, &binary.second, streams)
         }
         BinaryOp::Pipe => {
-            let (left, right) = streams.pipe();
-            let first = execute(*binary.first, left);
-            let second = execute(*binary.second, right);
-            waitpid(second, WaitPidFlag::from_bits(WEXITED));
+            let sys = state.sys.clone();
+            let (left, right) = match streams.pipe(&*sys) {
+                Ok(pipe) => pipe,
+                Err(e) => return report_spawn_error(e.into(), &streams),
+            };
+            let first = Process::subshell(state, &binary.first, left);
+            let _ = sys.close(left.stdout.unwrap());
+            let second = Process::subshell(state, &binary.second, right);
+            let _ = sys.close(right.stdin.unwrap());
+            if let Ok(mut first) = first {
+                first.wait();
+            }
+            match second {
+                Ok(mut second) => second.wait(),
+                Err(e) => report_spawn_error(e, &streams),
+            }
+        }
+        BinaryOp::LogAnd => {
+            let status = execute(state, &binary.first, streams);
+            if status != 0 || state.returning {
+                return status;
+            }
+            execute(state, &binary.second, streams)
+        }
+        BinaryOp::LogOr => {
+            let status = execute(state, &binary.first, streams);
+            if status == 0 || state.returning {
+                return status;
+            }
+            execute(state, &binary.second, streams)
         }
-        BinaryOp::LogAnd => {}
-        BinaryOp::LogOr => {}
     }
 }
 
-pub fn execute(cmd: Command, streams: StreamSet) {
-    unsafe {
-        match cmd {
-            Command::Nil => {}
-            Command::Single(c) => execute_single(c, streams),
-            Command::BinaryExpr(c) => execute_binary(c, streams),
-            Command::FileInput(_) => {}
-            Command::FileOutput(_) => {}
+/// Opens the file or finds the descriptor a redirection points at. Returns the new descriptor
+/// and whether the shell opened it and has to close it afterwards.
+fn open_redirection(
+    state: &mut State,
+    redirection: &Redirection,
+    streams: &StreamSet,
+) -> Result<(Option<RawFd>, bool), String> {
+    let fields = expand_words(state, std::slice::from_ref(&redirection.target))
+        .map_err(|e| e.to_string())?;
+    let target = match fields.as_slice() {
+        [target] => target,
+        _ => return Err(format!("{}: ambiguous redirect", redirection.target)),
+    };
+    let flags = match redirection.kind {
+        RedirectKind::Read => OFlag::O_RDONLY,
+        RedirectKind::Write => OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+        RedirectKind::Append => OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND,
+        RedirectKind::DupInput | RedirectKind::DupOutput => {
+            return match target.as_str() {
+                "-" => Ok((None, false)),
+                _ => match target.parse::<i32>() {
+                    Ok(fd) if fd >= 0 => Ok((streams.get(fd), false)),
+                    _ => Err(format!("{}: ambiguous redirect", target)),
+                },
+            };
         }
+    };
+    let mode = Mode::from_bits_truncate(0o666);
+    match state
+        .sys
+        .open(target.as_str(), flags | OFlag::O_CLOEXEC, mode)
+    {
+        Ok(fd) => Ok((Some(fd), true)),
+        Err(Error::Sys(errno)) => Err(format!("{}: {}", target, errno.desc())),
+        Err(e) => Err(format!("{}: {}", target, e)),
+    }
+}
+
+/// Points the shell's own descriptor `fd` at `target`, closing it for `None`.
+fn replace_fd(sys: &dyn Sys, fd: RawFd, target: Option<RawFd>) -> nix::Result<()> {
+    match target {
+        Some(target) if target == fd => Ok(()),
+        Some(target) => sys.dup2(target, fd).map(drop),
+        None => match sys.close(fd) {
+            Err(Error::Sys(Errno::EBADF)) => Ok(()),
+            result => result,
+        },
     }
 }
+
+/// Points the shell's own descriptor `fd` at `target`, so that it is inherited by commands, and
+/// adds what it was to `saved` if it is to be put back. A file in `opened` that landed on `fd`
+/// itself is moved out of the way first, since it is closed on exec.
+fn redirect_shell_fd(
+    sys: &dyn Sys,
+    fd: RawFd,
+    mut target: Option<RawFd>,
+    opened: &mut Vec<RawFd>,
+    saved: Option<&mut Vec<(RawFd, Option<RawFd>)>>,
+) -> nix::Result<()> {
+    if target == Some(fd) && opened.last() == Some(&fd) {
+        let moved = sys.dup(fd)?;
+        opened.pop();
+        opened.push(moved);
+        sys.close(fd)?;
+        target = Some(moved);
+    }
+    if let Some(saved) = saved {
+        match sys.dup(fd) {
+            Ok(old) => saved.push((fd, Some(old))),
+            Err(Error::Sys(Errno::EBADF)) => saved.push((fd, None)),
+            Err(e) => return Err(e),
+        }
+    }
+    replace_fd(sys, fd, target)
+}
+
+/// Whether `command` is `exec` without a command to run, whose redirections stay in place in the
+/// shell.
+fn is_bare_exec(command: &Command) -> bool {
+    matches!(command, Command::Single(single) if single.args == ["exec"])
+}
+
+/// Runs a command with redirections. Descriptors 0 to 2 are redirected in the streams the command
+/// gets; higher ones are the shell's own, so they are redirected in the shell itself and put back
+/// afterwards, except for a bare `exec`.
+fn execute_redirect(state: &mut State, redirect: &Redirect, streams: StreamSet) -> i32 {
+    let sys = state.sys.clone();
+    let permanent = is_bare_exec(&redirect.command);
+    let mut redirected = streams;
+    let mut opened = vec![];
+    // The shell's descriptors as they were, with `None` for those that were closed.
+    let mut saved = vec![];
+    let mut result = Ok(());
+    for redirection in &redirect.redirections {
+        let (fd, owned) = match open_redirection(state, redirection, &redirected) {
+            Ok(opened) => opened,
+            Err(message) => {
+                result = Err(message);
+                break;
+            }
+        };
+        if owned {
+            opened.extend(fd);
+        }
+        if !permanent && redirected.set(redirection.fd, fd) {
+            continue;
+        }
+        let replaced = redirect_shell_fd(
+            &*sys,
+            redirection.fd,
+            fd,
+            &mut opened,
+            if permanent { None } else { Some(&mut saved) },
+        );
+        if let Err(e) = replaced {
+            result = Err(match e {
+                Error::Sys(errno) => format!("{}: {}", redirection.target, errno.desc()),
+                e => format!("{}: {}", redirection.target, e),
+            });
+            break;
+        }
+    }
+    let status = match result {
+        Ok(()) => execute(state, &redirect.command, redirected),
+        Err(message) => {
+            streams.write_err(&format!("traash: {}\n", message));
+            1
+        }
+    };
+    for (fd, saved) in saved.into_iter().rev() {
+        let _ = replace_fd(&*sys, fd, saved);
+        if let Some(saved) = saved {
+            let _ = sys.close(saved);
+        }
+    }
+    for fd in opened {
+        let _ = sys.close(fd);
+    }
+    status
+}
+
+/// Runs an `(( expr ))` command, which succeeds when the expression is nonzero.
+fn execute_arith(state: &mut State, expr: &str, streams: StreamSet) -> i32 {
+    let result = expand_string(state, expr)
+        .and_then(|expr| arith::evaluate(state, &expr).map_err(|e| ExpansionError(e.to_string())));
+    match result {
+        Ok(0) => 1,
+        Ok(_) => 0,
+        Err(e) => {
+            streams.write_err(&format!("traash: {}\n", e));
+            1
+        }
+    }
+}
+
+pub fn execute(state: &mut State, cmd: &Command, streams: StreamSet) -> i32 {
+    let status = match cmd {
+        Command::Nil => state.last_status,
+        Command::Single(c) => execute_single(state, c, streams),
+        Command::BinaryExpr(c) => execute_binary(state, c, streams),
+        Command::Background(c) => execute_background(state, c, streams),
+        Command::Not(c) => match execute(state, c, streams) {
+            status if state.returning => status,
+            0 => 1,
+            _ => 0,
+        },
+        Command::Redirect(r) => execute_redirect(state, r, streams),
+        Command::Subshell(body) => match Process::subshell(state, body, streams) {
+            Ok(mut p) => p.wait(),
+            Err(e) => report_spawn_error(e, &streams),
+        },
+        Command::Group(body) => execute(state, body, streams),
+        Command::Arith(expr) => execute_arith(state, expr, streams),
+        Command::Conditional(expr) => match conditional::evaluate(state, expr) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                streams.write_err(&format!("traash: {}\n", e));
+                2
+            }
+        },
+        Command::FunctionDef(f) => {
+            state.functions.insert(f.name.clone(), (*f.body).clone());
+            0
+        }
+        // Only trees parsed past their errors have these, and those aren't run.
+        Command::Error => 2,
+    };
+    state.last_status = status;
+    status
+}
+
+/// Lexes, substitutes aliases in, parses and executes `input`, reporting syntax errors on stderr
+/// with status 2.
+pub fn run_string(state: &mut State, input: &str, streams: StreamSet) -> i32 {
+    let tokens = match lex(input) {
+        Ok(tokens) => alias::substitute(tokens, &state.aliases),
+        Err(e) => {
+            streams.write_err(&format!("traash: syntax error: {}\n", e));
+            return 2;
+        }
+    };
+    match parse(&tokens) {
+        Ok(cmd) => execute(state, &cmd, streams),
+        Err(e) => {
+            streams.write_err(&format!("traash: syntax error: {}\n", e));
+            2
+        }
+    }
+}
+
+/// Runs the commands in the file at `path` in the current shell, as `.` does. A `return` in the
+/// file ends just the file.
+pub fn source_file(state: &mut State, path: &str, streams: StreamSet) -> std::io::Result<i32> {
+    let input = std::fs::read_to_string(path)?;
+    echo_input(state, &input, &streams);
+    state.source_depth += 1;
+    let status = run_string(state, &input, streams);
+    state.source_depth -= 1;
+    state.returning = false;
+    Ok(status)
+}
+
+/// Runs `input` in a subshell and returns what it wrote to stdout, minus trailing newlines, for
+/// command substitution.
+///
+/// Like `Process::subshell`, the child runs the interpreter, relying on the shell having no
+/// other threads when it forks.
+pub fn capture_output(state: &mut State, input: &str) -> String {
+    let sys = state.sys.clone();
+    let (r, w) = match sys.pipe() {
+        Ok(fds) => fds,
+        Err(_) => return String::new(),
+    };
+    let streams = StreamSet {
+        stdin: Some(0),
+        stdout: Some(w),
+        stderr: Some(2),
+    };
+    let child = match sys.fork() {
+        Ok(ForkResult::Child) => {
+            streams.install(&*sys);
+            close_shell_fds();
+            state.subshell_depth += 1;
+            let status = run_string(state, input, StreamSet::std());
+            unsafe { libc::_exit(status) }
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(_) => {
+            let _ = sys.close(r);
+            let _ = sys.close(w);
+            return String::new();
+        }
+    };
+    let _ = sys.close(w);
+    let mut output = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        match read(r, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(Error::Sys(nix::errno::Errno::EINTR)) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = sys.close(r);
+    let status = wait_for(&*sys, child);
+    state.last_status = status;
+    state.substitution_status = Some(status);
+    let mut output = String::from_utf8_lossy(&output).into_owned();
+    while output.ends_with('\n') {
+        output.pop();
+    }
+    output
+}
+
+#[cfg(test)]
+fn run(state: &mut State, input: &str) -> i32 {
+    let streams = StreamSet {
+        stdin: Some(0),
+        stdout: Some(1),
+        stderr: None,
+    };
+    run_string(state, input, streams)
+}
+
+#[test]
+fn functions_see_their_own_positional_parameters() {
+    let mut state = State::new();
+    state.positional = vec!["outer".to_string()];
+
+    run(&mut state, "f() { first=$1; count=$#; }; f a b c");
+
+    assert_eq!(state.get_var("first"), Some("a"));
+    assert_eq!(state.get_var("count"), Some("3"));
+    assert_eq!(state.positional, vec!["outer".to_string()]);
+}
+
+#[test]
+fn locals_are_dynamically_scoped() {
+    let mut state = State::new();
+
+    run(
+        &mut state,
+        "x=global; inner() { seen=$x; }; outer() { local x=outer; inner; }; outer",
+    );
+
+    assert_eq!(state.get_var("seen"), Some("outer"));
+    assert_eq!(state.get_var("x"), Some("global"));
+}
+
+#[test]
+fn return_stops_function_with_status() {
+    let mut state = State::new();
+
+    let status = run(&mut state, "f() { a=1; return 3; a=2; }; f");
+
+    assert_eq!(status, 3);
+    assert_eq!(state.get_var("a"), Some("1"));
+    assert!(!state.returning);
+}
+
+#[test]
+fn temporary_assignments_are_not_function_calls() {
+    let mut state = State::new();
+
+    let status = run(&mut state, "x=1 return; after=1");
+    assert_eq!(status, 0);
+    assert_eq!(state.get_var("after"), Some("1"));
+    assert!(!state.returning);
+
+    let status = run(&mut state, "x=1 local y=2");
+    assert_eq!(status, 1);
+    assert_eq!(state.get_var("y"), None);
+    assert_eq!(state.get_var("x"), None);
+}
+
+#[test]
+fn temporary_assignments_are_restored() {
+    let mut state = State::new();
+
+    run(
+        &mut state,
+        "x=old; f() { seen=$x; local x=inner; }; x=1 x=2 f",
+    );
+
+    assert_eq!(state.get_var("seen"), Some("2"));
+    assert_eq!(state.get_var("x"), Some("old"));
+}
+
+#[test]
+fn recursion_is_limited_by_funcnest() {
+    let mut state = State::new();
+
+    let status = run(&mut state, "FUNCNEST=5; n=0; f() { n=$n.; f; }; f");
+
+    assert_eq!(status, 1);
+    assert_eq!(state.get_var("n"), Some("0....."));
+}
+
+#[test]
+fn command_substitution_captures_function_output() {
+    let mut state = State::new();
+
+    run(&mut state, "greet() { echo hello $1; }; x=$(greet world)");
+
+    assert_eq!(state.get_var("x"), Some("hello world"));
+}
+
+#[test]
+fn subshells_do_not_change_parent_state() {
+    let mut state = State::new();
+
+    run(&mut state, "x=1; (x=2; inner=$x); { y=3; }");
+
+    assert_eq!(state.get_var("x"), Some("1"));
+    assert_eq!(state.get_var("inner"), None);
+    assert_eq!(state.get_var("y"), Some("3"));
+}
+
+#[test]
+fn redirects_compound_commands() {
+    let mut state = State::new();
+    let dir = std::env::temp_dir().join(format!("traash-redirect-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    state.set_var("d", &dir.to_string_lossy());
+
+    run(
+        &mut state,
+        "{ echo out; ls /nonexistent; } > $d/log 2>&1; (echo again) >> $d/log; x=$(cat < $d/log)",
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    let x = state.get_var("x").unwrap();
+    assert!(x.starts_with("out\n"));
+    assert!(x.contains("nonexistent"));
+    assert!(x.ends_with("\nagain"));
+}
+
+#[test]
+fn evaluates_arithmetic_commands_and_expansions() {
+    let mut state = State::new();
+
+    let status = run(&mut state, "i=2; (( i *= 3 )); x=$(( i + 0x10 ))$((1 > 2))");
+
+    assert_eq!(status, 0);
+    assert_eq!(state.get_var("x"), Some("220"));
+    assert_eq!(run(&mut state, "(( i - 6 ))"), 1);
+    assert_eq!(run(&mut state, "(( i / 0 ))"), 1);
+    assert_eq!(run(&mut state, "y=$(( 1 / 0 )) && reached=1"), 1);
+    assert_eq!(state.get_var("y"), None);
+    assert_eq!(state.get_var("reached"), None);
+}
+
+#[test]
+fn functions_run_in_pipelines() {
+    let mut state = State::new();
+
+    run(
+        &mut state,
+        "shout() { echo $1 $1; }; x=$(shout hey | shout); y=$(shout ho | cat)",
+    );
+
+    assert_eq!(state.get_var("x"), Some(""));
+    assert_eq!(state.get_var("y"), Some("ho ho"));
+}
+
+#[test]
+fn evaluates_test_and_conditional_commands() {
+    let mut state = State::new();
+    state.set_var("file", "my file");
+
+    assert_eq!(run(&mut state, "[ -n \"$file\" -a 2 -gt 1 ]"), 0);
+    assert_eq!(run(&mut state, "test \"$file\" = my"), 1);
+    assert_eq!(run(&mut state, "[ 1 -eq 1"), 2);
+    assert_eq!(run(&mut state, "[[ $file == my* && $file != \"my*\" ]]"), 0);
+    assert_eq!(run(&mut state, "[[ $file == \"my\"* ]]"), 0);
+    assert_eq!(run(&mut state, "[[ 1+2 -eq 3 || $(exit 1) ]]"), 0);
+    assert_eq!(
+        run(&mut state, "[[ -z $unset ]] && [[ ! -d /nonexistent ]]"),
+        0
+    );
+}
+
+#[test]
+fn regex_matches_fill_bash_rematch() {
+    let mut state = State::new();
+    state.set_var("version", "v1.25");
+
+    assert_eq!(
+        run(&mut state, "[[ $version =~ ^v([0-9]+)\\.([0-9]+)$ ]]"),
+        0
+    );
+    assert_eq!(state.elements("BASH_REMATCH"), vec!["v1.25", "1", "25"]);
+    assert_eq!(run(&mut state, "[[ $version =~ 'v.' ]]"), 1);
+    assert!(state.elements("BASH_REMATCH").is_empty());
+}
+
+#[test]
+fn sourced_files_run_in_the_current_shell() {
+    let path = std::env::temp_dir().join(format!("traash-source-{}", nix::unistd::getpid()));
+    std::fs::write(&path, "x=$1\nreturn 3\nx=after\n").unwrap();
+    let mut state = State::new();
+    state.positional = vec!["outer".to_string()];
+    let path = path.to_string_lossy().into_owned();
+
+    assert_eq!(run(&mut state, &format!(". {} inner", path)), 3);
+    assert_eq!(state.get_var("x"), Some("inner"));
+    assert_eq!(state.positional, vec!["outer"]);
+    assert_eq!(run(&mut state, "return"), 1);
+    assert_eq!(run(&mut state, "source /nonexistent/file"), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Expects the calls the executor makes, in order, and returns them as a shell state.
+#[cfg(test)]
+struct Expect {
+    sys: crate::sys::MockSys,
+    sequence: mockall::Sequence,
+}
+
+#[cfg(test)]
+impl Expect {
+    fn new() -> Expect {
+        Expect {
+            sys: crate::sys::MockSys::new(),
+            sequence: mockall::Sequence::new(),
+        }
+    }
+
+    fn pipe(mut self, r: RawFd, w: RawFd) -> Expect {
+        self.sys
+            .expect_pipe()
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move || Ok((r, w)));
+        self
+    }
+
+    fn fork(mut self, child: i32) -> Expect {
+        let child = Pid::from_raw(child);
+        self.sys
+            .expect_fork()
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move || Ok(ForkResult::Parent { child }));
+        self
+    }
+
+    fn spawn(mut self, child: i32, args: &'static [&'static str], fds: [RawFd; 3]) -> Expect {
+        let child = Pid::from_raw(child);
+        self.sys
+            .expect_spawn()
+            .withf(move |spawn| {
+                spawn
+                    .argv
+                    .iter()
+                    .map(|a| a.to_str().unwrap())
+                    .eq(args.iter().copied())
+                    && spawn.fds == [Some(fds[0]), Some(fds[1]), Some(fds[2])]
+            })
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move |_| Ok(child));
+        self
+    }
+
+    fn close(mut self, fd: RawFd) -> Expect {
+        self.sys
+            .expect_close()
+            .with(mockall::predicate::eq(fd))
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(|_| Ok(()));
+        self
+    }
+
+    fn open(mut self, path: &'static str, flags: OFlag, fd: RawFd) -> Expect {
+        self.sys
+            .expect_open()
+            .withf(move |p, f, _| p == path && *f == flags | OFlag::O_CLOEXEC)
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move |_, _, _| Ok(fd));
+        self
+    }
+
+    fn wait(mut self, child: i32, code: i32) -> Expect {
+        let child = Pid::from_raw(child);
+        self.sys
+            .expect_waitpid()
+            .withf(move |pid, flags| *pid == child && flags.is_none())
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move |pid, _| Ok(WaitStatus::Exited(pid, code)));
+        self
+    }
+
+    fn poll(mut self, child: i32, status: WaitStatus) -> Expect {
+        let child = Pid::from_raw(child);
+        self.sys
+            .expect_waitpid()
+            .withf(move |pid, flags| *pid == child && *flags == Some(WaitPidFlag::WNOHANG))
+            .times(1)
+            .in_sequence(&mut self.sequence)
+            .returning(move |_, _| Ok(status));
+        self
+    }
+
+    fn state(self) -> State {
+        let mut state = State::new();
+        state.sys = SysRef::new(self.sys);
+        state
+    }
+}
+
+#[test]
+fn pipelines_connect_two_subshells() {
+    let mut state = Expect::new()
+        .pipe(10, 11)
+        .fork(100)
+        .close(11)
+        .fork(101)
+        .close(10)
+        .wait(100, 0)
+        .wait(101, 3)
+        .state();
+
+    assert_eq!(run(&mut state, "producer | consumer"), 3);
+}
+
+#[test]
+fn pipelines_report_failing_pipes() {
+    let mut expect = Expect::new();
+    expect
+        .sys
+        .expect_pipe()
+        .times(1)
+        .returning(|| Err(Error::Sys(Errno::EMFILE)));
+    let mut state = expect.state();
+
+    assert_eq!(run(&mut state, "producer | consumer"), 126);
+}
+
+#[test]
+fn and_or_lists_skip_commands_by_status() {
+    let mut state = Expect::new()
+        .spawn(100, &["failing"], [0, 1, 1])
+        .wait(100, 1)
+        .state();
+    assert_eq!(run(&mut state, "failing 2>&1 && skipped"), 1);
+
+    let mut state = Expect::new()
+        .spawn(100, &["failing"], [0, 1, 1])
+        .wait(100, 1)
+        .spawn(101, &["fallback", "x"], [0, 1, 1])
+        .wait(101, 0)
+        .state();
+    assert_eq!(run(&mut state, "failing 2>&1 || fallback x 2>&1"), 0);
+}
+
+#[test]
+fn background_commands_are_not_waited_for() {
+    let mut state = Expect::new()
+        .fork(100)
+        .poll(100, WaitStatus::StillAlive)
+        .spawn(101, &["client"], [0, 1, 1])
+        .wait(101, 0)
+        .state();
+
+    assert_eq!(run(&mut state, "server & client 2>&1"), 0);
+    assert_eq!(state.jobs.len(), 1);
+    assert_eq!(state.jobs[0].pid(), Pid::from_raw(100));
+    assert_eq!(state.last_background, Some(Pid::from_raw(100)));
+}
+
+#[test]
+fn background_statuses_are_recorded_for_wait() {
+    let server = Pid::from_raw(100);
+    let mut state = Expect::new()
+        .fork(100)
+        .poll(100, WaitStatus::StillAlive)
+        .poll(100, WaitStatus::Exited(server, 5))
+        .state();
+
+    assert_eq!(run(&mut state, "server &"), 0);
+    reap_jobs(&mut state);
+    assert_eq!(state.jobs.len(), 1);
+    reap_jobs(&mut state);
+    assert!(state.jobs.is_empty());
+    assert_eq!(run(&mut state, "wait $! 2>&1"), 5);
+    assert!(state.finished_jobs.is_empty());
+    // Dropping the state doesn't wait for or kill detached jobs, which the mock would reject.
+}
+
+#[test]
+fn dropping_a_process_waits_for_it_without_killing_it() {
+    let state = Expect::new().wait(100, 0).state();
+    let process = Process::new(&state.sys, Pid::from_raw(100));
+    drop(process);
+
+    let state = Expect::new()
+        .poll(100, WaitStatus::Exited(Pid::from_raw(100), 2))
+        .state();
+    let mut process = Process::new(&state.sys, Pid::from_raw(100));
+    assert_eq!(process.try_wait(), Ok(Some(2)));
+    assert_eq!(process.wait(), 2);
+    assert!(process.kill(Signal::SIGTERM).is_err());
+}
+
+#[test]
+fn redirections_open_and_close_files_around_the_command() {
+    let write = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC;
+    let mut state = Expect::new()
+        .open("out", write, 5)
+        .open("in", OFlag::O_RDONLY, 6)
+        .spawn(100, &["sort", "-r"], [6, 5, 5])
+        .wait(100, 0)
+        .close(5)
+        .close(6)
+        .state();
+
+    assert_eq!(run(&mut state, "sort -r > out < in 2>&1"), 0);
+}
+
+#[test]
+fn reports_commands_that_cannot_run() {
+    let mut state = State::new();
+    assert_eq!(
+        capture_output(&mut state, "traash-no-such-command 2>&1; echo $?"),
+        "traash: traash-no-such-command: command not found\n127"
+    );
+    assert_eq!(
+        capture_output(&mut state, "/nonexistent/cmd 2>&1; echo $?"),
+        "traash: /nonexistent/cmd: No such file or directory\n127"
+    );
+    assert_eq!(
+        capture_output(&mut state, "/ 2>&1; echo $?"),
+        "traash: /: Permission denied\n126"
+    );
+}
+
+#[test]
+fn background_jobs_can_be_killed_and_waited_for() {
+    let mut state = State::new();
+    assert_eq!(
+        capture_output(&mut state, "sleep 5 >/dev/null & kill $!; wait $!; echo $?"),
+        "143"
+    );
+    assert_eq!(
+        capture_output(
+            &mut state,
+            "(exit 3) & wait $!; echo $?; wait 1 2>&1; echo $?"
+        ),
+        "3\ntraash: wait: pid 1 is not a child of this shell\n127"
+    );
+}
+
+#[test]
+fn redirects_descriptors_above_stderr() {
+    let mut state = State::new();
+    let path = std::env::temp_dir().join(format!("traash-fds-{}", nix::unistd::getpid()));
+    state.set_var("file", path.to_str().unwrap());
+    assert_eq!(
+        capture_output(
+            &mut state,
+            "{ echo a >&3; } 3>&1; sh -c 'echo b >&4' 4>&1; echo c 3>&1 >&3"
+        ),
+        "a\nb\nc"
+    );
+    assert_eq!(
+        capture_output(
+            &mut state,
+            "exec 3>$file; echo d >&3; sh -c 'echo e >&3'; exec 3>&-; cat $file"
+        ),
+        "d\ne"
+    );
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(capture_output(&mut state, "exec echo f; echo g"), "f");
+}
+
+#[test]
+fn spawned_commands_get_default_signal_handling() {
+    let mut state = State::new();
+    // `yes` only stops when SIGPIPE kills it.
+    assert_eq!(capture_output(&mut state, "yes | head -n 1"), "y");
+}
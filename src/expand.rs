@@ -0,0 +1,625 @@
+use std::fmt;
+
+use crate::arith;
+use crate::executor::capture_output;
+use crate::lexer::{lex, read_quoted, read_substitution};
+use crate::state::State;
+use crate::tokens::Token;
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Characters with a special meaning in shell patterns.
+const PATTERN_SPECIAL: &str = "*?[]\\";
+
+/// Characters with a special meaning in POSIX extended regular expressions.
+const REGEX_SPECIAL: &str = "\\.[]()*+?{}|^$";
+
+/// An expansion that fails aborts the command it is part of.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ExpansionError(pub String);
+
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Accumulates the fields a word expands to.
+struct Fields {
+    fields: Vec<String>,
+    current: String,
+    /// Whether `current` is a field even if it is empty, e.g. because it contained quotes.
+    started: bool,
+    /// Whether unquoted IFS whitespace was seen and the next character starts a new field.
+    pending_break: bool,
+    /// Whether unquoted expansions are split into fields.
+    split: bool,
+    /// Characters escaped with a backslash when they are quoted, so that a pattern built from the
+    /// word matches them literally.
+    escape: &'static str,
+}
+
+impl Fields {
+    fn new(split: bool, escape: &'static str) -> Fields {
+        Fields {
+            fields: vec![],
+            current: String::new(),
+            started: false,
+            pending_break: false,
+            split,
+            escape,
+        }
+    }
+
+    fn push_literal(&mut self, text: &str) {
+        if self.pending_break {
+            self.end_field();
+        }
+        self.current.push_str(text);
+        self.started = true;
+    }
+
+    /// Appends quoted text, which never acts as a pattern.
+    fn push_quoted(&mut self, text: &str) {
+        if self.escape.is_empty() {
+            return self.push_literal(text);
+        }
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if self.escape.contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        self.push_literal(&escaped);
+    }
+
+    /// Appends the result of an unquoted expansion, splitting it on the characters of `ifs`.
+    fn push_split(&mut self, text: &str, ifs: &str) {
+        if !self.split {
+            return self.push_literal(text);
+        }
+        for c in text.chars() {
+            if !ifs.contains(c) {
+                if self.pending_break {
+                    self.end_field();
+                }
+                self.current.push(c);
+                self.started = true;
+            } else if c.is_whitespace() {
+                self.pending_break = true;
+            } else {
+                self.started = true;
+                self.end_field();
+            }
+        }
+    }
+
+    fn end_field(&mut self) {
+        if self.started {
+            self.fields.push(std::mem::take(&mut self.current));
+        }
+        self.started = false;
+        self.pending_break = false;
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.end_field();
+        self.fields
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Whether `name` is a valid variable name.
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if is_name_start(c) => chars.all(is_name_char),
+        _ => false,
+    }
+}
+
+/// Splits an assignment word like `NAME=value` into its name and unexpanded value.
+pub fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let eq = word.find('=')?;
+    if is_name(&word[..eq]) {
+        Some((&word[..eq], &word[eq + 1..]))
+    } else {
+        None
+    }
+}
+
+/// Quotes `text` in single quotes, so that it expands to itself as one word.
+pub fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Splits the value of an array assignment like `NAME=(a "b c")` into its unexpanded words, or
+/// returns `None` if the value is not an array.
+pub fn split_array(value: &str) -> Option<Vec<String>> {
+    let inner = value.strip_prefix('(')?.strip_suffix(')')?;
+    let tokens = lex(inner).ok()?;
+    Some(
+        tokens
+            .into_iter()
+            .filter_map(|token| match token {
+                Token::Text(word) => Some(word),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Looks up a named or special parameter. `$@` and `$*` are joined with spaces here; callers that
+/// need them as separate fields handle them before calling this.
+fn parameter(state: &State, name: &str) -> Option<String> {
+    match name {
+        "?" => Some(state.last_status.to_string()),
+        "$" => Some(nix::unistd::getpid().to_string()),
+        "!" => state.last_background.map(|pid| pid.to_string()),
+        "#" => Some(state.positional.len().to_string()),
+        "0" => Some(state.name.clone()),
+        "@" | "*" => Some(state.positional.join(" ")),
+        _ => match name.parse::<usize>() {
+            Ok(n) => n
+                .checked_sub(1)
+                .and_then(|i| state.positional.get(i).cloned()),
+            Err(_) => state.get_var(name).map(|v| v.to_string()),
+        },
+    }
+}
+
+/// Reads the parameter name following a `$`, returning the rest of the input and the name.
+fn read_parameter_name(in_str: &str) -> (&str, &str) {
+    let mut chars = in_str.char_indices();
+    match chars.next() {
+        Some((_, c)) if is_name_start(c) => {
+            let end = chars
+                .find(|(_, c)| !is_name_char(*c))
+                .map_or(in_str.len(), |(i, _)| i);
+            (&in_str[end..], &in_str[..end])
+        }
+        Some((_, c)) if c.is_ascii_digit() || "?$!#@*-".contains(c) => (&in_str[1..], &in_str[..1]),
+        _ => (in_str, ""),
+    }
+}
+
+/// Splits a `[subscript]` off the start of `in_str`, returning the rest and the subscript.
+fn read_subscript(in_str: &str) -> Option<(&str, &str)> {
+    let inner = in_str.strip_prefix('[')?;
+    let end = inner.find(']')?;
+    Some((&inner[end + 1..], &inner[..end]))
+}
+
+/// Looks up `name[subscript]`, where the subscript is an arithmetic expression. Negative indices
+/// count from the end, and `@` or `*` join every element with spaces.
+fn element(
+    state: &mut State,
+    name: &str,
+    subscript: &str,
+) -> Result<Option<String>, ExpansionError> {
+    let elements = state.elements(name);
+    if subscript == "@" || subscript == "*" {
+        return Ok(match elements.is_empty() {
+            true => None,
+            false => Some(elements.join(" ")),
+        });
+    }
+    let index = expand_arithmetic(state, subscript)?
+        .parse::<i64>()
+        .unwrap_or(0);
+    let index = match index < 0 {
+        true => index + elements.len() as i64,
+        false => index,
+    };
+    match index < 0 {
+        true => Ok(None),
+        false => Ok(elements.get(index as usize).cloned()),
+    }
+}
+
+/// Expands the inside of a `${...}` expression.
+fn expand_braced(state: &mut State, expr: &str) -> Result<String, ExpansionError> {
+    if expr.len() > 1 && expr.starts_with('#') {
+        let (rest, name) = read_parameter_name(&expr[1..]);
+        let value = match read_subscript(rest) {
+            Some(("", "@")) | Some(("", "*")) => {
+                return Ok(state.elements(name).len().to_string());
+            }
+            Some(("", subscript)) => element(state, name, subscript)?,
+            _ => parameter(state, &expr[1..]),
+        };
+        return Ok(value.map_or(0, |v| v.chars().count()).to_string());
+    }
+    let (rest, name) = read_parameter_name(expr);
+    let name = if name.is_empty() { expr } else { name };
+    let (rest, value) = match read_subscript(rest) {
+        Some((rest, subscript)) if is_name(name) => (rest, element(state, name, subscript)?),
+        _ => (rest, parameter(state, name)),
+    };
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let mut ops = rest.chars();
+    let op = ops.next();
+    let word = ops.as_str();
+    let is_set = match &value {
+        Some(v) => !(colon && v.is_empty()),
+        None => false,
+    };
+    match op {
+        Some('-') if !is_set => expand_string(state, word),
+        Some('=') if !is_set => {
+            let word = expand_string(state, word)?;
+            state.set_var(name, &word);
+            Ok(word)
+        }
+        Some('+') if is_set => expand_string(state, word),
+        Some('+') => Ok(String::new()),
+        Some('?') if !is_set => {
+            let message = match word {
+                "" => "parameter null or not set".to_string(),
+                _ => expand_string(state, word)?,
+            };
+            Err(ExpansionError(format!("{}: {}", name, message)))
+        }
+        _ => Ok(value.unwrap_or_default()),
+    }
+}
+
+/// Evaluates the inside of a `$((...))` expression after expanding parameters in it.
+fn expand_arithmetic(state: &mut State, expr: &str) -> Result<String, ExpansionError> {
+    let expr = expand_string(state, expr)?;
+    match arith::evaluate(state, &expr) {
+        Ok(value) => Ok(value.to_string()),
+        Err(e) => Err(ExpansionError(format!("{}: {}", expr.trim(), e))),
+    }
+}
+
+fn ifs(state: &State) -> String {
+    state.get_var("IFS").unwrap_or(DEFAULT_IFS).to_string()
+}
+
+/// Expands a `$...` expression at the start of `in_str`, returning the rest of the input.
+fn expand_dollar<'a>(
+    state: &mut State,
+    in_str: &'a str,
+    fields: &mut Fields,
+    quoted: bool,
+) -> Result<&'a str, ExpansionError> {
+    let after = &in_str[1..];
+    let (rest, value) = if after.starts_with('(') || after.starts_with('{') {
+        match read_substitution(in_str) {
+            Ok((rest, substitution)) => {
+                let inner = &substitution[2..substitution.len() - 1];
+                if inner.starts_with('(') && inner.ends_with(')') {
+                    (rest, expand_arithmetic(state, &inner[1..inner.len() - 1])?)
+                } else if after.starts_with('(') {
+                    (rest, capture_output(state, inner))
+                } else if inner == "@" {
+                    expand_positional(state, fields, quoted, true);
+                    return Ok(rest);
+                } else {
+                    (rest, expand_braced(state, inner)?)
+                }
+            }
+            Err(_) => (after, "$".to_string()),
+        }
+    } else {
+        let (rest, name) = read_parameter_name(after);
+        match name {
+            "" => (rest, "$".to_string()),
+            "@" | "*" => {
+                expand_positional(state, fields, quoted, name == "@");
+                return Ok(rest);
+            }
+            _ => (rest, parameter(state, name).unwrap_or_default()),
+        }
+    };
+    if quoted {
+        fields.push_quoted(&value);
+    } else {
+        fields.push_split(&value, &ifs(state));
+    }
+    Ok(rest)
+}
+
+/// Expands `$@` or `$*`. Quoted `"$@"` produces one field per positional parameter.
+fn expand_positional(state: &State, fields: &mut Fields, quoted: bool, at: bool) {
+    let ifs = ifs(state);
+    if !at && (quoted || !fields.split) {
+        let separator = ifs.chars().next().map_or(String::new(), |c| c.to_string());
+        let joined = state.positional.join(&separator);
+        if quoted {
+            fields.push_quoted(&joined);
+        } else {
+            fields.push_literal(&joined);
+        }
+        return;
+    }
+    for (i, param) in state.positional.iter().enumerate() {
+        if i > 0 {
+            fields.started = true;
+            fields.end_field();
+        }
+        if quoted {
+            fields.push_quoted(param);
+        } else {
+            fields.push_split(param, &ifs);
+        }
+    }
+}
+
+/// Expands the contents of a double-quoted string, excluding the quotes.
+fn expand_double_quoted(
+    state: &mut State,
+    mut in_str: &str,
+    fields: &mut Fields,
+) -> Result<(), ExpansionError> {
+    while let Some(c) = in_str.chars().next() {
+        match c {
+            '\\' => {
+                let mut chars = in_str[1..].chars();
+                match chars.next() {
+                    Some(e) if "$`\"\\\n".contains(e) => {
+                        if e != '\n' {
+                            fields.push_quoted(&e.to_string());
+                        }
+                        in_str = chars.as_str();
+                    }
+                    _ => {
+                        fields.push_quoted("\\");
+                        in_str = &in_str[1..];
+                    }
+                }
+            }
+            '$' => in_str = expand_dollar(state, in_str, fields, true)?,
+            '`' => in_str = expand_backquote(state, in_str, fields, true),
+            _ => {
+                fields.push_quoted(&c.to_string());
+                in_str = &in_str[c.len_utf8()..];
+            }
+        }
+    }
+    Ok(())
+}
+
+fn expand_backquote<'a>(
+    state: &mut State,
+    in_str: &'a str,
+    fields: &mut Fields,
+    quoted: bool,
+) -> &'a str {
+    match read_quoted(in_str, '`') {
+        Ok((rest, command)) => {
+            let output = capture_output(state, &command[1..command.len() - 1]);
+            if quoted {
+                fields.push_quoted(&output);
+            } else {
+                fields.push_split(&output, &ifs(state));
+            }
+            rest
+        }
+        Err(_) => {
+            fields.push_literal("`");
+            &in_str[1..]
+        }
+    }
+}
+
+fn expand_tilde<'a>(state: &State, word: &'a str, fields: &mut Fields) -> &'a str {
+    let end = word.find('/').unwrap_or(word.len());
+    let home = match &word[1..end] {
+        "" => state.get_var("HOME"),
+        "+" => state.get_var("PWD"),
+        "-" => state.get_var("OLDPWD"),
+        _ => None,
+    };
+    match home {
+        Some(home) => {
+            fields.push_quoted(home);
+            &word[end..]
+        }
+        None => word,
+    }
+}
+
+fn expand_into(state: &mut State, word: &str, fields: &mut Fields) -> Result<(), ExpansionError> {
+    let mut in_str = word;
+    if in_str.starts_with('~') {
+        in_str = expand_tilde(state, in_str, fields);
+    }
+    while let Some(c) = in_str.chars().next() {
+        match c {
+            '\\' => {
+                let mut chars = in_str[1..].chars();
+                if let Some(e) = chars.next() {
+                    fields.push_quoted(&e.to_string());
+                }
+                in_str = chars.as_str();
+            }
+            '\'' | '"' => match read_quoted(in_str, c) {
+                Ok((rest, quoted)) => {
+                    let inner = &quoted[1..quoted.len() - 1];
+                    if c == '\'' {
+                        fields.push_quoted(inner);
+                    } else if inner == "$@" || inner == "${@}" {
+                        expand_positional(state, fields, true, true);
+                    } else {
+                        fields.push_literal("");
+                        expand_double_quoted(state, inner, fields)?;
+                    }
+                    in_str = rest;
+                }
+                Err(_) => {
+                    fields.push_literal(&c.to_string());
+                    in_str = &in_str[1..];
+                }
+            },
+            '$' => in_str = expand_dollar(state, in_str, fields, false)?,
+            '`' => in_str = expand_backquote(state, in_str, fields, false),
+            _ => {
+                fields.push_literal(&c.to_string());
+                in_str = &in_str[c.len_utf8()..];
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Expands words into fields: parameter expansion, command substitution, field splitting and
+/// quote removal.
+pub fn expand_words(state: &mut State, words: &[String]) -> Result<Vec<String>, ExpansionError> {
+    let mut result = vec![];
+    for word in words {
+        let mut fields = Fields::new(true, "");
+        expand_into(state, word, &mut fields)?;
+        result.extend(fields.finish());
+    }
+    Ok(result)
+}
+
+/// Expands a word into a single string without field splitting, as for assignment values.
+pub fn expand_string(state: &mut State, word: &str) -> Result<String, ExpansionError> {
+    expand_unsplit(state, word, "")
+}
+
+/// Expands a word into a shell pattern without field splitting. Quoted parts of the word match
+/// literally.
+pub fn expand_pattern(state: &mut State, word: &str) -> Result<String, ExpansionError> {
+    expand_unsplit(state, word, PATTERN_SPECIAL)
+}
+
+/// Expands a word into an extended regular expression without field splitting. Quoted parts of
+/// the word match literally.
+pub fn expand_regex(state: &mut State, word: &str) -> Result<String, ExpansionError> {
+    expand_unsplit(state, word, REGEX_SPECIAL)
+}
+
+fn expand_unsplit(
+    state: &mut State,
+    word: &str,
+    escape: &'static str,
+) -> Result<String, ExpansionError> {
+    let mut fields = Fields::new(false, escape);
+    expand_into(state, word, &mut fields)?;
+    Ok(fields.finish().join(" "))
+}
+
+#[cfg(test)]
+fn words(words: &[&str]) -> Vec<String> {
+    words.iter().map(|w| w.to_string()).collect()
+}
+
+#[test]
+fn expands_variables_and_splits_fields() {
+    let mut state = State::new();
+    state.set_var("a", "one  two");
+
+    let result = expand_words(&mut state, &words(&["x$a", "\"$a\"", "${a}y"])).unwrap();
+
+    assert_eq!(result, words(&["xone", "two", "one  two", "one", "twoy"]));
+}
+
+#[test]
+fn removes_quotes_and_escapes() {
+    let mut state = State::new();
+    state.set_var("a", "A");
+
+    let result =
+        expand_words(&mut state, &words(&["'$a'", "\\$a", "\"\\$a\\q\"", "\"\""])).unwrap();
+
+    assert_eq!(result, words(&["$a", "$a", "$a\\q", ""]));
+}
+
+#[test]
+fn expands_positional_parameters() {
+    let mut state = State::new();
+    state.positional = words(&["a b", "c"]);
+
+    let result = expand_words(&mut state, &words(&["$#", "$1", "\"$@\"", "$*", "\"$*\""])).unwrap();
+
+    assert_eq!(
+        result,
+        words(&["2", "a", "b", "a b", "c", "a", "b", "c", "a b c"])
+    );
+}
+
+#[test]
+fn empty_unquoted_expansion_disappears() {
+    let mut state = State::new();
+    state.positional = vec![];
+
+    let result = expand_words(&mut state, &words(&["$unset", "\"$@\"", "\"$unset\""])).unwrap();
+
+    assert_eq!(result, words(&[""]));
+}
+
+#[test]
+fn expands_parameter_defaults() {
+    let mut state = State::new();
+    state.set_var("empty", "");
+    state.set_var("set", "value");
+
+    assert_eq!(
+        expand_string(&mut state, "${empty:-default}"),
+        Ok("default".to_string())
+    );
+    assert_eq!(
+        expand_string(&mut state, "${empty-default}"),
+        Ok("".to_string())
+    );
+    assert_eq!(
+        expand_string(&mut state, "${set:+alt}"),
+        Ok("alt".to_string())
+    );
+    assert_eq!(expand_string(&mut state, "${#set}"), Ok("5".to_string()));
+    assert_eq!(
+        expand_string(&mut state, "${new:=assigned}"),
+        Ok("assigned".to_string())
+    );
+    assert_eq!(state.get_var("new"), Some("assigned"));
+}
+
+#[test]
+fn expands_array_elements() {
+    let mut state = State::new();
+    state.set_array("a", words(&["zero", "one", "two"]));
+    state.set_var("i", "1");
+
+    assert_eq!(
+        expand_string(&mut state, "${a[i+1]}"),
+        Ok("two".to_string())
+    );
+    assert_eq!(expand_string(&mut state, "${a[-1]}"), Ok("two".to_string()));
+    assert_eq!(expand_string(&mut state, "${#a[@]}"), Ok("3".to_string()));
+    assert_eq!(expand_string(&mut state, "${#a[1]}"), Ok("3".to_string()));
+    assert_eq!(
+        expand_string(&mut state, "${a[5]:-none}"),
+        Ok("none".to_string())
+    );
+    assert_eq!(expand_string(&mut state, "$a"), Ok("zero".to_string()));
+}
+
+#[test]
+fn quoted_parts_of_patterns_are_literal() {
+    let mut state = State::new();
+    state.set_var("star", "*");
+
+    assert_eq!(
+        expand_pattern(&mut state, "a$star'*'\\?\"[x]\""),
+        Ok("a*\\*\\?\\[x\\]".to_string())
+    );
+    assert_eq!(
+        expand_regex(&mut state, "^'a.b'$"),
+        Ok("^a\\.b$".to_string())
+    );
+}
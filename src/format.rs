@@ -0,0 +1,339 @@
+use std::fmt;
+
+use crate::ast::{BinaryOp, Command, BACKGROUND_PRECEDENCE, NOT_PRECEDENCE};
+use crate::lexer::{lex_spans, LexerError};
+use crate::parser::{parse, ParserError};
+use crate::tokens::Token;
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum FormatError {
+    Lexer(LexerError),
+    Parser(ParserError),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Lexer(e) => write!(f, "{}", e),
+            FormatError::Parser(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// What a marker word stands for. The parser has no place for comments and blank lines, so they
+/// are passed through it as words of their own that the lexer can never produce: a comment on
+/// its own line as `#...`, one after a command as ` #...` and a blank line as an empty word.
+enum Marker<'a> {
+    Comment(&'a str),
+    Trailing(&'a str),
+    Blank,
+}
+
+fn marker(command: &Command) -> Option<Marker<'_>> {
+    let word = match command {
+        Command::Single(c) if c.args.len() == 1 => c.args[0].as_str(),
+        _ => return None,
+    };
+    match word {
+        "" => Some(Marker::Blank),
+        _ if word.starts_with('#') => Some(Marker::Comment(word)),
+        _ => word.strip_prefix(' ').map(Marker::Trailing),
+    }
+}
+
+/// Whether a command can start after the tokens so far, so that a marker can go there.
+fn at_statement_start(tokens: &[Token]) -> bool {
+    matches!(
+        tokens.last(),
+        None | Some(Token::Semicolon)
+            | Some(Token::LBrace)
+            | Some(Token::LParen)
+            | Some(Token::Fork)
+    )
+}
+
+/// The markers for the comments and blank lines in `input[start..end]`, a stretch between tokens.
+fn markers(input: &str, start: usize, end: usize) -> Vec<String> {
+    let mut markers = vec![];
+    let mut pos = start;
+    while let Some(i) = input[pos..end].find(['#', '\n'].as_ref()) {
+        let at = pos + i;
+        let line_start = input[..at].rfind('\n').map_or(0, |n| n + 1);
+        let own_line = input[line_start..at].trim().is_empty();
+        if input[at..].starts_with('\n') {
+            if own_line {
+                markers.push(String::new());
+            }
+            pos = at + 1;
+        } else {
+            let line_end = input[at..end].find('\n').map_or(end, |n| at + n);
+            let comment = input[at..line_end].trim_end();
+            markers.push(match own_line {
+                true => comment.to_string(),
+                false => format!(" {}", comment),
+            });
+            pos = line_end;
+        }
+    }
+    markers
+}
+
+/// Lexes `input` with its comments and blank lines kept as marker words. Markers that fall in
+/// the middle of a command wait for the end of it, and comments among them then follow it.
+fn lex_with_markers(input: &str) -> Result<Vec<Token>, LexerError> {
+    let (spans, error) = lex_spans(input);
+    if let Some((e, _)) = error {
+        return Err(e);
+    }
+    let mut tokens = vec![];
+    let mut pending: Vec<String> = vec![];
+    let mut gap_start = 0;
+    let flush = |tokens: &mut Vec<Token>, pending: &mut Vec<String>| {
+        for marker in pending.drain(..) {
+            tokens.push(Token::Text(marker));
+            tokens.push(Token::Semicolon);
+        }
+    };
+    for (token, span) in spans {
+        for marker in markers(input, gap_start, span.start) {
+            match marker {
+                _ if at_statement_start(&tokens) => pending.push(marker),
+                // Blank lines within a command go, and comments move to its end.
+                _ if marker.starts_with('#') => pending.push(format!(" {}", marker)),
+                _ if !marker.is_empty() => pending.push(marker),
+                _ => {}
+            }
+        }
+        if at_statement_start(&tokens) {
+            flush(&mut tokens, &mut pending);
+        }
+        gap_start = span.end;
+        tokens.push(token);
+        if at_statement_start(&tokens) {
+            flush(&mut tokens, &mut pending);
+        }
+    }
+    pending.extend(markers(input, gap_start, input.len()));
+    if !pending.is_empty() && !at_statement_start(&tokens) {
+        tokens.push(Token::Semicolon);
+    }
+    flush(&mut tokens, &mut pending);
+    Ok(tokens)
+}
+
+/// The commands of a list joined by `;`, `&` and newlines, each with whether it runs in the
+/// background.
+fn statements(command: &Command) -> Vec<(&Command, bool)> {
+    fn flatten<'a>(command: &'a Command, out: &mut Vec<(&'a Command, bool)>) {
+        match command {
+            Command::Nil => {}
+            Command::BinaryExpr(b) if b.op == BinaryOp::Seq => {
+                flatten(&b.first, out);
+                flatten(&b.second, out);
+            }
+            Command::Background(command) => out.push((command, true)),
+            command => out.push((command, false)),
+        }
+    }
+    let mut out = vec![];
+    flatten(command, &mut out);
+    out
+}
+
+/// Whether the body of a group or subshell is laid out on lines of its own.
+fn is_block(body: &Command) -> bool {
+    let statements = statements(body);
+    statements.len() > 1 || statements.iter().any(|(s, _)| needs_layout(s))
+}
+
+/// Whether `command` spans lines, because it contains a block, a function or a marker.
+fn needs_layout(command: &Command) -> bool {
+    match command {
+        Command::Single(_) => marker(command).is_some(),
+        Command::BinaryExpr(b) => needs_layout(&b.first) || needs_layout(&b.second),
+        Command::Background(command) | Command::Not(command) => needs_layout(command),
+        Command::Redirect(r) => needs_layout(&r.command),
+        Command::FunctionDef(_) => true,
+        Command::Group(body) | Command::Subshell(body) => is_block(body),
+        _ => false,
+    }
+}
+
+/// Writes commands one to a line, with the bodies of functions and of groups and subshells that
+/// hold more than one command indented on lines of their own.
+struct Layout {
+    out: String,
+    depth: usize,
+}
+
+impl Layout {
+    fn newline(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Writes the statements of a list, each on a new line, or with the first on the current line
+    /// if `inline`.
+    fn block(&mut self, body: &Command, inline: bool) {
+        let mut written = false;
+        let mut blank = false;
+        for (statement, background) in statements(body) {
+            if let Some(Marker::Trailing(comment)) = marker(statement) {
+                self.out.push(' ');
+                self.out.push_str(comment);
+                continue;
+            }
+            if let Some(Marker::Blank) = marker(statement) {
+                blank = written;
+                continue;
+            }
+            if std::mem::take(&mut blank) {
+                self.out.push('\n');
+            }
+            if written || !inline {
+                self.newline();
+            }
+            written = true;
+            match marker(statement) {
+                Some(Marker::Comment(comment)) => self.out.push_str(comment),
+                _ if background => {
+                    self.operand(statement, BACKGROUND_PRECEDENCE + 1);
+                    self.out.push_str(" &");
+                }
+                _ => self.command(statement),
+            }
+        }
+    }
+
+    /// Writes `body` between `open` and `close` on lines of their own.
+    fn enclosed(&mut self, open: &str, body: &Command, close: &str) {
+        self.out.push_str(open);
+        self.depth += 1;
+        self.block(body, false);
+        self.depth -= 1;
+        self.newline();
+        self.out.push_str(close);
+    }
+
+    /// Writes a command that starts on the current line, the way `Display` does unless it needs
+    /// more lines.
+    fn command(&mut self, command: &Command) {
+        match command {
+            _ if !needs_layout(command) => self.out.push_str(&command.to_string()),
+            // Lists are laid out as blocks, so only the operators within a list are left.
+            Command::BinaryExpr(b) => {
+                let precedence = b.op.precedence();
+                self.operand(&b.first, precedence);
+                self.out.push_str(&format!(" {} ", b.op.symbol()));
+                self.operand(&b.second, precedence + 1);
+            }
+            Command::Background(command) => {
+                self.operand(command, BACKGROUND_PRECEDENCE + 1);
+                self.out.push_str(" &");
+            }
+            Command::Not(command) => {
+                self.out.push_str("! ");
+                self.operand(command, NOT_PRECEDENCE);
+            }
+            Command::Redirect(r) => {
+                self.operand(&r.command, u8::MAX);
+                for redirection in &r.redirections {
+                    self.out.push_str(&format!(" {}", redirection));
+                }
+            }
+            Command::FunctionDef(def) => {
+                self.out.push_str(&format!("{}() ", def.name));
+                match &*def.body {
+                    Command::Group(body) => self.enclosed("{", body, "}"),
+                    body => self.command(body),
+                }
+            }
+            Command::Group(body) => self.enclosed("{", body, "}"),
+            Command::Subshell(body) => self.enclosed("(", body, ")"),
+            command => self.out.push_str(&command.to_string()),
+        }
+    }
+
+    /// Writes `command` as an operand, in braces if its operator binds more loosely than
+    /// `loosest`.
+    fn operand(&mut self, command: &Command, loosest: u8) {
+        match command.precedence() < loosest {
+            true => self.enclosed("{", command, "}"),
+            false => self.command(command),
+        }
+    }
+}
+
+/// Reformats a script: one command to a line, compound commands indented and comments kept.
+/// Blank lines between commands are kept too, but no more than one in a row.
+pub fn format(input: &str) -> Result<String, FormatError> {
+    let tokens = lex_with_markers(input).map_err(FormatError::Lexer)?;
+    let command = parse(&tokens).map_err(FormatError::Parser)?;
+    let mut layout = Layout {
+        out: String::new(),
+        depth: 0,
+    };
+    layout.block(&command, true);
+    let mut out = layout.out;
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[test]
+fn puts_commands_on_their_own_lines() {
+    assert_eq!(
+        format("a;b &&c | d\n\n\n\ne & f>g").unwrap(),
+        "a\nb && c | d\n\ne &\nf > g\n"
+    );
+    assert_eq!(format("\n\n").unwrap(), "");
+}
+
+#[test]
+fn indents_compound_commands() {
+    assert_eq!(
+        format("f() { a; (b; c) | d; }; { e; }").unwrap(),
+        "f() {\n    a\n    (\n        b\n        c\n    ) | d\n}\n{ e; }\n"
+    );
+}
+
+#[test]
+fn keeps_comments() {
+    let script =
+        "# start\na # after a\n\n\n{ # group\n    b |\n        # inside\n        c\n}\n# end\n";
+    assert_eq!(
+        format(script).unwrap(),
+        "# start\na # after a\n\n{ # group\n    b | c # inside\n}\n# end\n"
+    );
+}
+
+#[test]
+fn formatting_twice_changes_nothing() {
+    let scripts = [
+        "# a\nf() ( x=1; echo $x ) # b\n\nf > out 2>&1 &\n",
+        "{ a && { b; c; } || d; } > f\n",
+        "! a | b & c; d && e &\n",
+        "echo 'a; # b' \"c # d\" e#f\n",
+    ];
+    for script in scripts {
+        let formatted = format(script).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted, "{:?}", script);
+    }
+}
+
+#[test]
+fn reports_syntax_errors() {
+    assert_eq!(
+        format("echo 'a"),
+        Err(FormatError::Lexer(LexerError::UnterminatedQuote('\'')))
+    );
+    assert_eq!(
+        format("{ a"),
+        Err(FormatError::Parser(ParserError::MissingRBrace))
+    );
+}
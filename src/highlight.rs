@@ -0,0 +1,300 @@
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::builtins;
+use crate::completion::{is_executable, unquote, CommandCache};
+use crate::expand::{is_name, split_assignment};
+use crate::lexer::{at_command_start, lex_spans, read_quoted, read_substitution, LexerError};
+use crate::state::State;
+use crate::tokens::Token;
+
+/// Words that are understood in command position without naming a command.
+const KEYWORDS: &[&str] = &["function", "!"];
+
+const FOUND: &str = "\x1b[32m";
+const NOT_FOUND: &str = "\x1b[31m";
+const OPERATOR: &str = "\x1b[36m";
+const QUOTED: &str = "\x1b[33m";
+const VARIABLE: &str = "\x1b[35m";
+const TARGET: &str = "\x1b[34m";
+const COMMENT: &str = "\x1b[90m";
+const UNDERLINE: &str = "\x1b[4m";
+const RESET: &str = "\x1b[0m";
+
+/// How one byte of the line is shown.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Style {
+    colour: Option<&'static str>,
+    underline: bool,
+}
+
+/// The style of each byte of a line.
+struct Styles(Vec<Style>);
+
+impl Styles {
+    fn colour(&mut self, start: usize, end: usize, colour: &'static str) {
+        for style in &mut self.0[start..end] {
+            style.colour = Some(colour);
+        }
+    }
+
+    fn underline(&mut self, start: usize, end: usize) {
+        for style in &mut self.0[start..end] {
+            style.underline = true;
+        }
+    }
+}
+
+/// Colours `line` for display: commands green if they can be found and red otherwise, operators,
+/// quotes, variables, comments and redirection targets each in their own colour, and existing
+/// paths underlined. Input that doesn't lex yet, such as an open quote, is coloured as far as it
+/// goes.
+pub fn highlight(state: &State, cache: &mut CommandCache, line: &str) -> String {
+    let mut styles = Styles(vec![Style::default(); line.len()]);
+    let (spans, error) = lex_spans(line);
+    let mut tokens = vec![];
+    let mut gap_start = 0;
+    let mut assignments_only = false;
+    let mut redirected = false;
+    for (token, span) in spans {
+        comments(line, gap_start..span.start, &mut styles);
+        gap_start = span.end;
+        match &token {
+            Token::Text(text) if redirected => {
+                styles.colour(span.start, span.end, TARGET);
+                words(line, span.start, span.end, &mut styles);
+                underline_path(state, text, span.start, span.end, &mut styles);
+            }
+            Token::Text(text) if at_command_start(&tokens) || assignments_only => {
+                match split_assignment(text) {
+                    Some(_) => assignments_only = true,
+                    None => {
+                        assignments_only = false;
+                        match command_exists(state, cache, text) {
+                            Some(true) => styles.colour(span.start, span.end, FOUND),
+                            Some(false) => styles.colour(span.start, span.end, NOT_FOUND),
+                            None => {}
+                        }
+                        if text.contains('/') {
+                            underline_path(state, text, span.start, span.end, &mut styles);
+                        }
+                    }
+                }
+                words(line, span.start, span.end, &mut styles);
+            }
+            Token::Text(text) => {
+                words(line, span.start, span.end, &mut styles);
+                underline_path(state, text, span.start, span.end, &mut styles);
+            }
+            Token::Arith(_) | Token::Conditional(_) => {
+                words(line, span.start, span.end, &mut styles)
+            }
+            _ => styles.colour(span.start, span.end, OPERATOR),
+        }
+        if !matches!(token, Token::Text(_)) {
+            assignments_only = false;
+        }
+        redirected = matches!(
+            token,
+            Token::WriteFile
+                | Token::AppendFile
+                | Token::ReadFile
+                | Token::DupInput
+                | Token::DupOutput
+        );
+        tokens.push(token);
+    }
+    match error {
+        Some((LexerError::UnknownOperator(_), offset)) => {
+            comments(line, gap_start..offset, &mut styles);
+            styles.colour(offset, line.len(), OPERATOR);
+        }
+        Some((_, offset)) => {
+            comments(line, gap_start..offset, &mut styles);
+            words(line, offset, line.len(), &mut styles);
+        }
+        None => comments(line, gap_start..line.len(), &mut styles),
+    }
+    render(line, &styles)
+}
+
+/// Whether the command named by `word` can be run, or `None` if that depends on an expansion.
+pub(crate) fn command_exists(state: &State, cache: &mut CommandCache, word: &str) -> Option<bool> {
+    if word.contains(['$', '`'].as_ref()) {
+        return None;
+    }
+    let name = unquote(word);
+    if name.contains('/') {
+        return Some(is_executable(&expand_tilde(state, &name)));
+    }
+    let path = state.get_var("PATH").unwrap_or_default().to_string();
+    Some(
+        KEYWORDS.contains(&name.as_str())
+            || builtins::NAMES.contains(&name.as_str())
+            || state.builtins.contains_key(&name)
+            || state.functions.contains_key(&name)
+            || state.aliases.contains_key(&name)
+            || cache.commands(&path).binary_search(&name).is_ok(),
+    )
+}
+
+/// Underlines a word that names an existing file or directory.
+fn underline_path(state: &State, word: &str, start: usize, end: usize, styles: &mut Styles) {
+    if word.contains(['$', '`', '*', '?', '['].as_ref()) {
+        return;
+    }
+    let name = unquote(word);
+    if !name.is_empty() && expand_tilde(state, &name).exists() {
+        styles.underline(start, end);
+    }
+}
+
+fn expand_tilde<'a>(state: &State, name: &'a str) -> Cow<'a, Path> {
+    match name.strip_prefix("~/") {
+        Some(rest) => Path::new(state.get_var("HOME").unwrap_or_default())
+            .join(rest)
+            .into(),
+        None => Path::new(name).into(),
+    }
+}
+
+/// Colours the quotes and substitutions within the word at `start..end`. An unterminated one
+/// is coloured to the end of the word.
+fn words(line: &str, start: usize, end: usize, styles: &mut Styles) {
+    let mut i = start;
+    while i < end {
+        let rest = &line[i..end];
+        let c = rest.chars().next().unwrap_or_default();
+        let (colour, len) = match c {
+            '\\' => (None, 1 + rest[1..].chars().next().map_or(0, char::len_utf8)),
+            '\'' | '"' | '`' => {
+                let len = read_quoted(rest, c).map_or(rest.len(), |(_, quoted)| quoted.len());
+                if c == '"' {
+                    // Substitutions inside double quotes stand out from the rest.
+                    styles.colour(i, i + len, QUOTED);
+                    variables(line, i + 1, i + len, styles);
+                    i += len;
+                    continue;
+                }
+                (Some(QUOTED), len)
+            }
+            '$' => (Some(VARIABLE), variable_len(rest)),
+            _ => (None, c.len_utf8()),
+        };
+        if let Some(colour) = colour {
+            styles.colour(i, i + len, colour);
+        }
+        i += len;
+    }
+}
+
+/// Colours the substitutions within a double-quoted section.
+fn variables(line: &str, start: usize, end: usize, styles: &mut Styles) {
+    let mut i = start;
+    while i < end {
+        let rest = &line[i..end];
+        let c = rest.chars().next().unwrap_or_default();
+        let len = match c {
+            '\\' => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            '$' => {
+                let len = variable_len(rest);
+                styles.colour(i, i + len, VARIABLE);
+                len
+            }
+            _ => c.len_utf8(),
+        };
+        i += len;
+    }
+}
+
+/// The length of the parameter or substitution at the start of `rest`, which begins with `$`.
+fn variable_len(rest: &str) -> usize {
+    let after = &rest[1..];
+    if after.starts_with(['(', '{'].as_ref()) {
+        return read_substitution(rest).map_or(rest.len(), |(_, sub)| sub.len());
+    }
+    let name = after
+        .char_indices()
+        .take_while(|&(i, _)| is_name(&after[..=i]))
+        .count();
+    match after.chars().next() {
+        _ if name > 0 => 1 + name,
+        Some(c) if c.is_ascii_digit() || "?#$!@*-".contains(c) => 2,
+        _ => 1,
+    }
+}
+
+/// Colours the comments in a stretch of the line between tokens. The lexer only skips blanks
+/// and comments there, so any `#` starts a comment.
+fn comments(line: &str, range: Range<usize>, styles: &mut Styles) {
+    let gap = &line[range.clone()];
+    let mut offset = 0;
+    while let Some(hash) = gap[offset..].find('#') {
+        let start = offset + hash;
+        let end = gap[start..].find('\n').map_or(gap.len(), |n| start + n);
+        styles.colour(range.start + start, range.start + end, COMMENT);
+        offset = end;
+    }
+}
+
+/// Writes `line` with escape sequences wherever the style changes.
+fn render(line: &str, styles: &Styles) -> String {
+    let mut out = String::new();
+    let mut current = Style::default();
+    for (i, c) in line.char_indices() {
+        let style = styles.0[i];
+        if style != current {
+            if current != Style::default() {
+                out.push_str(RESET);
+            }
+            out.extend(style.colour);
+            if style.underline {
+                out.push_str(UNDERLINE);
+            }
+            current = style;
+        }
+        out.push(c);
+    }
+    if current != Style::default() {
+        out.push_str(RESET);
+    }
+    out
+}
+
+#[cfg(test)]
+fn highlighted(line: &str) -> String {
+    let mut state = State::new();
+    state.set_var("PATH", "");
+    highlight(&state, &mut CommandCache::new(), line)
+}
+
+#[test]
+fn colours_commands_operators_and_words() {
+    assert_eq!(
+        highlighted("echo \"a $b\" 'c' | nosuch >src # note"),
+        "\x1b[32mecho\x1b[0m \x1b[33m\"a \x1b[0m\x1b[35m$b\x1b[0m\x1b[33m\"\x1b[0m \
+         \x1b[33m'c'\x1b[0m \x1b[36m|\x1b[0m \x1b[31mnosuch\x1b[0m \
+         \x1b[36m>\x1b[0m\x1b[34m\x1b[4msrc\x1b[0m \x1b[90m# note\x1b[0m"
+    );
+    assert_eq!(
+        highlighted("x=1 cd src && ./src"),
+        "x=1 \x1b[32mcd\x1b[0m \x1b[4msrc\x1b[0m \x1b[36m&&\x1b[0m \x1b[31m\x1b[4m./src\x1b[0m"
+    );
+}
+
+#[test]
+fn colours_incomplete_input() {
+    assert_eq!(
+        highlighted("echo \"open $(ls"),
+        "\x1b[32mecho\x1b[0m \x1b[33m\"open \x1b[0m\x1b[35m$(ls\x1b[0m"
+    );
+    assert_eq!(
+        highlighted("(ls ${x"),
+        "\x1b[36m(\x1b[0m\x1b[31mls\x1b[0m \x1b[35m${x\x1b[0m"
+    );
+    assert_eq!(
+        highlighted("echo 'a"),
+        "\x1b[32mecho\x1b[0m \x1b[33m'a\x1b[0m"
+    );
+}
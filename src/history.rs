@@ -0,0 +1,483 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::{getpid, Pid};
+
+use crate::lexer::lex;
+use crate::state::State;
+use crate::tokens::Token;
+
+/// The number of entries kept when `HISTSIZE` is unset.
+const DEFAULT_HISTSIZE: usize = 500;
+
+/// Characters that end the prefix in a `!prefix` event.
+const EVENT_DELIMITERS: &str = ";&|<>()'\"";
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum HistoryError {
+    EventNotFound(String),
+    SubstitutionFailed(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::EventNotFound(event) => write!(f, "{}: event not found", event),
+            HistoryError::SubstitutionFailed(sub) => write!(f, "{}: substitution failed", sub),
+        }
+    }
+}
+
+/// The lines entered at the prompt, numbered from 1 in the order they were entered.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// How many entries were dropped from the front, so that numbers stay stable.
+    dropped: usize,
+    /// The index of the first entry not yet written to the history file.
+    unsaved: usize,
+    /// The process that loaded the history file and is the one to save it. Subshells that exit
+    /// leave the file alone.
+    owner: Option<Pid>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The number of the first entry.
+    pub fn first_number(&self) -> usize {
+        self.dropped + 1
+    }
+
+    /// Looks up an entry by its number.
+    pub fn get(&self, number: usize) -> Option<&str> {
+        number
+            .checked_sub(self.first_number())
+            .and_then(|i| self.entries.get(i))
+            .map(|e| e.as_str())
+    }
+
+    /// Adds an entry, then drops the oldest entries beyond `limit`.
+    pub fn push(&mut self, line: &str, limit: Option<usize>) {
+        self.entries.push(line.to_string());
+        self.truncate(limit);
+    }
+
+    fn truncate(&mut self, limit: Option<usize>) {
+        if let Some(limit) = limit {
+            if self.entries.len() > limit {
+                let excess = self.entries.len() - limit;
+                self.entries.drain(..excess);
+                self.dropped += excess;
+                self.unsaved = self.unsaved.saturating_sub(excess);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.dropped += self.entries.len();
+        self.entries.clear();
+        self.unsaved = 0;
+    }
+
+    /// Deletes an entry by its number. Returns false if there is no such entry.
+    pub fn delete(&mut self, number: usize) -> bool {
+        match number.checked_sub(self.first_number()) {
+            Some(i) if i < self.entries.len() => {
+                self.entries.remove(i);
+                if i < self.unsaved {
+                    self.unsaved -= 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds the lines of a history file as entries that are already saved. They go before any
+    /// entries not yet saved, which stay unsaved.
+    pub fn read_file(&mut self, path: &str, limit: Option<usize>) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockShared).map_err(to_io)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let lines: Vec<_> = contents.lines().map(|l| l.to_string()).collect();
+        let count = lines.len();
+        self.entries.splice(self.unsaved..self.unsaved, lines);
+        self.unsaved += count;
+        self.truncate(limit);
+        Ok(())
+    }
+
+    /// Appends the entries added since the last save to a history file, then trims the file to
+    /// `file_limit` lines. The file stays locked throughout, so concurrent shells interleave
+    /// whole saves instead of overwriting each other.
+    pub fn append_to_file(&mut self, path: &str, file_limit: Option<usize>) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(to_io)?;
+        let new: String = self.entries[self.unsaved..]
+            .iter()
+            .map(|e| format!("{}\n", e))
+            .collect();
+        file.write_all(new.as_bytes())?;
+        self.unsaved = self.entries.len();
+        match file_limit {
+            Some(limit) => trim_file(&mut file, limit),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the contents of a history file with the whole history.
+    pub fn write_file(&mut self, path: &str, file_limit: Option<usize>) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(to_io)?;
+        file.set_len(0)?;
+        let skip = match file_limit {
+            Some(limit) => self.entries.len().saturating_sub(limit),
+            None => 0,
+        };
+        let contents: String = self.entries[skip..]
+            .iter()
+            .map(|e| format!("{}\n", e))
+            .collect();
+        file.write_all(contents.as_bytes())?;
+        self.unsaved = self.entries.len();
+        Ok(())
+    }
+}
+
+fn to_io(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        _ => io::Error::other(err.to_string()),
+    }
+}
+
+/// Keeps only the last `limit` lines of a locked file opened for appending.
+fn trim_file(file: &mut File, limit: usize) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    if lines.len() <= limit {
+        return Ok(());
+    }
+    let kept: String = lines[lines.len() - limit..]
+        .iter()
+        .map(|l| format!("{}\n", l))
+        .collect();
+    file.set_len(0)?;
+    file.write_all(kept.as_bytes())
+}
+
+/// Reads a size limit from a variable. A negative number means no limit.
+fn limit(state: &State, name: &str, default: Option<usize>) -> Option<usize> {
+    match state.get_var(name).map(|v| v.trim().parse::<i64>()) {
+        Some(Ok(n)) if n < 0 => None,
+        Some(Ok(n)) => Some(n as usize),
+        _ => default,
+    }
+}
+
+/// The number of entries to keep in memory, from `HISTSIZE`.
+pub fn size_limit(state: &State) -> Option<usize> {
+    limit(state, "HISTSIZE", Some(DEFAULT_HISTSIZE))
+}
+
+/// The number of lines to keep in the history file, from `HISTFILESIZE` or else `HISTSIZE`.
+pub fn file_size_limit(state: &State) -> Option<usize> {
+    limit(state, "HISTFILESIZE", size_limit(state))
+}
+
+/// Adds an accepted line to the history unless `HISTCONTROL` says to skip it: `ignorespace`
+/// skips lines starting with a space, `ignoredups` repeats of the previous entry, and
+/// `ignoreboth` both.
+pub fn record(state: &mut State, line: &str) {
+    if line.trim().is_empty() {
+        return;
+    }
+    let control = state.get_var("HISTCONTROL").unwrap_or_default();
+    let options: Vec<&str> = control.split(':').collect();
+    let ignore_space = options.contains(&"ignorespace") || options.contains(&"ignoreboth");
+    let ignore_dups = options.contains(&"ignoredups") || options.contains(&"ignoreboth");
+    if ignore_space && line.starts_with(' ') {
+        return;
+    }
+    if ignore_dups && state.history.entries.last().map(|e| e.as_str()) == Some(line) {
+        return;
+    }
+    let limit = size_limit(state);
+    state.history.push(line, limit);
+}
+
+/// Loads `$HISTFILE`, if set, and makes this process responsible for saving it.
+pub fn load(state: &mut State) {
+    state.history.owner = Some(getpid());
+    if let Some(path) = state.get_var("HISTFILE").map(|p| p.to_string()) {
+        let limit = size_limit(state);
+        let _ = state.history.read_file(&path, limit);
+    }
+}
+
+/// Appends new entries to `$HISTFILE` when the shell exits. Does nothing in subshells or in
+/// shells that never loaded the history.
+pub fn save(state: &mut State) {
+    if state.history.owner != Some(getpid()) {
+        return;
+    }
+    if let Some(path) = state.get_var("HISTFILE").map(|p| p.to_string()) {
+        let limit = file_size_limit(state);
+        let _ = state.history.append_to_file(&path, limit);
+    }
+}
+
+/// The words of a history entry, as the lexer sees them.
+fn words(entry: &str) -> Vec<String> {
+    match lex(entry) {
+        Ok(tokens) => tokens
+            .into_iter()
+            .filter_map(|t| match t {
+                Token::Text(text) => Some(text),
+                _ => None,
+            })
+            .collect(),
+        Err(_) => entry.split_whitespace().map(|w| w.to_string()).collect(),
+    }
+}
+
+/// Reads the event after a `!`, returning what it expands to and how many bytes it used.
+fn expand_event(event: &str, history: &History) -> Result<(String, usize), HistoryError> {
+    let last = || {
+        history
+            .entries
+            .last()
+            .ok_or_else(|| HistoryError::EventNotFound("!".to_string()))
+    };
+    let digits = |s: &str| s.chars().take_while(|c| c.is_ascii_digit()).count();
+    let mut chars = event.chars();
+    match chars.next() {
+        Some('!') => Ok((last()?.clone(), 1)),
+        Some('$') => Ok((words(last()?).pop().unwrap_or_default(), 1)),
+        Some('^') => Ok((words(last()?).get(1).cloned().unwrap_or_default(), 1)),
+        Some('*') => Ok((words(last()?).get(1..).unwrap_or_default().join(" "), 1)),
+        Some(c) if c.is_ascii_digit() || c == '-' => {
+            let negative = c == '-';
+            let len = digits(&event[negative as usize..]) + negative as usize;
+            let n: usize = event[negative as usize..len].parse().unwrap_or(0);
+            let number = match negative {
+                true => (history.first_number() + history.entries.len()).checked_sub(n),
+                false => Some(n),
+            };
+            match number.and_then(|n| history.get(n)) {
+                Some(entry) => Ok((entry.to_string(), len)),
+                None => Err(HistoryError::EventNotFound(format!("!{}", &event[..len]))),
+            }
+        }
+        Some('?') => {
+            let end = event[1..].find('?').map_or(event.len(), |i| i + 1);
+            let needle = &event[1..end];
+            let used = (end + 1).min(event.len());
+            match history.entries.iter().rev().find(|e| e.contains(needle)) {
+                Some(entry) => Ok((entry.clone(), used)),
+                None => Err(HistoryError::EventNotFound(format!("!{}", &event[..used]))),
+            }
+        }
+        _ => {
+            let len = event
+                .find(|c: char| c.is_whitespace() || EVENT_DELIMITERS.contains(c))
+                .unwrap_or(event.len());
+            let prefix = &event[..len];
+            match history.entries.iter().rev().find(|e| e.starts_with(prefix)) {
+                Some(entry) => Ok((entry.clone(), len)),
+                None => Err(HistoryError::EventNotFound(format!("!{}", prefix))),
+            }
+        }
+    }
+}
+
+/// Applies history expansion to a line before it is lexed: `!!`, `!n`, `!-n`, `!prefix`,
+/// `!?text?`, `!$`, `!^`, `!*` and a leading `^old^new^`. Returns `None` if the line has nothing
+/// to expand. A `!` is literal inside single quotes, after a backslash, and before whitespace,
+/// `=` or `(`.
+pub fn expand(line: &str, history: &History) -> Result<Option<String>, HistoryError> {
+    if let Some(rest) = line.strip_prefix('^') {
+        let mut parts = rest.splitn(3, '^');
+        let old = parts.next().unwrap_or_default();
+        let new = parts.next().unwrap_or_default();
+        let tail = parts.next().unwrap_or_default();
+        let previous = history
+            .entries
+            .last()
+            .ok_or_else(|| HistoryError::EventNotFound("^".to_string()))?;
+        if old.is_empty() || !previous.contains(old) {
+            return Err(HistoryError::SubstitutionFailed(format!(
+                "^{}^{}",
+                old, new
+            )));
+        }
+        return Ok(Some(format!("{}{}", previous.replacen(old, new, 1), tail)));
+    }
+    let mut out = String::new();
+    let mut changed = false;
+    let (mut single, mut double) = (false, false);
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        match c {
+            '\\' if !single => {
+                let escaped = after.chars().next().map_or(0, |e| e.len_utf8());
+                out.push_str(&rest[..1 + escaped]);
+                rest = &after[escaped..];
+                continue;
+            }
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '!' if !single => match after.chars().next() {
+                None => {}
+                Some(n) if n.is_whitespace() || n == '=' || n == '(' || (double && n == '"') => {}
+                Some(_) => {
+                    let (expansion, used) = expand_event(after, history)?;
+                    out.push_str(&expansion);
+                    changed = true;
+                    rest = &after[used..];
+                    continue;
+                }
+            },
+            _ => {}
+        }
+        out.push(c);
+        rest = after;
+    }
+    Ok(if changed { Some(out) } else { None })
+}
+
+#[cfg(test)]
+fn history_of(lines: &[&str]) -> History {
+    let mut history = History::new();
+    for line in lines {
+        history.push(line, None);
+    }
+    history
+}
+
+#[test]
+fn expands_events() {
+    let history = history_of(&["echo one two", "ls -l /tmp", "echo three"]);
+
+    let expand = |line| expand(line, &history);
+    assert_eq!(expand("!!"), Ok(Some("echo three".to_string())));
+    assert_eq!(
+        expand("sudo !! | less"),
+        Ok(Some("sudo echo three | less".to_string()))
+    );
+    assert_eq!(expand("!2"), Ok(Some("ls -l /tmp".to_string())));
+    assert_eq!(expand("!-3 x"), Ok(Some("echo one two x".to_string())));
+    assert_eq!(
+        expand("!ls;!e"),
+        Ok(Some("ls -l /tmp;echo three".to_string()))
+    );
+    assert_eq!(expand("cd !$"), Ok(Some("cd three".to_string())));
+    assert_eq!(expand("!?one?"), Ok(Some("echo one two".to_string())));
+    assert_eq!(
+        expand("!nope"),
+        Err(HistoryError::EventNotFound("!nope".to_string()))
+    );
+}
+
+#[test]
+fn leaves_quoted_and_literal_bangs_alone() {
+    let history = history_of(&["echo x"]);
+
+    assert_eq!(expand("echo '!!' \\!! ! a!=b !", &history), Ok(None));
+    assert_eq!(
+        expand("echo \"!!\"", &history),
+        Ok(Some("echo \"echo x\"".to_string()))
+    );
+}
+
+#[test]
+fn substitutes_in_previous_command() {
+    let history = history_of(&["cat fiel.txt"]);
+
+    assert_eq!(
+        expand("^fiel^file^ | wc", &history),
+        Ok(Some("cat file.txt | wc".to_string()))
+    );
+    assert!(expand("^nothere^x", &history).is_err());
+}
+
+#[test]
+fn keeps_numbers_stable_when_trimmed() {
+    let mut history = history_of(&["a", "b"]);
+    history.push("c", Some(2));
+
+    assert_eq!(history.first_number(), 2);
+    assert_eq!(history.get(3), Some("c"));
+    assert!(history.delete(2));
+    assert_eq!(history.entries(), &["c".to_string()]);
+}
+
+#[test]
+fn honours_histcontrol() {
+    let mut state = State::new();
+    state.set_var("HISTCONTROL", "ignoredups:ignorespace");
+
+    for line in &["ls", "ls", " secret", "pwd", "ls"] {
+        record(&mut state, line);
+    }
+
+    assert_eq!(state.history.entries(), &["ls", "pwd", "ls"]);
+}
+
+#[test]
+fn appends_to_and_trims_history_file() {
+    let path = std::env::temp_dir().join(format!("traash-history-{}", getpid()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let mut first = history_of(&["a", "b"]);
+    let mut second = history_of(&["c"]);
+
+    first.append_to_file(path, None).unwrap();
+    second.append_to_file(path, None).unwrap();
+    first.push("d", None);
+    first.append_to_file(path, Some(3)).unwrap();
+
+    let mut loaded = History::new();
+    loaded.read_file(path, None).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.entries(), &["b", "c", "d"]);
+}
+
+#[test]
+fn reading_a_file_keeps_unsaved_entries() {
+    let path = std::env::temp_dir().join(format!("traash-history-read-{}", getpid()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, "old\n").unwrap();
+    let mut history = History::new();
+
+    history.push("typed", None);
+    history.read_file(path, None).unwrap();
+    assert_eq!(history.entries(), &["old", "typed"]);
+    history.append_to_file(path, None).unwrap();
+
+    let saved = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(saved, "old\ntyped\n");
+}
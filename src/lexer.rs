@@ -1,12 +1,22 @@
-use crate::lexer::LexerError::{TrailingBackslash, UnknownOperator};
+use crate::expand::is_name;
+use crate::lexer::LexerError::{
+    TrailingBackslash, UnknownOperator, UnterminatedArray, UnterminatedConditional,
+    UnterminatedQuote, UnterminatedSubstitution,
+};
 use crate::tokens::Token;
 use crate::tokens::Token::*;
+use std::borrow::Cow;
 use std::fmt;
+use std::ops::Range;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum LexerError {
     TrailingBackslash,
     UnknownOperator(String),
+    UnterminatedQuote(char),
+    UnterminatedSubstitution,
+    UnterminatedConditional,
+    UnterminatedArray,
 }
 
 impl fmt::Display for LexerError {
@@ -14,24 +24,116 @@ impl fmt::Display for LexerError {
         match self {
             LexerError::TrailingBackslash => write!(f, "trailing backslash"),
             LexerError::UnknownOperator(op) => write!(f, "unknown operator {}", op),
+            LexerError::UnterminatedQuote(q) => write!(f, "unterminated quote {}", q),
+            LexerError::UnterminatedSubstitution => write!(f, "unterminated substitution"),
+            LexerError::UnterminatedConditional => write!(f, "unterminated [[ conditional"),
+            LexerError::UnterminatedArray => write!(f, "unterminated array assignment"),
         }
     }
 }
 
 fn is_operator(c: char) -> bool {
-    return vec!['&', '|', '>', '<', ';'].contains(&c);
+    ['&', '|', '>', '<', ';'].contains(&c)
+}
+
+/// Characters that end a word unless they are escaped or quoted.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || is_operator(c) || c == '(' || c == ')'
 }
 
 fn is_text(c: char) -> bool {
-    match c {
-        _ if c.is_alphanumeric() => true,
-        '-' | '_' | '.' | '/' => true,
-        _ => false,
+    !is_delimiter(c)
+}
+
+fn skip_char(in_str: &str) -> &str {
+    match in_str.chars().next() {
+        None => in_str,
+        Some(c) => &in_str[c.len_utf8()..],
+    }
+}
+
+/// Reads a quoted section starting at the opening quote, returning it verbatim including both
+/// quotes. Backslashes only escape inside double quotes and backquotes.
+pub(crate) fn read_quoted(in_str: &str, quote: char) -> Result<(&str, &str), LexerError> {
+    let mut chars = in_str.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            let end = i + c.len_utf8();
+            return Ok((&in_str[end..], &in_str[..end]));
+        } else if c == '\\' && quote != '\'' {
+            chars.next();
+        } else if c == '$' && quote == '"' && in_str[i + 1..].starts_with(['(', '{'].as_ref()) {
+            let (_, inner) = read_substitution(&in_str[i..])?;
+            let skip = inner[1..].chars().count();
+            for _ in 0..skip {
+                chars.next();
+            }
+        }
     }
+    Err(UnterminatedQuote(quote))
 }
 
+/// Reads a `$(...)` or `${...}` substitution starting at the `$`, returning it verbatim. Nested
+/// brackets and quotes are balanced.
+pub(crate) fn read_substitution(in_str: &str) -> Result<(&str, &str), LexerError> {
+    let open = in_str[1..].chars().next().ok_or(UnterminatedSubstitution)?;
+    let close = if open == '(' { ')' } else { '}' };
+    let mut depth = 0;
+    let mut rest = &in_str[1..];
+    loop {
+        let c = rest.chars().next().ok_or(UnterminatedSubstitution)?;
+        match c {
+            '\\' => {
+                rest = skip_char(&rest[1..]);
+                continue;
+            }
+            '\'' | '"' | '`' => {
+                let (r, _) = read_quoted(rest, c)?;
+                rest = r;
+                continue;
+            }
+            '$' if rest[1..].starts_with(['(', '{'].as_ref()) => {
+                let (r, _) = read_substitution(rest)?;
+                rest = r;
+                continue;
+            }
+            _ if c == open => depth += 1,
+            _ if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    let end = in_str.len() - rest.len() + 1;
+                    return Ok((&in_str[end..], &in_str[..end]));
+                }
+            }
+            _ => {}
+        }
+        rest = skip_char(rest);
+    }
+}
+
+/// Reads the `(...)` of an array assignment such as `a=(x "y z")`, returning it verbatim. The
+/// elements are split into words when the assignment is expanded.
+fn read_array(in_str: &str) -> Result<(&str, &str), LexerError> {
+    let mut rest = &in_str[1..];
+    loop {
+        let c = rest.chars().next().ok_or(UnterminatedArray)?;
+        match c {
+            '\\' => rest = skip_char(&rest[1..]),
+            '\'' | '"' | '`' => rest = read_quoted(rest, c)?.0,
+            '$' if rest[1..].starts_with(['(', '{'].as_ref()) => rest = read_substitution(rest)?.0,
+            ')' => {
+                let end = in_str.len() - rest.len() + 1;
+                return Ok((&in_str[end..], &in_str[..end]));
+            }
+            _ => rest = skip_char(rest),
+        }
+    }
+}
+
+/// Reads one word. Quotes, substitutions and escapes are kept in their source form so that
+/// expansion can tell them apart later; only escapes of word delimiters are resolved here.
 fn read_text(mut in_str: &str) -> Result<(&str, String), LexerError> {
-    let mut acc: Vec<char> = vec![];
+    let mut acc = String::new();
     loop {
         let c = match in_str.chars().next() {
             None => break,
@@ -41,75 +143,317 @@ fn read_text(mut in_str: &str) -> Result<(&str, String), LexerError> {
             in_str = &in_str[1..];
             match in_str.chars().next() {
                 None => Err(TrailingBackslash)?,
-                Some(c) => acc.push(c),
+                Some('\n') => {}
+                Some(c) if is_delimiter(c) => acc.push(c),
+                Some(c) => {
+                    acc.push('\\');
+                    acc.push(c);
+                }
             };
+        } else if c == '\'' || c == '"' || c == '`' {
+            let (rest, quoted) = read_quoted(in_str, c)?;
+            acc.push_str(quoted);
+            in_str = rest;
+            continue;
+        } else if c == '$' && in_str[1..].starts_with(['(', '{'].as_ref()) {
+            let (rest, substitution) = read_substitution(in_str)?;
+            acc.push_str(substitution);
+            in_str = rest;
+            continue;
+        } else if c == '(' && acc.ends_with('=') && is_name(&acc[..acc.len() - 1]) {
+            let (rest, array) = read_array(in_str)?;
+            acc.push_str(array);
+            in_str = rest;
+            continue;
         } else if !is_text(c) {
             break;
         } else {
             acc.push(c);
         }
-        in_str = &in_str[1..];
+        in_str = skip_char(in_str);
+    }
+    Ok((in_str, acc))
+}
+
+/// Reads an `(( ... ))` arithmetic command, returning the rest of the input and the expression.
+/// Returns `None` if the parentheses don't close with `))`, in which case they are nested
+/// subshells instead.
+fn read_arith_command(in_str: &str) -> Option<(&str, &str)> {
+    let body = in_str.strip_prefix("((")?;
+    let mut depth = 0;
+    for (i, c) in body.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                return match body[i + 1..].starts_with(')') {
+                    true => Some((&body[i + 2..], &body[..i])),
+                    false => None,
+                };
+            }
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Escapes the delimiters in a word from `read_text`, which resolves their escapes, so that it
+/// reads back as the same word.
+pub(crate) fn escape_delimiters(word: &str) -> Cow<'_, str> {
+    if !word.contains(is_delimiter) {
+        return Cow::Borrowed(word);
+    }
+    let mut out = String::new();
+    let mut rest = word;
+    while let Some(c) = rest.chars().next() {
+        let part = match c {
+            '\\' => &rest[..1 + rest[1..].chars().next().map_or(0, char::len_utf8)],
+            '\'' | '"' | '`' => read_quoted(rest, c).map_or(rest, |(_, quoted)| quoted),
+            '$' if rest[1..].starts_with(['(', '{'].as_ref()) => {
+                read_substitution(rest).map_or(rest, |(_, substitution)| substitution)
+            }
+            '(' if out.ends_with('=') && is_name(&out[..out.len() - 1]) => {
+                read_array(rest).map_or(rest, |(_, array)| array)
+            }
+            _ => {
+                if is_delimiter(c) {
+                    out.push('\\');
+                }
+                &rest[..c.len_utf8()]
+            }
+        };
+        out.push_str(part);
+        rest = &rest[part.len()..];
+    }
+    Cow::Owned(out)
+}
+
+/// Reads the regular expression after `=~` in a conditional. Unlike other words it may contain
+/// unquoted parentheses and `|`, and only ends at whitespace outside of parentheses.
+fn read_regex(mut in_str: &str) -> Result<(&str, String), LexerError> {
+    let mut acc = String::new();
+    let mut depth = 0;
+    while let Some(c) = in_str.chars().next() {
+        match c {
+            '\\' => {
+                let escaped = in_str[1..].chars().next().ok_or(TrailingBackslash)?;
+                acc.push('\\');
+                acc.push(escaped);
+                in_str = &in_str[1 + escaped.len_utf8()..];
+                continue;
+            }
+            '\'' | '"' => {
+                let (rest, quoted) = read_quoted(in_str, c)?;
+                acc.push_str(quoted);
+                in_str = rest;
+                continue;
+            }
+            '$' if in_str[1..].starts_with(['(', '{'].as_ref()) => {
+                let (rest, substitution) = read_substitution(in_str)?;
+                acc.push_str(substitution);
+                in_str = rest;
+                continue;
+            }
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            _ if c.is_whitespace() && depth == 0 => break,
+            _ => {}
+        }
+        acc.push(c);
+        in_str = skip_char(in_str);
+    }
+    Ok((in_str, acc))
+}
+
+/// Reads the inside of a `[[ ... ]]` command after the opening brackets, returning the rest of
+/// the input after the closing `]]`.
+fn read_conditional(mut in_str: &str) -> Result<(&str, Vec<String>), LexerError> {
+    let mut words: Vec<String> = vec![];
+    loop {
+        in_str = skip_whitespace(in_str);
+        let c = in_str.chars().next().ok_or(UnterminatedConditional)?;
+        if words.last().map(|w| w.as_str()) == Some("=~") {
+            let (rest, regex) = read_regex(in_str)?;
+            words.push(regex);
+            in_str = rest;
+        } else if in_str.starts_with("&&") || in_str.starts_with("||") {
+            words.push(in_str[..2].to_string());
+            in_str = &in_str[2..];
+        } else if c == '(' || c == ')' || c == '<' || c == '>' {
+            words.push(c.to_string());
+            in_str = &in_str[1..];
+        } else if is_operator(c) {
+            let (_, repetitions) = read_operator(in_str, c);
+            Err(UnknownOperator((0..repetitions).map(|_| c).collect()))?
+        } else {
+            let (rest, word) = read_text(in_str)?;
+            in_str = rest;
+            if word == "]]" {
+                return Ok((in_str, words));
+            }
+            words.push(word);
+        }
+    }
+}
+
+/// Whether a word after `tokens` would be the first word of a command, which is the only place
+/// `[[` starts a conditional or an alias is substituted.
+pub(crate) fn at_command_start(tokens: &[Token]) -> bool {
+    match tokens.split_last() {
+        // `!` negates the pipeline after it, but is a command of its own after a `|`.
+        Some((Text(word), before)) if word == "!" => {
+            at_command_start(before) && before.last() != Some(&Pipe)
+        }
+        _ => matches!(
+            tokens.last(),
+            None | Some(Semicolon)
+                | Some(LogAnd)
+                | Some(LogOr)
+                | Some(Pipe)
+                | Some(Fork)
+                | Some(LParen)
+                | Some(LBrace)
+        ),
     }
-    Ok((&in_str, acc.into_iter().collect()))
 }
 
 fn read_operator(mut in_str: &str, operator: char) -> (&str, i8) {
     let mut repetitions = 0;
-    while in_str.chars().next() == Some(operator) {
+    while in_str.starts_with(operator) {
         repetitions += 1;
         in_str = &in_str[1..];
     }
     (in_str, repetitions)
 }
 
-fn skip_whitespace(mut in_str: &str) -> &str {
-    while let Some(x) = in_str.chars().next() {
-        if !x.is_whitespace() {
-            break;
+fn skip_whitespace(in_str: &str) -> &str {
+    in_str.trim_start()
+}
+
+/// Reads the token at the start of `input`, which is not empty. Returns `None` in place of the
+/// token for whitespace and comments, along with the rest of the input.
+fn next_token<'a>(
+    input: &'a str,
+    tokens: &[Token],
+) -> Result<(Option<Token>, &'a str), LexerError> {
+    let c = input.chars().next().unwrap_or_default();
+    if let Some((t, expr)) = read_arith_command(input) {
+        return Ok((Some(Arith(expr.to_string())), t));
+    }
+    let token = if c == '(' {
+        LParen
+    } else if c == ')' {
+        RParen
+    } else if c == '\n' {
+        // A newline ends a command, unless one is still to come after an operator.
+        return match at_command_start(tokens) {
+            true => Ok((None, &input[1..])),
+            false => Ok((Some(Semicolon), &input[1..])),
+        };
+    } else if c.is_whitespace() {
+        let t = input.trim_start_matches(|c: char| c.is_whitespace() && c != '\n');
+        return Ok((None, t));
+    } else if c == '#' {
+        let end = input.find('\n').unwrap_or(input.len());
+        return Ok((None, &input[end..]));
+    } else if is_text(c) {
+        let (mut t, text) = read_text(input)?;
+        let io_number = match t.chars().next() {
+            Some('<') | Some('>') => text.parse::<i32>().ok(),
+            _ => None,
+        };
+        let token = match (text.as_str(), io_number) {
+            (_, Some(fd)) => IoNumber(fd),
+            ("{", _) => LBrace,
+            ("}", _) => RBrace,
+            ("[[", _) if at_command_start(tokens) => {
+                let (rest, words) = read_conditional(t)?;
+                t = rest;
+                Conditional(words)
+            }
+            _ => Token::Text(text),
+        };
+        return Ok((Some(token), t));
+    } else {
+        let (mut t, repetitions) = read_operator(input, c);
+        let dup = repetitions == 1 && t.starts_with('&');
+        if dup {
+            t = &t[1..];
         }
-        in_str = &in_str[1..];
+        let token = match (c, repetitions) {
+            ('>', 1) if dup => Token::DupOutput,
+            ('<', 1) if dup => Token::DupInput,
+            ('&', 1) => Token::Fork,
+            ('&', 2) => Token::LogAnd,
+            ('|', 1) => Token::Pipe,
+            ('|', 2) => Token::LogOr,
+            ('>', 1) => Token::WriteFile,
+            ('>', 2) => Token::AppendFile,
+            ('<', 1) => Token::ReadFile,
+            (';', 1) => Token::Semicolon,
+            _ => Err(UnknownOperator((0..repetitions).map(|_| c).collect()))?,
+        };
+        return Ok((Some(token), t));
+    };
+    Ok((Some(token), &input[1..]))
+}
+
+/// A token and the byte range of the input it was read from.
+pub type Spanned = (Token, Range<usize>);
+
+/// Lexes as much of `input` as it can. If lexing fails, the tokens before the failure are still
+/// returned, along with the error and the offset of the token that caused it, so that incomplete
+/// input can be highlighted as it is typed.
+pub fn lex_spans(input: &str) -> (Vec<Spanned>, Option<(LexerError, usize)>) {
+    let (mut tokens, errors) = lex_recovering(input);
+    let error = errors.into_iter().next();
+    if let Some((_, offset)) = &error {
+        tokens.retain(|(_, span)| span.start < *offset);
     }
-    in_str
+    (tokens, error)
 }
 
-pub fn lex(mut input: &str) -> Result<Vec<Token>, LexerError> {
-    let mut tokens: Vec<Token> = vec![];
-    loop {
-        let c = match input.chars().next() {
-            None => break,
-            Some(c) => c,
-        };
-        if c == '(' {
-            tokens.push(LParen);
-            input = &input[1..];
-        } else if c == ')' {
-            tokens.push(RParen);
-            input = &input[1..];
-        } else if c.is_whitespace() {
-            input = skip_whitespace(input);
-        } else if is_text(c) {
-            let (t, text) = read_text(input)?;
-            tokens.push(Token::Text(text));
-            input = t;
-        } else if is_operator(c) {
-            let (t, repetitions) = read_operator(input, c);
-            let token = match (c, repetitions) {
-                ('&', 1) => Token::Fork,
-                ('&', 2) => Token::LogAnd,
-                ('|', 1) => Token::Pipe,
-                ('|', 2) => Token::LogOr,
-                ('>', 1) => Token::WriteFile,
-                ('>', 2) => Token::AppendFile,
-                (';', 1) => Token::Semicolon,
-                _ => Err(UnknownOperator((0..repetitions).map(|_| c).collect()))?,
-            };
-            tokens.push(token);
-            input = t;
-        } else {
-            panic!("Unknown character {}", c)
+/// Lexes `input` past errors, returning every error with the offset of the token that caused
+/// it. An unknown operator is skipped, and one inside `[[ ... ]]` skips the rest of its line.
+/// Other errors, such as an unterminated quote, run to the end of the input, so lexing stops.
+pub fn lex_recovering(input: &str) -> (Vec<Spanned>, Vec<(LexerError, usize)>) {
+    let mut tokens = vec![];
+    let mut spans = vec![];
+    let mut rest = input;
+    let mut errors = vec![];
+    while !rest.is_empty() {
+        let start = input.len() - rest.len();
+        match next_token(rest, &tokens) {
+            Ok((token, after)) => {
+                if let Some(token) = token {
+                    tokens.push(token);
+                    spans.push(start..input.len() - after.len());
+                }
+                rest = after;
+            }
+            Err(e) => {
+                let resume = match &e {
+                    UnknownOperator(op) if rest.starts_with(op.as_str()) => Some(op.len()),
+                    UnknownOperator(_) => rest.find('\n'),
+                    _ => None,
+                };
+                errors.push((e, start));
+                match resume {
+                    Some(skip) => rest = &rest[skip..],
+                    None => break,
+                }
+            }
         }
     }
-    Ok(tokens)
+    (tokens.into_iter().zip(spans).collect(), errors)
+}
+
+pub fn lex(input: &str) -> Result<Vec<Token>, LexerError> {
+    match lex_spans(input) {
+        (tokens, None) => Ok(tokens.into_iter().map(|(token, _)| token).collect()),
+        (_, Some((e, _))) => Err(e),
+    }
 }
 
 #[test]
@@ -130,6 +474,83 @@ fn lexes_whitespace() {
     assert_eq!(output, vec![]);
 }
 
+#[test]
+fn lexes_newlines_as_separators() {
+    let in_str = "a\n\nb &&\n c \\\nd\n";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Token::text("a"),
+            Semicolon,
+            Token::text("b"),
+            LogAnd,
+            Token::text("c"),
+            Token::text("d"),
+            Semicolon,
+        ]
+    )
+}
+
+#[test]
+fn skips_comments() {
+    let in_str = "echo a#b # c ; d\n# e\nf";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Token::text("echo"),
+            Token::text("a#b"),
+            Semicolon,
+            Token::text("f"),
+        ]
+    )
+}
+
+#[test]
+fn keeps_spans_and_partial_tokens() {
+    let (tokens, error) = lex_spans("ls -l | grep \"x");
+
+    assert_eq!(
+        tokens,
+        vec![
+            (Token::text("ls"), 0..2),
+            (Token::text("-l"), 3..5),
+            (Pipe, 6..7),
+            (Token::text("grep"), 8..12),
+        ]
+    );
+    assert_eq!(error, Some((UnterminatedQuote('"'), 13)));
+}
+
+#[test]
+fn lexes_past_unknown_operators() {
+    let (tokens, errors) = lex_recovering("a ;; b\n[[ x &&& y ]] c\nd 'e");
+
+    let tokens: Vec<_> = tokens.into_iter().map(|(token, _)| token).collect();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::text("a"),
+            Token::text("b"),
+            Semicolon,
+            Token::text("d"),
+        ]
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (UnknownOperator(";;".to_string()), 2),
+            (UnknownOperator("&".to_string()), 7),
+            (UnterminatedQuote('\''), 25),
+        ]
+    );
+}
+
 #[test]
 fn lexes_fork_command() {
     let in_str = "echo this is a --test & cat ./foo.bar > carp";
@@ -165,6 +586,22 @@ fn escapes_chars() {
     )
 }
 
+#[test]
+fn escapes_delimiters_back() {
+    for input in [
+        r"a\ b\;c",
+        r"'a b'\|\\",
+        r#""$(a b)"\ x"#,
+        "x=(1 2)",
+        "plain",
+    ] {
+        match lex(input).unwrap().as_slice() {
+            [Text(word)] => assert_eq!(escape_delimiters(word), input),
+            tokens => panic!("{:?} lexed as {:?}", input, tokens),
+        }
+    }
+}
+
 #[test]
 fn optional_whitespace() {
     let in_str = "echo;tok&&en&alpha||beta>>     \tend";
@@ -219,3 +656,174 @@ fn errors_on_invalid_operators() {
 
     assert_eq!(output, Err(UnknownOperator(";;".to_string())))
 }
+
+#[test]
+fn keeps_quotes_and_substitutions_in_words() {
+    let in_str = "echo 'a b'\"c;d\" $(x | y) ${z}\\$ {";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Token::text("echo"),
+            Token::text("'a b'\"c;d\""),
+            Token::text("$(x | y)"),
+            Token::text("${z}\\$"),
+            LBrace,
+        ]
+    )
+}
+
+#[test]
+fn keeps_array_assignments_in_one_word() {
+    let in_str = "a=(x 'y )' $(z)) b (c)";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Token::text("a=(x 'y )' $(z))"),
+            Token::text("b"),
+            LParen,
+            Token::text("c"),
+            RParen,
+        ]
+    );
+    assert_eq!(lex("a=(x"), Err(UnterminatedArray));
+}
+
+#[test]
+fn lexes_braces_only_as_whole_words() {
+    let in_str = "{ a{b} }";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(output, vec![LBrace, Token::text("a{b}"), RBrace])
+}
+
+#[test]
+fn errors_on_unterminated_quote() {
+    let in_str = "echo \"foo";
+
+    let output = lex(in_str);
+
+    assert_eq!(output, Err(UnterminatedQuote('"')))
+}
+
+#[test]
+fn lexes_redirections_with_fd_numbers() {
+    let in_str = "cmd <in >>log 2>&1 3<&- 4 >x";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Token::text("cmd"),
+            ReadFile,
+            Token::text("in"),
+            AppendFile,
+            Token::text("log"),
+            IoNumber(2),
+            DupOutput,
+            Token::text("1"),
+            IoNumber(3),
+            DupInput,
+            Token::text("-"),
+            Token::text("4"),
+            WriteFile,
+            Token::text("x"),
+        ]
+    )
+}
+
+#[test]
+fn lexes_arithmetic_commands() {
+    let in_str = "((x = (1 + 2) * 3)) && (( echo &) )";
+
+    let output = lex(in_str).unwrap();
+
+    assert_eq!(
+        output,
+        vec![
+            Arith("x = (1 + 2) * 3".to_string()),
+            LogAnd,
+            LParen,
+            LParen,
+            Token::text("echo"),
+            Fork,
+            RParen,
+            RParen,
+        ]
+    )
+}
+
+#[test]
+fn lexes_conditionals_in_command_position() {
+    let in_str = "[[ -n $a && ( $b == \"x y\" || $c < d ) ]]; echo [[ x ]]";
+
+    let output = lex(in_str).unwrap();
+
+    let words = [
+        "-n", "$a", "&&", "(", "$b", "==", "\"x y\"", "||", "$c", "<", "d", ")",
+    ];
+    assert_eq!(
+        output,
+        vec![
+            Conditional(words.iter().map(|w| w.to_string()).collect()),
+            Semicolon,
+            Token::text("echo"),
+            Token::text("[["),
+            Token::text("x"),
+            Token::text("]]"),
+        ]
+    );
+    let negated = |input| lex(input).unwrap().last().cloned();
+    assert_eq!(
+        negated("! [[ x ]]"),
+        Some(Conditional(vec!["x".to_string()]))
+    );
+    assert_eq!(negated("a | ! [[ x ]]"), Some(Token::text("]]")));
+}
+
+#[test]
+fn lexes_unquoted_regex_in_conditionals() {
+    let in_str = "[[ $x =~ ^(a|b)+\\ $ ]]";
+
+    let output = lex(in_str).unwrap();
+
+    let words = ["$x", "=~", "^(a|b)+\\ $"];
+    assert_eq!(
+        output,
+        vec![Conditional(words.iter().map(|w| w.to_string()).collect())]
+    );
+    assert_eq!(lex("[[ -n x"), Err(UnterminatedConditional));
+}
+
+#[test]
+fn skips_multibyte_blanks_in_conditionals() {
+    let output = lex("[[ \u{a0}x\u{2003}]]").unwrap();
+
+    assert_eq!(output, vec![Conditional(vec!["x".to_string()])]);
+    assert_eq!(lex("[[\u{2003}"), Err(UnterminatedConditional));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn lexing_never_panics(input in "\\PC*|[ a-z0-9é\u{a0}\u{2003}\t\n'\"`\\\\$(){}\\[\\]<>|&;#=~*-]{0,40}") {
+        let _ = lex(&input);
+        let (spans, error) = lex_spans(&input);
+        let mut end = 0;
+        for (_, span) in spans {
+            proptest::prop_assert!(end <= span.start && span.start < span.end);
+            proptest::prop_assert!(input.get(span.clone()).is_some());
+            end = span.end;
+        }
+        if let Some((_, offset)) = error {
+            proptest::prop_assert!(end <= offset && input.is_char_boundary(offset));
+        }
+    }
+}
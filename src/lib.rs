@@ -0,0 +1,40 @@
+//! traash as a library: lex, parse and run shell code from Rust.
+//!
+//! [`Shell`] is the way in for most uses. The lexer, parser and syntax tree are public too, for
+//! tools that only need to read shell code, and [`run`] is the whole `traash` command.
+
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod tokens;
+
+mod alias;
+mod arith;
+mod builtins;
+mod capture;
+mod cli;
+mod completion;
+mod conditional;
+mod dump;
+mod editor;
+mod executor;
+mod expand;
+mod format;
+mod highlight;
+mod history;
+mod pattern;
+mod prompt;
+mod shell;
+mod startup;
+mod state;
+mod suggest;
+mod sys;
+mod terminal;
+mod trace;
+
+pub use crate::builtins::Builtin;
+pub use crate::capture::{CaptureOptions, Output};
+pub use crate::cli::run;
+pub use crate::executor::StreamSet;
+pub use crate::shell::Shell;
+pub use crate::state::State;
@@ -1,25 +1,54 @@
-use crate::ast::BinaryOp::LogAnd;
 use crate::ast::Command::Nil;
-use crate::ast::{binary, fork, log_and, sequential, single, BinaryOp, Command, SingleCommand};
-use crate::lexer::lex;
+use crate::ast::{
+    background, binary, function_def, group, not, redirect, sequential, subshell, BinaryOp,
+    Command, CondExpr, RedirectKind, Redirection, SingleCommand, BACKGROUND_PRECEDENCE,
+    NOT_PRECEDENCE,
+};
+#[cfg(test)]
+use crate::ast::{log_and, log_or, pipe, single};
+use crate::conditional::{is_binary_operator, is_unary_operator};
+use crate::lexer::{lex, LexerError};
 use crate::parser::Command::Single;
-use crate::parser::ParserError::{ExtraRParen, MissingRParen};
+use crate::parser::ParserError::{
+    BadConditional, ExtraRBrace, ExtraRParen, MissingCommandAfterOperator, MissingFunctionBody,
+    MissingRBrace, MissingRParen, MissingRedirectTarget, UnexpectedKeyword, UnexpectedToken,
+};
 use crate::tokens::Token;
-use crate::tokens::Token::Semicolon;
 use std::fmt;
+use std::ops::Range;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum Symbol {
     Text(String),
     BinaryOp(Command, BinaryOp),
+    /// The list so far, whose last command ended with `&`, waiting for the rest of the list.
+    Background(Command),
+    /// `!`, waiting for the pipeline it negates.
+    Not,
     Command(Command),
     LParen,
+    LBrace,
+    FunctionHeader(String),
+    Redirection(Redirection),
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ParserError {
     ExtraRParen,
     MissingRParen,
+    ExtraRBrace,
+    MissingRBrace,
+    MissingFunctionBody,
+    MissingRedirectTarget,
+    /// A malformed `[[ ... ]]` expression, with the word where it went wrong.
+    BadConditional(String),
+    /// A token that can't go where it is, such as a word after a subshell or an `&&` with no
+    /// command before it.
+    UnexpectedToken(String),
+    /// `&&`, `||` or `|` followed by the end of the list instead of a command.
+    MissingCommandAfterOperator(String),
+    /// A reserved word that can't go where it is, such as `{` after a subshell.
+    UnexpectedKeyword(String),
 }
 
 impl fmt::Display for ParserError {
@@ -27,110 +56,604 @@ impl fmt::Display for ParserError {
         match self {
             ParserError::ExtraRParen => write!(f, "there was an extra right parenthesis"),
             ParserError::MissingRParen => write!(f, "there was an missing right parenthesis"),
+            ParserError::ExtraRBrace => write!(f, "there was an extra right brace"),
+            ParserError::MissingRBrace => write!(f, "there was a missing right brace"),
+            ParserError::MissingFunctionBody => {
+                write!(
+                    f,
+                    "function definition was not followed by a compound command"
+                )
+            }
+            ParserError::MissingRedirectTarget => {
+                write!(f, "redirection was not followed by a file name")
+            }
+            ParserError::BadConditional(word) => {
+                write!(f, "unexpected {} in conditional expression", word)
+            }
+            ParserError::UnexpectedToken(token) => write!(f, "unexpected token `{}'", token),
+            ParserError::MissingCommandAfterOperator(op) => {
+                write!(f, "`{}' was not followed by a command", op)
+            }
+            ParserError::UnexpectedKeyword(word) => write!(f, "unexpected keyword `{}'", word),
         }
     }
 }
 
+/// Parses the words of a `[[ ... ]]` command. `||` binds loosest, then `&&`, then `!`.
+struct ConditionalParser<'a> {
+    words: &'a [String],
+}
+
+impl<'a> ConditionalParser<'a> {
+    fn peek(&self, i: usize) -> Option<&'a str> {
+        self.words.get(i).map(|w| w.as_str())
+    }
+
+    /// Takes the next word, which must be an operand rather than an operator.
+    fn operand(&mut self) -> Result<String, ParserError> {
+        match self.peek(0) {
+            None => Err(BadConditional("end of expression".to_string())),
+            Some(w @ "&&") | Some(w @ "||") | Some(w @ "(") | Some(w @ ")") => {
+                Err(BadConditional(format!("`{}'", w)))
+            }
+            Some(w) => {
+                self.words = &self.words[1..];
+                Ok(w.to_string())
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<CondExpr, ParserError> {
+        let mut expr = self.and()?;
+        while self.peek(0) == Some("||") {
+            self.words = &self.words[1..];
+            expr = CondExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<CondExpr, ParserError> {
+        let mut expr = self.not()?;
+        while self.peek(0) == Some("&&") {
+            self.words = &self.words[1..];
+            expr = CondExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<CondExpr, ParserError> {
+        match self.peek(0) {
+            Some("!") => {
+                self.words = &self.words[1..];
+                Ok(CondExpr::Not(Box::new(self.not()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<CondExpr, ParserError> {
+        if self.peek(0) == Some("(") {
+            self.words = &self.words[1..];
+            let expr = self.or()?;
+            return match self.peek(0) {
+                Some(")") => {
+                    self.words = &self.words[1..];
+                    Ok(expr)
+                }
+                Some(w) => Err(BadConditional(format!("`{}'", w))),
+                None => Err(BadConditional("end of expression".to_string())),
+            };
+        }
+        let first = self.operand()?;
+        match self.peek(0) {
+            Some(op) if is_binary_operator(op) => {
+                self.words = &self.words[1..];
+                Ok(CondExpr::Binary(first, op.to_string(), self.operand()?))
+            }
+            Some(w) if is_unary_operator(&first) && !matches!(w, "&&" | "||" | ")") => {
+                Ok(CondExpr::Unary(first, self.operand()?))
+            }
+            _ => Ok(CondExpr::Word(first)),
+        }
+    }
+}
+
+/// Parses the words between `[[` and `]]` into an expression.
+fn parse_conditional(words: &[String]) -> Result<CondExpr, ParserError> {
+    let mut parser = ConditionalParser { words };
+    let expr = parser.or()?;
+    match parser.peek(0) {
+        None => Ok(expr),
+        Some(w) => Err(BadConditional(format!("`{}'", w))),
+    }
+}
+
 struct Parser<'a> {
     tokens: &'a [Token],
+    /// How many tokens there were to begin with, to tell where the current one is.
+    len: usize,
     stack: Vec<Symbol>,
+    /// The errors found so far, each with the range of tokens it was found at.
+    errors: Vec<(ParserError, Range<usize>)>,
 }
 
 impl Parser<'_> {
-    fn new(tokens: &[Token]) -> Parser {
+    fn new(tokens: &[Token]) -> Parser<'_> {
         Parser {
             tokens,
+            len: tokens.len(),
             stack: vec![],
+            errors: vec![],
         }
     }
 
-    fn reduce_single(self: &mut Self) -> Command {
+    /// The index of the current token.
+    fn position(&self) -> usize {
+        self.len - self.tokens.len()
+    }
+
+    /// Whether the next word would be the first word of a command, which is where reserved words
+    /// like `{` and `}` are recognized.
+    fn in_command_position(&self) -> bool {
+        !matches!(
+            self.stack.last(),
+            Some(Symbol::Text(_)) | Some(Symbol::Redirection(_))
+        )
+    }
+
+    /// Whether the stack ends with exactly one word in command position, optionally preceded by
+    /// the `function` keyword.
+    fn single_word_on_stack(&self) -> bool {
+        let len = self.stack.len();
+        match self.stack.last() {
+            Some(Symbol::Text(_)) => !matches!(
+                len.checked_sub(2).map(|i| &self.stack[i]),
+                Some(Symbol::Text(_)) | Some(Symbol::Command(_))
+            ),
+            _ => false,
+        }
+    }
+
+    /// Reduces the words and redirections on top of the stack into a simple command. Redirections
+    /// with no words in front of them apply to the compound command before them, if any.
+    fn reduce_single(&mut self) -> Command {
         let mut args: Vec<String> = vec![];
-        while let Some(Symbol::Text(str)) = self.stack.last() {
-            args.push(str.clone());
+        let mut redirections: Vec<Redirection> = vec![];
+        loop {
+            match self.stack.last() {
+                Some(Symbol::Text(str)) => args.push(str.clone()),
+                Some(Symbol::Redirection(r)) => redirections.push(r.clone()),
+                _ => break,
+            }
             self.stack.pop();
         }
-        if args.is_empty() {
+        args.reverse();
+        redirections.reverse();
+        let command = if !args.is_empty() {
+            Single(SingleCommand { args })
+        } else if let (false, Some(Symbol::Command(_))) =
+            (redirections.is_empty(), self.stack.last())
+        {
+            match self.stack.pop() {
+                Some(Symbol::Command(command)) => command,
+                _ => Nil,
+            }
+        } else {
             Nil
+        };
+        if redirections.is_empty() {
+            command
         } else {
-            args.reverse();
-            Single(SingleCommand { args })
+            redirect(command, redirections)
+        }
+    }
+
+    /// Reads a redirection operator, optionally preceded by a file descriptor number, and its
+    /// target. Leaves the target as the current token.
+    fn read_redirection(&mut self) -> Result<Redirection, ParserError> {
+        let fd = match self.tokens.first() {
+            Some(Token::IoNumber(fd)) => {
+                self.tokens = &self.tokens[1..];
+                Some(*fd)
+            }
+            _ => None,
+        };
+        let kind = self
+            .tokens
+            .first()
+            .and_then(RedirectKind::from)
+            .ok_or(MissingRedirectTarget)?;
+        match self.tokens.get(1) {
+            Some(Token::Text(target)) => {
+                self.tokens = &self.tokens[1..];
+                Ok(Redirection {
+                    fd: fd.unwrap_or_else(|| kind.default_fd()),
+                    kind,
+                    target: target.clone(),
+                })
+            }
+            _ => Err(MissingRedirectTarget),
         }
     }
 
-    fn reduce(self: &mut Self) -> Command {
+    /// Reduces the top of the stack to one command, folding in the operators before it that
+    /// bind at least as tightly as `loosest`. Stops at an opening bracket or function header.
+    fn reduce(&mut self, loosest: u8) -> Command {
+        let mut command = match self.stack.last() {
+            Some(Symbol::Text(_)) | Some(Symbol::Redirection(_)) => self.reduce_single(),
+            Some(Symbol::Command(_)) => match self.stack.pop() {
+                Some(Symbol::Command(command)) => command,
+                _ => Nil,
+            },
+            _ => Nil,
+        };
         loop {
-            let push = match self.stack.pop() {
-                Some(Symbol::Text(text)) => {
-                    self.stack.push(Symbol::Text(text));
-                    let command = self.reduce_single();
-                    Symbol::Command(command)
+            command = match self.stack.pop() {
+                Some(Symbol::BinaryOp(left, op)) if op.precedence() >= loosest => {
+                    binary(op, left, command)
                 }
-                Some(Symbol::Command(cmd)) => match self.stack.pop() {
-                    Some(Symbol::BinaryOp(left, op)) => Symbol::Command(binary(op, left, cmd)),
-                    Some(Symbol::LParen) => {
-                        self.stack.push(Symbol::LParen);
-                        return cmd;
-                    }
-                    None => {
-                        return cmd;
-                    }
-                    _ => continue,
+                Some(Symbol::Background(list)) if BACKGROUND_PRECEDENCE >= loosest => match command
+                {
+                    Nil => list,
+                    command => sequential(list, command),
                 },
-                Some(Symbol::BinaryOp(left, op)) => Symbol::Command(binary(op, left, Nil)),
-                None => {
-                    return Nil;
+                Some(Symbol::Not) if NOT_PRECEDENCE >= loosest => not(command),
+                Some(symbol) => {
+                    self.stack.push(symbol);
+                    return command;
                 }
-                Some(x) => panic!("Encountered a {:#?}", x),
+                None => return command,
             };
-            self.stack.push(push);
         }
     }
 
-    fn parse(self: &mut Self) -> Result<Command, ParserError> {
-        // Read through tokens
-        loop {
-            let token = match self.tokens.first() {
-                Some(t) => t,
-                None => break,
+    /// Whether a `!` here would negate a pipeline rather than be a word. Only a whole pipeline
+    /// can be negated, so not a command after `|` or another command.
+    fn negation_allowed(&self) -> bool {
+        self.in_command_position()
+            && !matches!(
+                self.stack.last(),
+                Some(Symbol::BinaryOp(_, BinaryOp::Pipe)) | Some(Symbol::Command(_))
+            )
+    }
+
+    /// Pushes a finished compound command, attaching it to a pending function header if there is
+    /// one.
+    fn push_compound(&mut self, command: Command) {
+        if let Some(Symbol::FunctionHeader(name)) = self.stack.last() {
+            let definition = function_def(name, command);
+            self.stack.pop();
+            self.stack.push(Symbol::Command(definition));
+        } else {
+            self.stack.push(Symbol::Command(command));
+        }
+    }
+
+    /// Whether the words and redirections on top of the stack follow a compound command, which
+    /// can only be followed by redirections.
+    fn follows_compound(&self) -> bool {
+        let mut symbols = self.stack.iter().rev();
+        matches!(
+            symbols.find(|s| !matches!(s, Symbol::Redirection(_))),
+            Some(Symbol::Command(_))
+        )
+    }
+
+    /// Whether the innermost bracket still open is `open`, so that the closing one pairs with it.
+    fn innermost_open(&self, open: Symbol) -> bool {
+        let mut symbols = self.stack.iter().rev();
+        symbols.find(|s| matches!(s, Symbol::LParen | Symbol::LBrace)) == Some(&open)
+    }
+
+    /// A word that isn't a keyword here, which can't follow a compound command.
+    fn word(&self, word: &str) -> Result<Symbol, ParserError> {
+        match self.follows_compound() {
+            true => Err(UnexpectedToken(word.to_string())),
+            false => Ok(Symbol::Text(word.to_string())),
+        }
+    }
+
+    /// Fails if a compound command, starting with `token`, can't go here because another command
+    /// is already in progress.
+    fn check_compound_start(&self, token: &str) -> Result<(), ParserError> {
+        match self.stack.last() {
+            Some(Symbol::Text(_)) | Some(Symbol::Redirection(_)) | Some(Symbol::Command(_)) => {
+                Err(UnexpectedToken(token.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Shifts the current token onto the stack, reducing if it is an operator or a closing
+    /// bracket. Leaves the last token it used as the current one.
+    fn shift(&mut self, token: &Token) -> Result<(), ParserError> {
+        if let Some(Symbol::FunctionHeader(_)) = self.stack.last() {
+            match token {
+                Token::LBrace | Token::LParen => {}
+                _ => Err(MissingFunctionBody)?,
+            }
+        }
+        if let Some(Symbol::BinaryOp(_, op)) = self.stack.last() {
+            let ends_list = match token {
+                Token::RBrace => true,
+                token => BinaryOp::from(token).is_some() || *token == Token::Fork,
             };
+            if *op != BinaryOp::Seq && (ends_list || *token == Token::RParen) {
+                Err(MissingCommandAfterOperator(op.symbol().to_string()))?;
+            }
+        }
 
-            let push = match BinaryOp::from(token) {
-                Some(op) => {
-                    let command = self.reduce();
-                    Symbol::BinaryOp(command, op)
+        let push = match BinaryOp::from(token) {
+            Some(op) => {
+                // Every element of a list needs a command, as in `a; b` but not `a; ; b`.
+                let empty = !matches!(
+                    self.stack.last(),
+                    Some(Symbol::Text(_)) | Some(Symbol::Redirection(_)) | Some(Symbol::Command(_))
+                );
+                let command = self.reduce(op.precedence());
+                if empty {
+                    Err(UnexpectedToken(op.symbol().to_string()))?;
                 }
-                None => match token {
-                    Token::Text(str) => Symbol::Text(str.clone()),
-                    Token::LParen => Symbol::LParen,
-                    Token::RParen => {
-                        let command = self.reduce();
-                        match self.stack.pop() {
-                            Some(Symbol::LParen) => Symbol::Command(command),
-                            _ => Err(ExtraRParen)?,
+                Some(Symbol::BinaryOp(command, op))
+            }
+            None => match token {
+                Token::Text(str) => match self.stack.last() {
+                    Some(Symbol::Text(keyword))
+                        if keyword == "function" && self.single_word_on_stack() =>
+                    {
+                        self.stack.pop();
+                        Some(Symbol::FunctionHeader(str.clone()))
+                    }
+                    _ if str == "!" && self.negation_allowed() => Some(Symbol::Not),
+                    _ => Some(self.word(str)?),
+                },
+                // `&` ends the `&&` or `||` list before it, which joins the list so far.
+                Token::Fork => {
+                    let command = self.reduce(BACKGROUND_PRECEDENCE + 1);
+                    if command == Nil {
+                        Err(UnexpectedToken("&".to_string()))?;
+                    }
+                    self.stack.push(Symbol::Command(background(command)));
+                    Some(Symbol::Background(self.reduce(0)))
+                }
+                Token::LParen if self.tokens.get(1) == Some(&Token::RParen) => {
+                    match self.stack.last() {
+                        Some(Symbol::FunctionHeader(_)) => {}
+                        _ if self.single_word_on_stack() => {
+                            if let Some(Symbol::Text(name)) = self.stack.pop() {
+                                self.stack.push(Symbol::FunctionHeader(name));
+                            }
                         }
+                        _ => Err(MissingFunctionBody)?,
                     }
-                    _ => panic!(),
+                    self.tokens = &self.tokens[1..];
+                    None
+                }
+                Token::LParen => {
+                    self.check_compound_start("(")?;
+                    Some(Symbol::LParen)
+                }
+                Token::RParen if self.innermost_open(Symbol::LParen) => {
+                    let command = self.reduce(0);
+                    self.stack.pop();
+                    self.push_compound(subshell(command));
+                    None
+                }
+                Token::RParen => Err(ExtraRParen)?,
+                Token::LBrace if self.in_command_position() => match self.stack.last() {
+                    Some(Symbol::Command(_)) => Err(UnexpectedKeyword("{".to_string()))?,
+                    _ => Some(Symbol::LBrace),
                 },
-            };
+                Token::LBrace => Some(self.word("{")?),
+                Token::RBrace if self.in_command_position() => {
+                    if !self.innermost_open(Symbol::LBrace) {
+                        Err(ExtraRBrace)?;
+                    }
+                    let command = self.reduce(0);
+                    self.stack.pop();
+                    self.push_compound(group(command));
+                    None
+                }
+                Token::RBrace => Some(self.word("}")?),
+                Token::Arith(expr) => {
+                    self.check_compound_start("((")?;
+                    self.push_compound(Command::Arith(expr.clone()));
+                    None
+                }
+                Token::Conditional(words) => {
+                    self.check_compound_start("[[")?;
+                    self.push_compound(Command::Conditional(parse_conditional(words)?));
+                    None
+                }
+                Token::IoNumber(_)
+                | Token::ReadFile
+                | Token::WriteFile
+                | Token::AppendFile
+                | Token::DupInput
+                | Token::DupOutput => Some(Symbol::Redirection(self.read_redirection()?)),
+                token => Err(UnexpectedToken(format!("{:?}", token)))?,
+            },
+        };
+        if let Some(push) = push {
             self.stack.push(push);
+        }
+        Ok(())
+    }
 
+    /// Records `error` at the current token and puts an error node in place of the command it
+    /// was found in. Unless the error is a command missing before the token, skips the token and
+    /// what follows up to where parsing can pick up again: the next `;` or newline, or a closing
+    /// bracket, outside any brackets opened while skipping.
+    fn recover(&mut self, error: ParserError) {
+        let start = self.position();
+        let end = (start + 1).min(self.len);
+        let missing = matches!(error, MissingCommandAfterOperator(_));
+        self.errors.push((error, start..end));
+        while matches!(
+            self.stack.last(),
+            Some(Symbol::Text(_))
+                | Some(Symbol::Redirection(_))
+                | Some(Symbol::Command(_))
+                | Some(Symbol::FunctionHeader(_))
+        ) {
+            self.stack.pop();
+        }
+        self.stack.push(Symbol::Command(Command::Error));
+        if missing {
+            return;
+        }
+        let mut depth = 0;
+        let mut skipped = false;
+        while let Some(token) = self.tokens.first() {
+            match token {
+                Token::Semicolon | Token::RParen | Token::RBrace if depth == 0 && skipped => break,
+                Token::LParen | Token::LBrace => depth += 1,
+                Token::RParen | Token::RBrace if depth > 0 => depth -= 1,
+                _ => {}
+            }
             self.tokens = &self.tokens[1..];
+            skipped = true;
         }
+    }
 
-        // Clear out the stack
-        let result = self.reduce();
-        match self.stack.last() {
-            None => Ok(result),
-            Some(Symbol::LParen) => Err(MissingRParen),
-            _ => panic!(),
+    /// Shifts every token onto the stack, reducing as operators and closing brackets arrive, and
+    /// recovering from errors.
+    fn shift_all(&mut self) {
+        while let Some(token) = self.tokens.first() {
+            match self.shift(token) {
+                Ok(()) => self.tokens = &self.tokens[1..],
+                Err(e) => self.recover(e),
+            }
         }
     }
+
+    /// Parses all the tokens, with the errors found replaced by error nodes. Brackets left open
+    /// at the end are closed, and a missing function body or command after an operator is an
+    /// error node.
+    fn parse(&mut self) -> Command {
+        self.shift_all();
+        let end = self.len..self.len;
+        if let Some(Symbol::BinaryOp(_, op)) = self.stack.last() {
+            if *op != BinaryOp::Seq {
+                let error = MissingCommandAfterOperator(op.symbol().to_string());
+                self.errors.push((error, end.clone()));
+                self.stack.push(Symbol::Command(Command::Error));
+            }
+        }
+        loop {
+            let command = self.reduce(0);
+            let error = match self.stack.pop() {
+                None => return command,
+                Some(Symbol::LParen) => {
+                    self.push_compound(subshell(command));
+                    MissingRParen
+                }
+                Some(Symbol::LBrace) => {
+                    self.push_compound(group(command));
+                    MissingRBrace
+                }
+                Some(Symbol::FunctionHeader(_)) => {
+                    self.stack.push(Symbol::Command(Command::Error));
+                    MissingFunctionBody
+                }
+                Some(symbol) => unreachable!("{:?} left after reducing", symbol),
+            };
+            self.errors.push((error, end.clone()));
+        }
+    }
+}
+
+/// Where a word following some tokens would go.
+#[derive(Debug, Eq, PartialEq)]
+pub enum WordPosition {
+    Command,
+    Argument,
+    RedirectTarget,
+}
+
+/// Works out where a word typed after `tokens` would go, for completion. Input that doesn't
+/// parse is treated as being in argument position.
+pub fn next_word_position(tokens: &[Token]) -> WordPosition {
+    if tokens.last().and_then(RedirectKind::from).is_some() {
+        return WordPosition::RedirectTarget;
+    }
+    let mut parser = Parser::new(tokens);
+    parser.shift_all();
+    if !parser.errors.is_empty() {
+        return WordPosition::Argument;
+    }
+    match parser.stack.last() {
+        Some(Symbol::Text(_))
+        | Some(Symbol::Redirection(_))
+        | Some(Symbol::Command(_))
+        | Some(Symbol::FunctionHeader(_)) => WordPosition::Argument,
+        _ => WordPosition::Command,
+    }
 }
 
 pub fn parse(tokens: &[Token]) -> Result<Command, ParserError> {
+    match parse_recovering(tokens) {
+        (command, errors) if errors.is_empty() => Ok(command),
+        (_, mut errors) => Err(errors.swap_remove(0).0),
+    }
+}
+
+/// Parses `tokens` past any errors, for reporting all of them. Each comes with the range of
+/// tokens it was found at, which is empty at `tokens.len()` when the tokens end too soon. The
+/// command an error is in becomes `Command::Error` in the tree.
+pub fn parse_recovering(tokens: &[Token]) -> (Command, Vec<(ParserError, Range<usize>)>) {
     let mut parser = Parser::new(tokens);
-    parser.parse()
+    let command = parser.parse();
+    (command, parser.errors)
+}
+
+/// Whether `input` stops in the middle of a command, such as inside quotes or a brace group or
+/// after `&&`, so that the rest should be read from the next line.
+pub fn is_incomplete(input: &str) -> bool {
+    let tokens = match lex(input) {
+        Ok(tokens) => tokens,
+        Err(LexerError::UnknownOperator(_)) => return false,
+        Err(_) => return true,
+    };
+    // Only errors at the end could be fixed by more input.
+    let (_, errors) = parse_recovering(&tokens);
+    !errors.is_empty()
+        && errors.iter().all(|(e, span)| {
+            span.start == tokens.len()
+                && matches!(
+                    e,
+                    MissingRBrace
+                        | MissingRParen
+                        | MissingFunctionBody
+                        | MissingCommandAfterOperator(_)
+                )
+        })
+}
+
+#[test]
+fn detects_incomplete_input() {
+    for input in &[
+        "echo 'a", "echo $(a", "{ a", "(a", "f()", "a &&", "a |", "a \\",
+    ] {
+        assert!(is_incomplete(input), "{:?} is incomplete", input);
+    }
+    for input in &["echo 'a'", "{ a; }", "a && b", "a )", "a ;;"] {
+        assert!(!is_incomplete(input), "{:?} is complete", input);
+    }
+    let tokens = lex("{\n  echo a\n  echo b\n}\n").unwrap();
+    assert_eq!(
+        parse(&tokens),
+        Ok(sequential(
+            group(sequential(
+                sequential(
+                    single(vec!["echo".to_string(), "a".to_string()]),
+                    single(vec!["echo".to_string(), "b".to_string()])
+                ),
+                Nil
+            )),
+            Nil
+        ))
+    );
 }
 
 #[test]
@@ -142,11 +665,19 @@ fn parses_empty_tokens() {
 }
 
 #[test]
-fn parses_empty_commands() {
-    let tokens = vec![Token::Semicolon, Token::Semicolon, Token::LogAnd];
-    let result = parse(tokens.as_slice()).unwrap();
+fn errors_on_empty_commands() {
+    let tokens = vec![Token::Semicolon, Token::Semicolon];
+    let result = parse(tokens.as_slice());
+    assert_eq!(result, Err(UnexpectedToken(";".to_string())));
 
-    assert_eq!(result, log_and(sequential(sequential(Nil, Nil), Nil), Nil));
+    let tokens = vec![
+        Token::text("a"),
+        Token::Semicolon,
+        Token::Semicolon,
+        Token::text("b"),
+    ];
+    let result = parse(tokens.as_slice());
+    assert_eq!(result, Err(UnexpectedToken(";".to_string())));
 }
 
 #[test]
@@ -208,10 +739,10 @@ fn reorders_binary_chain_with_paren() {
         result,
         sequential(
             single(vec!["echo".to_string(), "foo".to_string()]),
-            sequential(
+            subshell(sequential(
                 single(vec!["echo".to_string(), "bar".to_string()]),
                 single(vec!["echo".to_string(), "spam".to_string()])
-            )
+            ))
         )
     );
 }
@@ -229,12 +760,41 @@ fn parses_binary_command_with_trailing_op() {
 
     assert_eq!(
         result,
-        fork(
-            sequential(
-                single(vec!["echo".to_string(), "foo".to_string()]),
-                single(vec!["echo".to_string()]),
-            ),
-            Nil
+        sequential(
+            single(vec!["echo".to_string(), "foo".to_string()]),
+            background(single(vec!["echo".to_string()])),
+        )
+    );
+}
+
+#[test]
+fn follows_posix_precedence() {
+    let parsed = |input: &str| parse(&lex(input).unwrap()).unwrap();
+    let word = |w: &str| single(vec![w.to_string()]);
+    assert_eq!(
+        parsed("a; b | c && d"),
+        sequential(word("a"), log_and(pipe(word("b"), word("c")), word("d")))
+    );
+    assert_eq!(
+        parsed("a && b | c || d"),
+        log_or(log_and(word("a"), pipe(word("b"), word("c"))), word("d"))
+    );
+    assert_eq!(
+        parsed("a && b & c; d &"),
+        sequential(
+            sequential(background(log_and(word("a"), word("b"))), word("c")),
+            background(word("d"))
+        )
+    );
+    assert_eq!(
+        parsed("! a | b && ! ! c"),
+        log_and(not(pipe(word("a"), word("b"))), not(not(word("c"))))
+    );
+    assert_eq!(
+        parsed("a | ! b; echo !"),
+        sequential(
+            pipe(word("a"), single(vec!["!".to_string(), "b".to_string()])),
+            single(vec!["echo".to_string(), "!".to_string()])
         )
     );
 }
@@ -252,10 +812,10 @@ fn parses_happy_command_with_parentheses() {
 
     assert_eq!(
         result,
-        sequential(
+        subshell(sequential(
             single(vec!["uptime".to_string()]),
             single(vec!["echo".to_string()]),
-        )
+        ))
     );
 }
 
@@ -279,10 +839,10 @@ fn parses_happy_command_with_nested_parentheses() {
         result,
         sequential(
             single(vec!["uptime".to_string()]),
-            log_and(
-                fork(single(vec!["echo".to_string()]), Nil),
+            subshell(log_and(
+                subshell(background(single(vec!["echo".to_string()]))),
                 single(vec!["apt".to_string()]),
-            )
+            ))
         )
     );
 }
@@ -298,10 +858,10 @@ fn fullstack_parse() {
         result,
         sequential(
             single(vec!["uptime".to_string()]),
-            log_and(
-                fork(single(vec!["echo".to_string()]), Nil),
+            subshell(log_and(
+                subshell(background(single(vec!["echo".to_string()]))),
                 single(vec!["apt".to_string()]),
-            )
+            ))
         )
     );
 }
@@ -323,7 +883,6 @@ fn errors_on_extra_right_paren() {
 #[test]
 fn errors_on_missing_right_paren() {
     let tokens = vec![
-        Token::Semicolon,
         Token::LParen,
         Token::LParen,
         Token::text("echo"),
@@ -332,3 +891,375 @@ fn errors_on_missing_right_paren() {
     let result = parse(tokens.as_slice());
     assert_eq!(result, Err(ParserError::MissingRParen))
 }
+
+#[test]
+fn parses_function_definition() {
+    let input = "greet() { echo hi; }; greet";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    assert_eq!(
+        result,
+        sequential(
+            function_def(
+                "greet",
+                group(sequential(
+                    single(vec!["echo".to_string(), "hi".to_string()]),
+                    Nil
+                ))
+            ),
+            single(vec!["greet".to_string()]),
+        )
+    );
+}
+
+#[test]
+fn parses_function_keyword_definition() {
+    let input = "function greet { echo; }";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    assert_eq!(
+        result,
+        function_def(
+            "greet",
+            group(sequential(single(vec!["echo".to_string()]), Nil))
+        )
+    );
+}
+
+#[test]
+fn braces_are_words_in_argument_position() {
+    let input = "echo { }";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    assert_eq!(
+        result,
+        single(vec!["echo".to_string(), "{".to_string(), "}".to_string()])
+    );
+}
+
+#[test]
+fn errors_on_function_without_body() {
+    let input = "greet() echo";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice());
+
+    assert_eq!(result, Err(ParserError::MissingFunctionBody))
+}
+
+#[test]
+fn errors_on_missing_right_brace() {
+    let input = "greet() { echo }";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice());
+
+    assert_eq!(result, Err(ParserError::MissingRBrace))
+}
+
+#[test]
+fn parses_redirections_on_simple_commands() {
+    let input = "cat <in 2>&1 -n >>out";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    assert_eq!(
+        result,
+        redirect(
+            single(vec!["cat".to_string(), "-n".to_string()]),
+            vec![
+                Redirection {
+                    fd: 0,
+                    kind: RedirectKind::Read,
+                    target: "in".to_string()
+                },
+                Redirection {
+                    fd: 2,
+                    kind: RedirectKind::DupOutput,
+                    target: "1".to_string()
+                },
+                Redirection {
+                    fd: 1,
+                    kind: RedirectKind::Append,
+                    target: "out".to_string()
+                },
+            ]
+        )
+    );
+}
+
+#[test]
+fn parses_redirections_on_compound_commands() {
+    let input = "{ a; b; } > log 2>&1; (c) <in";
+
+    let tokens = lex(input).unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    assert_eq!(
+        result,
+        sequential(
+            redirect(
+                group(sequential(
+                    sequential(single(vec!["a".to_string()]), single(vec!["b".to_string()])),
+                    Nil
+                )),
+                vec![
+                    Redirection {
+                        fd: 1,
+                        kind: RedirectKind::Write,
+                        target: "log".to_string()
+                    },
+                    Redirection {
+                        fd: 2,
+                        kind: RedirectKind::DupOutput,
+                        target: "1".to_string()
+                    },
+                ]
+            ),
+            redirect(
+                subshell(single(vec!["c".to_string()])),
+                vec![Redirection {
+                    fd: 0,
+                    kind: RedirectKind::Read,
+                    target: "in".to_string()
+                }]
+            )
+        )
+    );
+}
+
+#[test]
+fn errors_on_missing_redirect_target() {
+    let tokens = vec![Token::text("echo"), Token::WriteFile, Token::Semicolon];
+    let result = parse(tokens.as_slice());
+    assert_eq!(result, Err(ParserError::MissingRedirectTarget))
+}
+
+#[test]
+fn parses_conditionals() {
+    let tokens = lex("[[ ! -f $x && ( $a == b* || -z \"$c\" ) ]] > log").unwrap();
+    let result = parse(tokens.as_slice()).unwrap();
+
+    let word = |w: &str| w.to_string();
+    let expr = CondExpr::And(
+        Box::new(CondExpr::Not(Box::new(CondExpr::Unary(
+            word("-f"),
+            word("$x"),
+        )))),
+        Box::new(CondExpr::Or(
+            Box::new(CondExpr::Binary(word("$a"), word("=="), word("b*"))),
+            Box::new(CondExpr::Unary(word("-z"), word("\"$c\""))),
+        )),
+    );
+    assert_eq!(
+        result,
+        redirect(
+            Command::Conditional(expr),
+            vec![Redirection {
+                fd: 1,
+                kind: RedirectKind::Write,
+                target: "log".to_string()
+            }]
+        )
+    );
+}
+
+#[test]
+fn errors_on_malformed_conditionals() {
+    for input in &[
+        "[[ ]]",
+        "[[ a && ]]",
+        "[[ ( a ]]",
+        "[[ a b ]]",
+        "[[ a == ]]",
+    ] {
+        let tokens = lex(input).unwrap();
+        assert!(matches!(
+            parse(&tokens),
+            Err(ParserError::BadConditional(_))
+        ));
+    }
+}
+
+#[test]
+fn finds_where_errors_are() {
+    let errors = |input: &str| parse_recovering(&lex(input).unwrap()).1;
+    assert_eq!(errors("a; b ) c"), vec![(ExtraRParen, 3..4)]);
+    assert_eq!(errors("a > ; b"), vec![(MissingRedirectTarget, 1..2)]);
+    assert_eq!(errors("f() x"), vec![(MissingFunctionBody, 3..4)]);
+    assert_eq!(
+        errors("{ a; ( b"),
+        vec![(MissingRParen, 5..5), (MissingRBrace, 5..5)]
+    );
+}
+
+#[test]
+fn recovers_from_errors() {
+    let tokens = lex("a (b) c; d && ; } e\n(g >; h)").unwrap();
+    let (command, errors) = parse_recovering(&tokens);
+    assert_eq!(
+        errors,
+        vec![
+            (UnexpectedToken("(".to_string()), 1..2),
+            (MissingCommandAfterOperator("&&".to_string()), 8..9),
+            (ExtraRBrace, 9..10),
+            (MissingRedirectTarget, 14..15),
+        ]
+    );
+    assert_eq!(
+        command,
+        sequential(
+            sequential(
+                sequential(
+                    Command::Error,
+                    log_and(single(vec!["d".to_string()]), Command::Error)
+                ),
+                Command::Error
+            ),
+            subshell(sequential(Command::Error, single(vec!["h".to_string()])))
+        )
+    );
+}
+
+#[test]
+fn errors_on_commands_after_commands() {
+    for input in &[
+        "a (b)",
+        "((x)) !",
+        "{ a a ((x)) }",
+        "(a) { b; }",
+        "(a) > f b",
+    ] {
+        let tokens = lex(input).unwrap();
+        assert!(
+            matches!(
+                parse(&tokens),
+                Err(UnexpectedToken(_)) | Err(UnexpectedKeyword(_))
+            ),
+            "{:?}",
+            input
+        );
+    }
+}
+
+#[test]
+fn errors_on_operators_without_commands() {
+    let error = |input: &str| parse(&lex(input).unwrap()).unwrap_err();
+    assert_eq!(error("&& a"), UnexpectedToken("&&".to_string()));
+    assert_eq!(error("a; | b"), UnexpectedToken("|".to_string()));
+    assert_eq!(error("a & &"), UnexpectedToken("&".to_string()));
+    assert_eq!(
+        error("a || ; b"),
+        MissingCommandAfterOperator("||".to_string())
+    );
+    assert_eq!(error("(a |)"), MissingCommandAfterOperator("|".to_string()));
+}
+
+#[test]
+fn finds_position_of_next_word() {
+    let position = |input| next_word_position(&lex(input).unwrap());
+
+    assert_eq!(position(""), WordPosition::Command);
+    assert_eq!(position("echo"), WordPosition::Argument);
+    assert_eq!(position("echo a |"), WordPosition::Command);
+    assert_eq!(position("a && { b; ("), WordPosition::Command);
+    assert_eq!(position("cat <"), WordPosition::RedirectTarget);
+    assert_eq!(position("cat < in"), WordPosition::Argument);
+    assert_eq!(position("(a)"), WordPosition::Argument);
+}
+
+#[cfg(test)]
+fn token_strategy() -> impl proptest::strategy::Strategy<Value = Token> {
+    use proptest::prelude::*;
+    let word = prop_oneof![
+        "[a-z][a-z0-9_.=/-]{0,5}",
+        "'[a-z $|;&(){}]{0,5}'",
+        "\"[a-z |;&(){}]{0,5}\"",
+        "[a-z][ |;&()<>]{1,2}[a-z]",
+        Just("$x".to_string()),
+        Just("\"$x\"".to_string()),
+        Just("!".to_string()),
+    ];
+    let conditional = prop_oneof![
+        Just("a"),
+        Just("$x"),
+        Just("-n"),
+        Just("-f"),
+        Just("=="),
+        Just("<"),
+        Just("!"),
+        Just("&&"),
+        Just("||"),
+        Just("("),
+        Just(")"),
+    ];
+    prop_oneof![
+        8 => word.prop_map(Token::Text),
+        1 => Just(Token::LogAnd),
+        1 => Just(Token::LogOr),
+        1 => Just(Token::Pipe),
+        1 => Just(Token::Semicolon),
+        1 => Just(Token::Fork),
+        1 => Just(Token::WriteFile),
+        1 => Just(Token::AppendFile),
+        1 => Just(Token::ReadFile),
+        1 => Just(Token::DupInput),
+        1 => Just(Token::DupOutput),
+        1 => (0..10).prop_map(Token::IoNumber),
+        1 => Just(Token::LParen),
+        1 => Just(Token::RParen),
+        1 => Just(Token::LBrace),
+        1 => Just(Token::RBrace),
+        1 => Just(Token::Arith("x + 1".to_string())),
+        1 => proptest::collection::vec(conditional, 1..5)
+            .prop_map(|words| Token::Conditional(words.iter().map(|w| w.to_string()).collect())),
+    ]
+}
+
+#[test]
+fn prints_commands_that_parse_back() {
+    for input in &[
+        "> a {",
+        "{ ((x + 1)) }",
+        "{ a; } > f && b &",
+        "f() ( a )",
+        "a & b &",
+        "{ a & } | ! b",
+        "! { a || b; } && ! c",
+    ] {
+        let command = parse(&lex(input).unwrap()).unwrap();
+        assert_eq!(parse(&lex(&command.to_string()).unwrap()), Ok(command));
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn parsing_never_panics(tokens in proptest::collection::vec(token_strategy(), 0..20)) {
+        let _ = parse(&tokens);
+        let _ = next_word_position(&tokens);
+    }
+
+    #[test]
+    fn incomplete_input_detection_never_panics(input in "[ a-z'\"\\\\$(){}<>|&;#\n]{0,30}") {
+        let _ = is_incomplete(&input);
+    }
+
+    #[test]
+    fn printed_commands_parse_to_the_same_tree(
+        tokens in proptest::collection::vec(token_strategy(), 0..20)
+    ) {
+        if let Ok(command) = parse(&tokens) {
+            let printed = command.to_string();
+            let reparsed = lex(&printed).map(|tokens| parse(&tokens));
+            proptest::prop_assert_eq!(reparsed, Ok(Ok(command)), "printed as {:?}", printed);
+        }
+    }
+}
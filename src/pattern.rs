@@ -0,0 +1,201 @@
+/// One element of a shell pattern.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Item {
+    Literal(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`
+    Bracket(Bracket),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Bracket {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+    classes: Vec<String>,
+}
+
+impl Bracket {
+    fn matches(&self, c: char) -> bool {
+        let found = self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+            || self.classes.iter().any(|class| in_class(class, c));
+        found != self.negated
+    }
+}
+
+fn in_class(class: &str, c: char) -> bool {
+    match class {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => !c.is_whitespace() && !c.is_control(),
+        "lower" => c.is_lowercase(),
+        "print" => !c.is_control(),
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+/// Reads a bracket expression after its opening `[`. Returns `None` if it is not closed, in which
+/// case the `[` matches itself.
+fn read_bracket(chars: &[char]) -> Option<(Bracket, usize)> {
+    let mut bracket = Bracket {
+        negated: false,
+        ranges: vec![],
+        classes: vec![],
+    };
+    let mut i = 0;
+    if let Some('!') | Some('^') = chars.first() {
+        bracket.negated = true;
+        i += 1;
+    }
+    let start = i;
+    loop {
+        let c = *chars.get(i)?;
+        if c == ']' && i > start {
+            return Some((bracket, i + 1));
+        }
+        if c == '[' && chars.get(i + 1) == Some(&':') {
+            let rest: String = chars[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                let class = &rest[..end];
+                i += 2 + class.chars().count() + 2;
+                bracket.classes.push(class.to_string());
+                continue;
+            }
+        }
+        let (lo, next) = match c {
+            '\\' => (*chars.get(i + 1)?, i + 2),
+            _ => (c, i + 1),
+        };
+        i = next;
+        match (chars.get(i), chars.get(i + 1)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                let (hi, next) = match hi {
+                    '\\' => (*chars.get(i + 2)?, i + 3),
+                    _ => (hi, i + 2),
+                };
+                bracket.ranges.push((lo, hi));
+                i = next;
+            }
+            _ => bracket.ranges.push((lo, lo)),
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Vec<Item> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut items = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let item = match chars[i] {
+            '*' => Item::Star,
+            '?' => Item::Any,
+            '[' => match read_bracket(&chars[i + 1..]) {
+                Some((bracket, len)) => {
+                    i += len;
+                    Item::Bracket(bracket)
+                }
+                None => Item::Literal('['),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                Item::Literal(chars[i])
+            }
+            c => Item::Literal(c),
+        };
+        items.push(item);
+        i += 1;
+    }
+    items
+}
+
+fn matches_one(item: &Item, c: char) -> bool {
+    match item {
+        Item::Literal(l) => *l == c,
+        Item::Any => true,
+        Item::Bracket(bracket) => bracket.matches(c),
+        Item::Star => false,
+    }
+}
+
+/// Whether `text` matches the shell pattern `pattern` in full. `*` matches any string, `?` any
+/// character and `[...]` a set of characters; a backslash makes the next character literal.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let items = compile(pattern);
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*` if the rest of the pattern fails to match.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match items.get(p) {
+            Some(Item::Star) => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(item) if matches_one(item, text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    items[p..].iter().all(|item| *item == Item::Star)
+}
+
+/// Escapes the characters that are special in patterns, so that `text` matches only itself.
+#[cfg(test)]
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "*?[]\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[test]
+fn matches_wildcards() {
+    assert!(matches("*.rs", "main.rs"));
+    assert!(matches("a*b*c", "aXXbYYc"));
+    assert!(matches("?at", "cat"));
+    assert!(!matches("?at", "at"));
+    assert!(!matches("*.rs", "main.rs.bak"));
+    assert!(matches("", ""));
+    assert!(matches("**", ""));
+}
+
+#[test]
+fn matches_brackets() {
+    assert!(matches("[abc]x", "bx"));
+    assert!(matches("[!abc]x", "dx"));
+    assert!(!matches("[^abc]x", "ax"));
+    assert!(matches("[a-z][0-9]", "q7"));
+    assert!(matches("[]]", "]"));
+    assert!(matches("[[:digit:]]*", "4th"));
+    assert!(matches("[x", "[x"));
+}
+
+#[test]
+fn escaped_characters_are_literal() {
+    assert!(matches("\\*", "*"));
+    assert!(!matches("\\*", "x"));
+    assert!(matches(&escape("a*[b]?"), "a*[b]?"));
+    assert!(!matches(&escape("a*"), "abc"));
+}
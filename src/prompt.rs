@@ -0,0 +1,261 @@
+use std::ffi::CString;
+
+use nix::libc;
+use nix::unistd::{gethostname, getuid, User};
+
+use crate::editor::{END_IGNORE, START_IGNORE};
+use crate::executor::{run_string, StreamSet};
+use crate::expand::expand_string;
+use crate::lexer::{read_quoted, read_substitution};
+use crate::state::State;
+
+/// Sets the prompts that are unset to bash's defaults. Only interactive shells get a `PS1`.
+pub fn set_defaults(state: &mut State, interactive: bool) {
+    let mut defaults = vec![("PS2", "> "), ("PS4", "+ ")];
+    if interactive {
+        defaults.push(("PS1", "\\s-\\v\\$ "));
+    }
+    for (name, value) in defaults {
+        if state.get_var(name).is_none() {
+            state.set_var(name, value);
+        }
+    }
+}
+
+/// Runs `PROMPT_COMMAND`, if set, before a prompt. `$?` keeps the status of the last command
+/// entered, so the prompt can still show it.
+pub fn run_prompt_command(state: &mut State) {
+    let command = match state.get_var("PROMPT_COMMAND") {
+        Some(command) if !command.is_empty() => command.to_string(),
+        _ => return,
+    };
+    let status = state.last_status;
+    run_string(state, &command, StreamSet::std());
+    state.last_status = status;
+}
+
+/// The prompt in the variable `name`, expanded, or an empty string if it is unset.
+pub fn render(state: &mut State, name: &str) -> String {
+    let template = state.get_var(name).unwrap_or_default().to_string();
+    expand(state, &template)
+}
+
+/// Expands a prompt string: backslash escapes first, then parameters, command substitutions and
+/// arithmetic, as inside double quotes. `\[` and `\]` become the editor's ignore markers.
+pub fn expand(state: &mut State, template: &str) -> String {
+    let decoded = decode_escapes(state, template);
+    let status = state.last_status;
+    let expanded = expand_string(state, &format!("\"{}\"", decoded));
+    state.last_status = status;
+    expanded.unwrap_or_else(|_| template.to_string())
+}
+
+/// Escapes text so that it comes out of double-quoted expansion unchanged.
+fn quote(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if "$`\"\\".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Replaces the backslash escapes in a prompt, leaving the rest to be expanded inside double
+/// quotes.
+fn decode_escapes(state: &State, template: &str) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        let substitution = match c {
+            '$' if rest[1..].starts_with(['(', '{'].as_ref()) => read_substitution(rest).ok(),
+            '`' => read_quoted(rest, '`').ok(),
+            _ => None,
+        };
+        if let Some((after, substitution)) = substitution {
+            // Substitutions are parsed on their own, so their quotes are left alone.
+            out.push_str(substitution);
+            rest = after;
+            continue;
+        }
+        rest = &rest[c.len_utf8()..];
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => {
+                let (text, after) = decode_escape(state, rest);
+                out.push_str(&quote(&text));
+                rest = after;
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Decodes the escape at the start of `rest`, which follows a backslash. Returns its text and
+/// what comes after it.
+fn decode_escape<'a>(state: &State, rest: &'a str) -> (String, &'a str) {
+    let c = match rest.chars().next() {
+        Some(c) => c,
+        None => return ("\\".to_string(), rest),
+    };
+    let after = &rest[c.len_utf8()..];
+    let text = match c {
+        'u' => user_name(state),
+        'h' => host_name()
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        'H' => host_name(),
+        'w' => working_dir(state, false),
+        'W' => working_dir(state, true),
+        '$' if getuid().is_root() => "#".to_string(),
+        '$' => "$".to_string(),
+        't' => format_time("%H:%M:%S"),
+        'T' => format_time("%I:%M:%S"),
+        '@' => format_time("%I:%M %p"),
+        'A' => format_time("%H:%M"),
+        'd' => format_time("%a %b %d"),
+        'D' if after.starts_with('{') => {
+            let end = after.find('}').unwrap_or(after.len());
+            let format = match &after[1..end] {
+                "" => "%X",
+                format => format,
+            };
+            return (
+                format_time(format),
+                after.get(end + 1..).unwrap_or_default(),
+            );
+        }
+        'j' => state
+            .jobs
+            .iter()
+            .filter(|job| job.status().is_none())
+            .count()
+            .to_string(),
+        '!' => {
+            let history = &state.history;
+            (history.first_number() + history.entries().len()).to_string()
+        }
+        's' => state
+            .name
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        'v' | 'V' => env!("CARGO_PKG_VERSION").to_string(),
+        'n' => "\n".to_string(),
+        'r' => "\r".to_string(),
+        'a' => "\x07".to_string(),
+        'e' => "\x1b".to_string(),
+        '[' => START_IGNORE.to_string(),
+        ']' => END_IGNORE.to_string(),
+        '\\' => "\\".to_string(),
+        '0'..='7' => {
+            let digits = rest.chars().take(3).take_while(|c| c.is_digit(8)).count();
+            let code = u32::from_str_radix(&rest[..digits], 8).unwrap_or_default();
+            let text = std::char::from_u32(code).map(String::from);
+            return (text.unwrap_or_default(), &rest[digits..]);
+        }
+        _ => format!("\\{}", c),
+    };
+    (text, after)
+}
+
+fn user_name(state: &State) -> String {
+    match User::from_uid(getuid()) {
+        Ok(Some(user)) => user.name,
+        _ => state.get_var("USER").unwrap_or_default().to_string(),
+    }
+}
+
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    gethostname(&mut buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// `$PWD` with `$HOME` abbreviated to `~`, or just its last component if `base` is set.
+fn working_dir(state: &State, base: bool) -> String {
+    let pwd = state.get_var("PWD").unwrap_or_default();
+    let home = state.get_var("HOME").unwrap_or_default();
+    let in_home = match pwd.strip_prefix(home) {
+        Some(rest) if !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) => Some(rest),
+        _ => None,
+    };
+    match (in_home, base) {
+        (Some(""), _) => "~".to_string(),
+        (_, true) if pwd != "/" => pwd.rsplit('/').next().unwrap_or_default().to_string(),
+        (Some(rest), false) => format!("~{}", rest),
+        _ => pwd.to_string(),
+    }
+}
+
+extern "C" {
+    // Not bound by the libc crate version in use.
+    fn strftime(
+        s: *mut libc::c_char,
+        max: libc::size_t,
+        format: *const libc::c_char,
+        tm: *const libc::tm,
+    ) -> libc::size_t;
+}
+
+/// Formats the current local time with `strftime`.
+fn format_time(format: &str) -> String {
+    let format = match CString::new(format) {
+        Ok(format) => format,
+        Err(_) => return String::new(),
+    };
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[test]
+fn expands_escapes_and_variables() {
+    let mut state = State::new();
+    state.set_var("HOME", "/home/me");
+    state.set_var("PWD", "/home/me/src/traash");
+    state.set_var("NAME", "$x");
+    assert_eq!(expand(&mut state, "\\w \\W"), "~/src/traash traash");
+    assert_eq!(expand(&mut state, "[$NAME] \"q\" \\\\"), "[$x] \"q\" \\");
+    assert_eq!(
+        expand(&mut state, "\\[\\e[32m\\]ok\\[\\e[0m\\]\\101"),
+        "\x01\x1b[32m\x02ok\x01\x1b[0m\x02A"
+    );
+    assert_eq!(expand(&mut state, "$(echo \"sub\")\\D{%%}>"), "sub%>");
+    state.set_var("PWD", "/home/me");
+    assert_eq!(expand(&mut state, "\\w \\W"), "~ ~");
+    state.set_var("PWD", "/");
+    assert_eq!(expand(&mut state, "\\w \\W"), "/ /");
+
+    let time = expand(&mut state, "\\t");
+    assert_eq!(time.len(), 8);
+    assert_eq!(&time[2..3], ":");
+}
+
+#[test]
+fn keeps_the_last_status_for_the_prompt() {
+    let mut state = State::new();
+    state.last_status = 3;
+    state.set_var("PROMPT_COMMAND", "false; seen=$?");
+    run_prompt_command(&mut state);
+    assert_eq!(state.get_var("seen"), Some("1"));
+    assert_eq!(state.last_status, 3);
+    let prompt = "$? $([ $? = 0 ] && echo ok || echo fail)";
+    assert_eq!(expand(&mut state, prompt), "3 fail");
+}
@@ -0,0 +1,120 @@
+use std::io;
+
+use crate::ast::Command;
+use crate::builtins::Builtin;
+use crate::capture::{capture, CaptureOptions, Output};
+use crate::executor::{execute, run_string, source_file, StreamSet};
+use crate::state::State;
+
+/// A shell that runs commands in-process, for programs that embed traash.
+///
+/// Commands share one set of variables, functions and aliases, as they would at a prompt. The
+/// `exit` builtin still exits the whole process.
+#[derive(Debug)]
+pub struct Shell {
+    state: State,
+}
+
+impl Shell {
+    /// A shell whose variables start as the environment of this process.
+    pub fn new() -> Shell {
+        Shell {
+            state: State::from_env(),
+        }
+    }
+
+    /// Runs `input` as shell code on the standard streams and returns its exit status. Syntax
+    /// errors are reported on stderr with status 2.
+    pub fn run_str(&mut self, input: &str) -> i32 {
+        self.run_str_with(input, StreamSet::std())
+    }
+
+    /// Runs `input` as shell code on `streams`.
+    pub fn run_str_with(&mut self, input: &str, streams: StreamSet) -> i32 {
+        run_string(&mut self.state, input, streams)
+    }
+
+    /// Runs the commands in a file on the standard streams, as `.` does.
+    pub fn run_file(&mut self, path: &str) -> io::Result<i32> {
+        source_file(&mut self.state, path, StreamSet::std())
+    }
+
+    /// Runs `input` in a subshell and collects what it writes to stdout and stderr, with stdin
+    /// empty. Changes the subshell makes to variables are lost.
+    pub fn capture(&mut self, input: &str) -> io::Result<Output> {
+        self.capture_with(input, &CaptureOptions::default())
+    }
+
+    /// Runs `input` in a subshell like `capture`, with input, a timeout or extra variables.
+    pub fn capture_with(&mut self, input: &str, options: &CaptureOptions) -> io::Result<Output> {
+        capture(&mut self.state, input, options).map_err(|e| match e.as_errno() {
+            Some(errno) => errno.into(),
+            None => io::Error::other(e),
+        })
+    }
+
+    /// Runs a command that has already been parsed.
+    pub fn execute(&mut self, command: &Command, streams: StreamSet) -> i32 {
+        execute(&mut self.state, command, streams)
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.state.get_var(name)
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.state.set_var(name, value);
+    }
+
+    /// Adds a builtin, replacing any builtin of the same name. Functions still come first.
+    pub fn register_builtin(&mut self, name: &str, builtin: Builtin) {
+        self.state.builtins.insert(name.to_string(), builtin);
+    }
+
+    /// The status of the last command run.
+    pub fn last_status(&self) -> i32 {
+        self.state.last_status
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell::new()
+    }
+}
+
+#[test]
+fn runs_code_with_registered_builtins() {
+    use nix::fcntl::OFlag;
+    use nix::unistd::{close, pipe2, read};
+
+    fn greet(state: &mut State, args: &[String], streams: &StreamSet) -> i32 {
+        let name = state.get_var("NAME").unwrap_or_default();
+        streams.write_out(&format!("{} {}\n", args[1], name));
+        7
+    }
+
+    let mut shell = Shell::new();
+    shell.register_builtin("greet", greet);
+    shell.set_var("NAME", "world");
+    let (r, w) = pipe2(OFlag::O_CLOEXEC).unwrap();
+    let streams = StreamSet::new(None, Some(w), None);
+
+    let status = shell.run_str_with("greet hello; seen=$?; greet bye", streams);
+    close(w).unwrap();
+    let mut buf = [0u8; 64];
+    let n = read(r, &mut buf).unwrap();
+    close(r).unwrap();
+
+    assert_eq!(status, 7);
+    assert_eq!(shell.get_var("seen"), Some("7"));
+    assert_eq!(&buf[..n], b"hello world\nbye world\n");
+}
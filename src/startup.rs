@@ -0,0 +1,223 @@
+use std::path::Path;
+
+use crate::executor::{source_file, StreamSet};
+use crate::expand::expand_string;
+use crate::state::State;
+
+const SYSTEM_PROFILE: &str = "/etc/traash/profile";
+
+/// What `--dump-tokens` or `--dump-ast` prints instead of running the script.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Dump {
+    Tokens,
+    /// `--dump-ast`: the syntax tree, indented.
+    Tree,
+    /// `--dump-ast=json`
+    Json,
+}
+
+/// How the shell was started, from its command line.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Options {
+    /// `-l` or `--login`, or an `argv[0]` starting with `-`.
+    pub login: bool,
+    /// `-i`: interactive even if standard input is not a terminal.
+    pub interactive: bool,
+    /// `--posix`, or `POSIXLY_CORRECT` in the environment: read `$ENV` instead of `~/.traashrc`.
+    pub posix: bool,
+    pub norc: bool,
+    pub noprofile: bool,
+    /// `--rcfile FILE`: read FILE instead of `~/.traashrc`.
+    pub rcfile: Option<String>,
+    /// `--fmt`: print the script, or standard input, reformatted instead of running it.
+    pub format: bool,
+    /// `-n`: check the syntax of the script, or standard input, instead of running it.
+    pub check: bool,
+    pub dump: Option<Dump>,
+    /// `-x`: start with `set -x`.
+    pub xtrace: bool,
+    /// `-v`: start with `set -v`.
+    pub verbose: bool,
+    /// `-c COMMAND`: run COMMAND instead of reading commands.
+    pub command: Option<String>,
+    /// The script to run, unless commands come from `-c` or standard input.
+    pub script: Option<String>,
+    /// `$0`.
+    pub name: String,
+    /// The positional parameters.
+    pub args: Vec<String>,
+}
+
+impl Options {
+    /// Parses the shell's arguments, including `argv[0]`. Options end at the first argument that
+    /// isn't one, which is the script, or `COMMAND`'s `$0` with `-c`.
+    pub fn parse(argv: &[String]) -> Result<Options, String> {
+        let argv0 = argv.first().map_or("traash", |a| a.as_str());
+        let mut options = Options {
+            login: argv0.starts_with('-'),
+            name: argv0.trim_start_matches('-').to_string(),
+            ..Options::default()
+        };
+        let mut command = false;
+        let mut args = argv.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => break,
+                "--login" => options.login = true,
+                "--posix" => options.posix = true,
+                "--norc" => options.norc = true,
+                "--noprofile" => options.noprofile = true,
+                "--fmt" => options.format = true,
+                "--dump-tokens" => options.dump = Some(Dump::Tokens),
+                "--dump-ast" => options.dump = Some(Dump::Tree),
+                "--dump-ast=json" => options.dump = Some(Dump::Json),
+                "--rcfile" => match args.next() {
+                    Some(file) => options.rcfile = Some(file.clone()),
+                    None => return Err("--rcfile: option requires an argument".to_string()),
+                },
+                _ if arg.starts_with("--") => return Err(format!("{}: invalid option", arg)),
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    for flag in arg.chars().skip(1) {
+                        match flag {
+                            'c' => command = true,
+                            'i' => options.interactive = true,
+                            'l' => options.login = true,
+                            'n' => options.check = true,
+                            'v' => options.verbose = true,
+                            'x' => options.xtrace = true,
+                            _ => return Err(format!("-{}: invalid option", flag)),
+                        }
+                    }
+                }
+                _ => {
+                    options.args.push(arg.clone());
+                    break;
+                }
+            }
+        }
+        options.args.extend(args.cloned());
+        let mut operands = std::mem::take(&mut options.args).into_iter();
+        if command {
+            match operands.next() {
+                Some(text) => options.command = Some(text),
+                None => return Err("-c: option requires an argument".to_string()),
+            }
+            if let Some(name) = operands.next() {
+                options.name = name;
+            }
+        } else if let Some(script) = operands.next() {
+            options.name = script.clone();
+            options.script = Some(script);
+        }
+        options.args = operands.collect();
+        Ok(options)
+    }
+}
+
+/// Sources a startup file if it exists, reporting other errors.
+fn source_if_exists(state: &mut State, path: &str) {
+    if !Path::new(path).exists() {
+        return;
+    }
+    let streams = StreamSet::std();
+    if let Err(e) = source_file(state, path, streams) {
+        streams.write_err(&format!("traash: {}: {}\n", path, e));
+    }
+}
+
+/// Sources the startup files: the profiles for a login shell, then `~/.traashrc`, or `$ENV` in
+/// POSIX mode, for an interactive shell that is not a login shell.
+pub fn source_startup_files(state: &mut State, options: &Options, interactive: bool) {
+    let home = state.get_var("HOME").map(|h| h.to_string());
+    if options.login && !options.noprofile {
+        source_if_exists(state, SYSTEM_PROFILE);
+        if let Some(home) = &home {
+            source_if_exists(state, &format!("{}/.traash_profile", home));
+        }
+    }
+    if !interactive || options.login || options.norc {
+        return;
+    }
+    let rcfile = match (&options.rcfile, options.posix) {
+        (Some(file), _) => Some(file.clone()),
+        (None, true) => match state.get_var("ENV").map(|e| e.to_string()) {
+            Some(env) => expand_string(state, &env).ok(),
+            None => None,
+        },
+        (None, false) => home.map(|home| format!("{}/.traashrc", home)),
+    };
+    if let Some(rcfile) = rcfile.filter(|f| !f.is_empty()) {
+        source_if_exists(state, &rcfile);
+    }
+}
+
+#[cfg(test)]
+fn parse(args: &[&str]) -> Result<Options, String> {
+    let argv: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    Options::parse(&argv)
+}
+
+#[test]
+fn parses_shell_arguments() {
+    let options = parse(&["-traash"]).unwrap();
+    assert!(options.login);
+    assert_eq!(options.name, "traash");
+
+    let options = parse(&["traash", "-ilx", "--norc", "script.sh", "-x", "y"]).unwrap();
+    assert!(options.interactive && options.login && options.norc && options.xtrace);
+    assert_eq!(options.script, Some("script.sh".to_string()));
+    assert_eq!(options.name, "script.sh");
+    assert_eq!(options.args, vec!["-x", "y"]);
+
+    let options = parse(&["traash", "-c", "echo $0 $1", "name", "one"]).unwrap();
+    assert_eq!(options.command, Some("echo $0 $1".to_string()));
+    assert_eq!(
+        (options.name.as_str(), options.args),
+        ("name", vec!["one".to_string()])
+    );
+
+    let options = parse(&["traash", "--rcfile", "rc", "--", "-script"]).unwrap();
+    assert_eq!(options.rcfile, Some("rc".to_string()));
+    assert_eq!(options.script, Some("-script".to_string()));
+
+    let options = parse(&["traash", "--fmt", "script.sh"]).unwrap();
+    assert!(options.format);
+    assert_eq!(options.script, Some("script.sh".to_string()));
+
+    let options = parse(&["traash", "-n", "--dump-ast=json", "script.sh"]).unwrap();
+    assert!(options.check);
+    assert_eq!(options.dump, Some(Dump::Json));
+
+    assert!(parse(&["traash", "-q"]).is_err());
+    assert!(parse(&["traash", "--rcfile"]).is_err());
+    assert!(parse(&["traash", "-c"]).is_err());
+}
+
+#[test]
+fn sources_startup_files_by_kind_of_shell() {
+    let home = std::env::temp_dir().join(format!("traash-home-{}", nix::unistd::getpid()));
+    std::fs::create_dir_all(&home).unwrap();
+    let write = |name: &str, text: &str| std::fs::write(home.join(name), text).unwrap();
+    write(".traash_profile", "read=\"$read profile\"");
+    write(".traashrc", "read=\"$read rc\"");
+    write("other", "read=\"$read other\"");
+    write("env", "read=\"$read env\"");
+    let sourced = |argv: &[&str], interactive: bool| {
+        let mut state = State::new();
+        state.set_var("HOME", &home.to_string_lossy());
+        state.set_var("ENV", "$HOME/env");
+        let options = parse(argv).unwrap();
+        source_startup_files(&mut state, &options, interactive);
+        state.get_var("read").unwrap_or_default().to_string()
+    };
+
+    assert_eq!(sourced(&["-traash"], true), " profile");
+    assert_eq!(sourced(&["traash", "--noprofile", "-l"], true), "");
+    assert_eq!(sourced(&["traash"], true), " rc");
+    assert_eq!(sourced(&["traash"], false), "");
+    assert_eq!(sourced(&["traash", "--norc"], true), "");
+    let rcfile = home.join("other").to_string_lossy().into_owned();
+    assert_eq!(sourced(&["traash", "--rcfile", &rcfile], true), " other");
+    assert_eq!(sourced(&["traash", "--posix"], true), " env");
+    std::fs::remove_dir_all(&home).unwrap();
+}
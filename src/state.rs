@@ -0,0 +1,337 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+
+use nix::unistd::Pid;
+
+use crate::ast::Command;
+use crate::builtins::Builtin;
+use crate::completion::CompSpec;
+use crate::executor::Process;
+use crate::history::History;
+use crate::sys::SysRef;
+
+/// The nesting limit used when `FUNCNEST` is unset, so runaway recursion fails with a shell error
+/// instead of overflowing the interpreter's stack.
+const DEFAULT_FUNCNEST: usize = 1000;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Variable {
+    pub value: String,
+    pub exported: bool,
+}
+
+/// A variable as `save_var` found it, with its array elements if it was an array.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SavedVar {
+    var: Option<Variable>,
+    elements: Option<Vec<String>>,
+}
+
+/// Variables shadowed by `local` in one function call, restored when the call returns.
+#[derive(Debug, Clone, Default)]
+struct Frame {
+    saved: HashMap<String, SavedVar>,
+}
+
+#[derive(Debug)]
+pub struct State {
+    vars: HashMap<String, Variable>,
+    /// The elements of indexed array variables. Element 0 is also stored in `vars`, so an array
+    /// reads as its first element when used as a plain variable.
+    arrays: HashMap<String, Vec<String>>,
+    frames: Vec<Frame>,
+    pub(crate) functions: HashMap<String, Command>,
+    /// Builtins added by the program embedding the shell, which take precedence over the
+    /// shell's own.
+    pub(crate) builtins: HashMap<String, Builtin>,
+    pub(crate) aliases: HashMap<String, String>,
+    pub(crate) name: String,
+    pub(crate) positional: Vec<String>,
+    pub(crate) last_status: i32,
+    pub(crate) last_background: Option<Pid>,
+    /// Background processes that were still running when last checked. Once reaped, their
+    /// statuses move to `finished_jobs`.
+    pub(crate) jobs: Vec<Process>,
+    /// The pids and statuses of background jobs that finished before `wait` asked for them,
+    /// oldest first.
+    pub(crate) finished_jobs: VecDeque<(Pid, i32)>,
+    pub(crate) returning: bool,
+    /// How many files `.` is running, inside which `return` is allowed.
+    pub(crate) source_depth: usize,
+    /// How many subshells deep the shell is, 0 in the shell itself.
+    pub(crate) subshell_depth: usize,
+    /// `set -x`: trace simple commands after expanding them.
+    pub(crate) xtrace: bool,
+    /// `set -v`: echo input as it is read.
+    pub(crate) verbose: bool,
+    /// The status of the last command substitution in the current simple command, which becomes
+    /// the status of a command that only assigns variables.
+    pub(crate) substitution_status: Option<i32>,
+    pub(crate) history: History,
+    /// Completion specs set by `complete`, by command name.
+    pub(crate) completions: HashMap<String, CompSpec>,
+    /// The system calls the executor makes.
+    pub(crate) sys: SysRef,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            vars: HashMap::new(),
+            arrays: HashMap::new(),
+            frames: vec![],
+            functions: HashMap::new(),
+            builtins: HashMap::new(),
+            aliases: HashMap::new(),
+            name: "traash".to_string(),
+            positional: vec![],
+            last_status: 0,
+            last_background: None,
+            jobs: vec![],
+            finished_jobs: VecDeque::new(),
+            returning: false,
+            source_depth: 0,
+            subshell_depth: 0,
+            xtrace: false,
+            verbose: false,
+            substitution_status: None,
+            history: History::new(),
+            completions: HashMap::new(),
+            sys: SysRef::default(),
+        }
+    }
+
+    /// Creates a state whose variables are the exported environment of this process.
+    pub fn from_env() -> State {
+        let mut state = State::new();
+        for (name, value) in env::vars() {
+            state.vars.insert(
+                name,
+                Variable {
+                    value,
+                    exported: true,
+                },
+            );
+        }
+        state
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|v| v.value.as_str())
+    }
+
+    pub fn var_names(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(|k| k.as_str())
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        if let Some(first) = self.arrays.get_mut(name).and_then(|a| a.first_mut()) {
+            *first = value.to_string();
+        }
+        match self.vars.get_mut(name) {
+            Some(var) => var.value = value.to_string(),
+            None => {
+                self.vars.insert(
+                    name.to_string(),
+                    Variable {
+                        value: value.to_string(),
+                        exported: false,
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+        self.arrays.remove(name);
+    }
+
+    /// Makes `name` an indexed array holding `elements`.
+    pub fn set_array(&mut self, name: &str, elements: Vec<String>) {
+        match elements.first() {
+            Some(first) => self.set_var(name, first),
+            None => {
+                self.vars.remove(name);
+            }
+        }
+        self.arrays.insert(name.to_string(), elements);
+    }
+
+    /// The elements of a variable. A plain variable has one element and an unset one none.
+    pub fn elements(&self, name: &str) -> Vec<String> {
+        match self.arrays.get(name) {
+            Some(elements) => elements.clone(),
+            None => self
+                .get_var(name)
+                .map(|v| v.to_string())
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Marks a variable as exported, creating it empty if it does not exist yet.
+    pub fn export_var(&mut self, name: &str) {
+        self.vars
+            .entry(name.to_string())
+            .or_insert_with(|| Variable {
+                value: String::new(),
+                exported: true,
+            })
+            .exported = true;
+    }
+
+    /// The exported variables as `NAME=value` strings, for passing to `execve`.
+    pub fn environment(&self) -> Vec<String> {
+        self.vars
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .map(|(name, var)| format!("{}={}", name, var.value))
+            .collect()
+    }
+
+    /// The current function call depth, 0 at the top level.
+    pub fn function_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The maximum function call depth, taken from `FUNCNEST` when it is a positive integer.
+    pub fn function_limit(&self) -> usize {
+        match self
+            .get_var("FUNCNEST")
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(limit) if limit > 0 => limit,
+            _ => DEFAULT_FUNCNEST,
+        }
+    }
+
+    /// A copy of the variable `name` as it is now, to put back later with `restore_var`.
+    pub fn save_var(&self, name: &str) -> SavedVar {
+        SavedVar {
+            var: self.vars.get(name).cloned(),
+            elements: self.arrays.get(name).cloned(),
+        }
+    }
+
+    /// Puts back a variable saved with `save_var`, unsetting it if it didn't exist then.
+    pub fn restore_var(&mut self, name: &str, saved: SavedVar) {
+        match saved.var {
+            Some(var) => self.vars.insert(name.to_string(), var),
+            None => self.vars.remove(name),
+        };
+        match saved.elements {
+            Some(elements) => self.arrays.insert(name.to_string(), elements),
+            None => self.arrays.remove(name),
+        };
+    }
+
+    pub fn push_frame(&mut self) {
+        self.frames.push(Frame::default());
+    }
+
+    /// Leaves the innermost function call, restoring every variable it declared `local`.
+    pub fn pop_frame(&mut self) {
+        if let Some(frame) = self.frames.pop() {
+            for (name, saved) in frame.saved {
+                self.restore_var(&name, saved);
+            }
+        }
+    }
+
+    /// Makes `name` local to the innermost function call. Returns false outside of a function.
+    ///
+    /// Scoping is dynamic: the previous value is saved and the variable table is shared, so
+    /// functions called from here see the local value.
+    pub fn declare_local(&mut self, name: &str, value: Option<&str>) -> bool {
+        let saved = self.save_var(name);
+        let frame = match self.frames.last_mut() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        frame.saved.entry(name.to_string()).or_insert(saved);
+        match value {
+            Some(value) => self.set_var(name, value),
+            None => {
+                if !self.vars.contains_key(name) {
+                    self.set_var(name, "");
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::new()
+    }
+}
+
+#[test]
+fn locals_are_restored_when_frame_pops() {
+    let mut state = State::new();
+    state.set_var("x", "global");
+
+    state.push_frame();
+    assert!(state.declare_local("x", Some("outer")));
+    assert!(state.declare_local("y", Some("new")));
+    state.push_frame();
+    state.declare_local("x", Some("inner"));
+    assert_eq!(state.get_var("x"), Some("inner"));
+    state.pop_frame();
+    assert_eq!(state.get_var("x"), Some("outer"));
+    state.pop_frame();
+
+    assert_eq!(state.get_var("x"), Some("global"));
+    assert_eq!(state.get_var("y"), None);
+}
+
+#[test]
+fn local_arrays_are_restored_when_frame_pops() {
+    let mut state = State::new();
+    state.set_array("a", vec!["1".to_string(), "2".to_string(), "3".to_string()]);
+
+    state.push_frame();
+    state.declare_local("a", None);
+    state.set_array("a", vec!["x".to_string(), "y".to_string()]);
+    state.declare_local("b", None);
+    state.set_array("b", vec!["p".to_string()]);
+    state.pop_frame();
+
+    assert_eq!(state.elements("a"), vec!["1", "2", "3"]);
+    assert_eq!(state.get_var("a"), Some("1"));
+    assert_eq!(state.elements("b"), Vec::<String>::new());
+}
+
+#[test]
+fn local_outside_function_fails() {
+    let mut state = State::new();
+
+    assert!(!state.declare_local("x", Some("1")));
+    assert_eq!(state.get_var("x"), None);
+}
+
+#[test]
+fn arrays_read_as_their_first_element() {
+    let mut state = State::new();
+
+    state.set_array("a", vec!["x".to_string(), "y".to_string()]);
+    assert_eq!(state.get_var("a"), Some("x"));
+    state.set_var("a", "z");
+    assert_eq!(state.elements("a"), vec!["z", "y"]);
+    state.unset_var("a");
+    assert!(state.elements("a").is_empty());
+}
+
+#[test]
+fn funcnest_configures_limit() {
+    let mut state = State::new();
+    assert_eq!(state.function_limit(), DEFAULT_FUNCNEST);
+
+    state.set_var("FUNCNEST", "7");
+    assert_eq!(state.function_limit(), 7);
+
+    state.set_var("FUNCNEST", "0");
+    assert_eq!(state.function_limit(), DEFAULT_FUNCNEST);
+}
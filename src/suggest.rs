@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::completion::CommandCache;
+use crate::expand::split_assignment;
+use crate::highlight::command_exists;
+use crate::lexer::{at_command_start, lex};
+use crate::state::State;
+use crate::tokens::Token;
+
+/// Suggests how to finish the line being typed from the history, in the style of fish.
+#[derive(Debug, Default)]
+pub struct Suggester {
+    /// The directories each line was entered in during this session.
+    places: HashMap<String, HashSet<String>>,
+}
+
+impl Suggester {
+    pub fn new() -> Suggester {
+        Suggester::default()
+    }
+
+    /// Remembers that `line` was entered in the directory `dir`.
+    pub fn record(&mut self, line: &str, dir: &str) {
+        self.places
+            .entry(line.to_string())
+            .or_default()
+            .insert(dir.to_string());
+    }
+
+    /// The rest of the most recent history entry that starts with `line`, preferring entries
+    /// entered in the current directory and skipping those that run commands which no longer
+    /// exist.
+    pub fn suggest(
+        &self,
+        state: &State,
+        cache: &mut CommandCache,
+        history: &[String],
+        line: &str,
+    ) -> Option<String> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let pwd = state.get_var("PWD").unwrap_or_default();
+        let mut seen = HashSet::new();
+        let mut fallback = None;
+        for entry in history.iter().rev() {
+            if entry.len() <= line.len() || !entry.starts_with(line) || entry.contains('\n') {
+                continue;
+            }
+            if !seen.insert(entry) || !commands_exist(state, cache, entry) {
+                continue;
+            }
+            let here = self
+                .places
+                .get(entry)
+                .is_some_and(|dirs| dirs.contains(pwd));
+            if here {
+                return Some(entry[line.len()..].to_string());
+            }
+            fallback.get_or_insert(entry);
+        }
+        fallback.map(|entry| entry[line.len()..].to_string())
+    }
+}
+
+/// Whether every command that `line` runs can still be found. Commands named by expansions are
+/// given the benefit of the doubt.
+fn commands_exist(state: &State, cache: &mut CommandCache, line: &str) -> bool {
+    let tokens = match lex(line) {
+        Ok(tokens) => tokens,
+        Err(_) => return false,
+    };
+    let mut assignments_only = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let word = match token {
+            Token::Text(word) if assignments_only || at_command_start(&tokens[..i]) => word,
+            _ => {
+                assignments_only = false;
+                continue;
+            }
+        };
+        assignments_only = split_assignment(word).is_some();
+        if !assignments_only && command_exists(state, cache, word) == Some(false) {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+fn history(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|l| l.to_string()).collect()
+}
+
+#[test]
+fn suggests_the_latest_matching_entry() {
+    let mut state = State::new();
+    state.set_var("PATH", "");
+    state.set_var("PWD", "/elsewhere");
+    let suggester = Suggester::new();
+    let cache = &mut CommandCache::new();
+    let history = history(&["echo one", "echo two", "echo", "cd /tmp"]);
+    assert_eq!(
+        suggester.suggest(&state, cache, &history, "ec"),
+        Some("ho".to_string())
+    );
+    assert_eq!(
+        suggester.suggest(&state, cache, &history, "echo"),
+        Some(" two".to_string())
+    );
+    assert_eq!(suggester.suggest(&state, cache, &history, "echo two"), None);
+    assert_eq!(suggester.suggest(&state, cache, &history, " "), None);
+}
+
+#[test]
+fn prefers_the_current_directory_and_skips_missing_commands() {
+    let mut state = State::new();
+    state.set_var("PATH", "");
+    let mut suggester = Suggester::new();
+    suggester.record("echo here", "/project");
+    let cache = &mut CommandCache::new();
+    let history = history(&["echo here", "echo there", "echo x | gone-command"]);
+    state.set_var("PWD", "/project");
+    assert_eq!(
+        suggester.suggest(&state, cache, &history, "echo "),
+        Some("here".to_string())
+    );
+    state.set_var("PWD", "/elsewhere");
+    assert_eq!(
+        suggester.suggest(&state, cache, &history, "echo "),
+        Some("there".to_string())
+    );
+}
@@ -0,0 +1,381 @@
+use std::ffi::{CString, NulError};
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::rc::Rc;
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::{ForkResult, Pid};
+
+const DEV_NULL: &[u8] = b"/dev/null\0";
+
+/// Signals that the shell ignores but commands should get with their default handling. Rust
+/// ignores `SIGPIPE` in every program, and ignored signals stay ignored across exec.
+const DEFAULT_SIGNALS: &[libc::c_int] = &[libc::SIGPIPE];
+
+/// A command ready to be started, with everything the child needs allocated beforehand.
+#[derive(Debug)]
+pub struct Spawn {
+    pub path: CString,
+    pub argv: Vec<CString>,
+    pub envp: Vec<CString>,
+    /// The descriptors to give the child as stdin, stdout and stderr. `None` is `/dev/null`.
+    pub fds: [Option<RawFd>; 3],
+}
+
+impl Spawn {
+    pub fn new(
+        path: &str,
+        args: &[String],
+        env: &[String],
+        fds: [Option<RawFd>; 3],
+    ) -> Result<Spawn, NulError> {
+        let strings = |strings: &[String]| {
+            strings
+                .iter()
+                .map(|s| CString::new(s.as_str()))
+                .collect::<Result<Vec<CString>, NulError>>()
+        };
+        Ok(Spawn {
+            path: CString::new(path)?,
+            argv: strings(args)?,
+            envp: strings(env)?,
+            fds,
+        })
+    }
+}
+
+/// A null-terminated array of pointers to `strings`, for exec.
+fn pointers(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(ptr::null()))
+        .collect()
+}
+
+/// The process and file descriptor system calls the executor makes, so that tests can check
+/// which calls it makes without running anything.
+#[cfg_attr(test, mockall::automock)]
+pub trait Sys {
+    /// Forks the process. If the parent has other threads, the child may only do
+    /// async-signal-safe work before it execs or exits.
+    fn fork(&self) -> nix::Result<ForkResult>;
+    /// Starts a command in a new process without running any of the shell in it.
+    fn spawn(&self, spawn: &Spawn) -> nix::Result<Pid>;
+    /// Creates a pipe whose ends are closed on exec, returning the read end and then the write
+    /// end.
+    fn pipe(&self) -> nix::Result<(RawFd, RawFd)>;
+    fn dup2(&self, old: RawFd, new: RawFd) -> nix::Result<RawFd>;
+    /// Duplicates `fd` onto a descriptor of 10 or above that is closed on exec, out of the way
+    /// of the low descriptors that redirections use.
+    fn dup(&self, fd: RawFd) -> nix::Result<RawFd>;
+    fn close(&self, fd: RawFd) -> nix::Result<()>;
+    fn open(&self, path: &str, flags: OFlag, mode: Mode) -> nix::Result<RawFd>;
+    fn waitpid(&self, pid: Pid, flags: Option<WaitPidFlag>) -> nix::Result<WaitStatus>;
+    fn kill(&self, pid: Pid, signal: Signal) -> nix::Result<()>;
+    /// Replaces the shell with a command, set up the way `spawn` would start it. Only returns
+    /// if that fails.
+    fn exec(&self, spawn: &Spawn) -> nix::Error;
+}
+
+/// The system calls themselves.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealSys;
+
+impl Sys for RealSys {
+    fn fork(&self) -> nix::Result<ForkResult> {
+        unsafe { nix::unistd::fork() }
+    }
+
+    fn spawn(&self, spawn: &Spawn) -> nix::Result<Pid> {
+        match posix_spawn(spawn) {
+            Err(nix::Error::Sys(Errno::ENOSYS)) => spawn_forked(spawn),
+            result => result,
+        }
+    }
+
+    fn pipe(&self) -> nix::Result<(RawFd, RawFd)> {
+        nix::unistd::pipe2(OFlag::O_CLOEXEC)
+    }
+
+    fn dup2(&self, old: RawFd, new: RawFd) -> nix::Result<RawFd> {
+        nix::unistd::dup2(old, new)
+    }
+
+    fn dup(&self, fd: RawFd) -> nix::Result<RawFd> {
+        nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(10))
+    }
+
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        nix::unistd::close(fd)
+    }
+
+    fn open(&self, path: &str, flags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+        nix::fcntl::open(path, flags, mode)
+    }
+
+    fn waitpid(&self, pid: Pid, flags: Option<WaitPidFlag>) -> nix::Result<WaitStatus> {
+        nix::sys::wait::waitpid(pid, flags)
+    }
+
+    fn kill(&self, pid: Pid, signal: Signal) -> nix::Result<()> {
+        nix::sys::signal::kill(pid, signal)
+    }
+
+    fn exec(&self, spawn: &Spawn) -> nix::Error {
+        let argv = pointers(&spawn.argv);
+        let envp = pointers(&spawn.envp);
+        unsafe { nix::Error::Sys(exec_in_place(spawn, &argv, &envp)) }
+    }
+}
+
+/// Checks the return value of a `posix_spawn` function, which is the error number itself.
+fn check(result: libc::c_int) -> nix::Result<()> {
+    match result {
+        0 => Ok(()),
+        errno => Err(nix::Error::Sys(Errno::from_i32(errno))),
+    }
+}
+
+/// Starts a command with `posix_spawn`, which lets the C library avoid copying the shell's
+/// memory.
+fn posix_spawn(spawn: &Spawn) -> nix::Result<Pid> {
+    let argv = pointers(&spawn.argv);
+    let envp = pointers(&spawn.envp);
+    // The file actions run in order, so a standard stream that is the source of another would be
+    // overwritten before it is read, as when swapping stdout and stderr. Those are copied first.
+    let mut copies = vec![];
+    let mut fds = spawn.fds;
+    let mut copied = Ok(());
+    for (target, fd) in fds.iter_mut().enumerate() {
+        match *fd {
+            Some(source) if source != target as RawFd && source <= 2 => match RealSys.dup(source) {
+                Ok(copy) => {
+                    copies.push(copy);
+                    *fd = Some(copy);
+                }
+                Err(e) => {
+                    copied = Err(e);
+                    break;
+                }
+            },
+            _ => {}
+        }
+    }
+    let result = copied.and_then(|()| posix_spawn_with(spawn, &fds, &argv, &envp));
+    for copy in copies {
+        let _ = RealSys.close(copy);
+    }
+    result
+}
+
+/// Starts a command with `posix_spawn` and `fds` as its standard streams.
+fn posix_spawn_with(
+    spawn: &Spawn,
+    fds: &[Option<RawFd>; 3],
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+) -> nix::Result<Pid> {
+    let mut actions = MaybeUninit::<libc::posix_spawn_file_actions_t>::uninit();
+    let mut attr = MaybeUninit::<libc::posix_spawnattr_t>::uninit();
+    unsafe {
+        check(libc::posix_spawn_file_actions_init(actions.as_mut_ptr()))?;
+        if let Err(e) = check(libc::posix_spawnattr_init(attr.as_mut_ptr())) {
+            libc::posix_spawn_file_actions_destroy(actions.as_mut_ptr());
+            return Err(e);
+        }
+        let result = (|| {
+            let actions = actions.as_mut_ptr();
+            let attr = attr.as_mut_ptr();
+            for (target, fd) in fds.iter().enumerate() {
+                let target = target as RawFd;
+                match *fd {
+                    Some(fd) if fd == target => {}
+                    Some(fd) => check(libc::posix_spawn_file_actions_adddup2(actions, fd, target))?,
+                    None => check(libc::posix_spawn_file_actions_addopen(
+                        actions,
+                        target,
+                        DEV_NULL.as_ptr() as *const libc::c_char,
+                        match target {
+                            0 => libc::O_RDONLY,
+                            _ => libc::O_WRONLY,
+                        },
+                        0,
+                    ))?,
+                }
+            }
+            let flags = libc::POSIX_SPAWN_SETSIGDEF | libc::POSIX_SPAWN_SETSIGMASK;
+            let mut signals = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(signals.as_mut_ptr());
+            check(libc::posix_spawnattr_setsigmask(attr, signals.as_ptr()))?;
+            for signal in DEFAULT_SIGNALS {
+                libc::sigaddset(signals.as_mut_ptr(), *signal);
+            }
+            check(libc::posix_spawnattr_setsigdefault(attr, signals.as_ptr()))?;
+            check(libc::posix_spawnattr_setflags(attr, flags as libc::c_short))?;
+            let mut pid = 0;
+            check(libc::posix_spawn(
+                &mut pid,
+                spawn.path.as_ptr(),
+                actions,
+                attr,
+                argv.as_ptr() as *const *mut libc::c_char,
+                envp.as_ptr() as *const *mut libc::c_char,
+            ))?;
+            Ok(Pid::from_raw(pid))
+        })();
+        libc::posix_spawnattr_destroy(attr.as_mut_ptr());
+        libc::posix_spawn_file_actions_destroy(actions.as_mut_ptr());
+        result
+    }
+}
+
+/// Starts a command by forking, for systems without `posix_spawn`. The child only makes
+/// async-signal-safe calls on memory prepared before the fork, so it is sound even if the shell
+/// has other threads, and exits with 127 if the command can't be executed.
+fn spawn_forked(spawn: &Spawn) -> nix::Result<Pid> {
+    let argv = pointers(&spawn.argv);
+    let envp = pointers(&spawn.envp);
+    let name = spawn.argv.first().map_or(&[][..], |arg| arg.as_bytes());
+    let message = [&b"traash: "[..], name, &b": cannot execute\n"[..]].concat();
+    unsafe {
+        match libc::fork() {
+            -1 => Err(nix::Error::Sys(Errno::last())),
+            0 => {
+                exec_in_place(spawn, &argv, &envp);
+                libc::write(2, message.as_ptr() as *const libc::c_void, message.len());
+                libc::_exit(127)
+            }
+            child => Ok(Pid::from_raw(child)),
+        }
+    }
+}
+
+/// Gives the current process the streams and signal handling of `spawn` and
+/// executes it with the prepared `argv` and `envp`, returning the error if that fails. Only
+/// makes async-signal-safe calls, so a forked child can use it.
+unsafe fn exec_in_place(
+    spawn: &Spawn,
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+) -> Errno {
+    // Standard streams that are the sources of others are copied before any is replaced.
+    let mut sources = spawn.fds;
+    for (target, fd) in sources.iter_mut().enumerate() {
+        if let Some(source) = *fd {
+            if source != target as RawFd && source <= 2 {
+                *fd = Some(libc::fcntl(source, libc::F_DUPFD_CLOEXEC, 10));
+            }
+        }
+    }
+    for (target, fd) in sources.iter().enumerate() {
+        let target = target as RawFd;
+        match *fd {
+            Some(fd) if fd == target => {}
+            Some(fd) => {
+                libc::dup2(fd, target);
+            }
+            None => {
+                let flags = match target {
+                    0 => libc::O_RDONLY,
+                    _ => libc::O_WRONLY,
+                };
+                let null = libc::open(DEV_NULL.as_ptr() as *const libc::c_char, flags);
+                if null >= 0 && null != target {
+                    libc::dup2(null, target);
+                    libc::close(null);
+                }
+            }
+        }
+    }
+    for signal in DEFAULT_SIGNALS {
+        libc::signal(*signal, libc::SIG_DFL);
+    }
+    let mut signals = MaybeUninit::<libc::sigset_t>::uninit();
+    libc::sigemptyset(signals.as_mut_ptr());
+    libc::sigprocmask(libc::SIG_SETMASK, signals.as_ptr(), ptr::null_mut());
+    libc::execve(spawn.path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    Errno::last()
+}
+
+/// A shared handle to the `Sys` a shell uses, which subshells and processes hold on to.
+#[derive(Clone)]
+pub struct SysRef(Rc<dyn Sys>);
+
+impl SysRef {
+    pub fn new<S: Sys + 'static>(sys: S) -> SysRef {
+        SysRef(Rc::new(sys))
+    }
+}
+
+impl Default for SysRef {
+    fn default() -> SysRef {
+        SysRef::new(RealSys)
+    }
+}
+
+impl Deref for SysRef {
+    type Target = dyn Sys;
+
+    fn deref(&self) -> &(dyn Sys + 'static) {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SysRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SysRef")
+    }
+}
+
+#[test]
+fn both_backends_connect_streams() {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    for backend in [posix_spawn, spawn_forked] {
+        let (read, write) = RealSys.pipe().unwrap();
+        let args = ["sh", "-c", "echo out; exit 4"].map(String::from);
+        let spawn = Spawn::new("/bin/sh", &args, &[], [None, Some(write), None]).unwrap();
+        let child = backend(&spawn).unwrap();
+        RealSys.close(write).unwrap();
+        let mut output = vec![];
+        let mut file = unsafe { std::fs::File::from_raw_fd(read) };
+        file.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"out\n");
+        assert_eq!(
+            RealSys.waitpid(child, None),
+            Ok(WaitStatus::Exited(child, 4))
+        );
+    }
+}
+
+#[test]
+fn both_backends_swap_streams() {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let link = |fd: RawFd| std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap();
+    let expected = format!("{}\n{}\n", link(2).display(), link(0).display());
+    for backend in [posix_spawn, spawn_forked] {
+        let (read, write) = RealSys.pipe().unwrap();
+        let args = ["sh", "-c", "readlink /proc/self/fd/0 /proc/self/fd/2"].map(String::from);
+        // Stdin and stderr trade places, so whichever goes first must not clobber the other.
+        let spawn = Spawn::new("/bin/sh", &args, &[], [Some(2), Some(write), Some(0)]).unwrap();
+        let child = backend(&spawn).unwrap();
+        RealSys.close(write).unwrap();
+        let mut output = String::new();
+        let mut file = unsafe { std::fs::File::from_raw_fd(read) };
+        file.read_to_string(&mut output).unwrap();
+        assert_eq!(output, expected);
+        RealSys.waitpid(child, None).unwrap();
+    }
+}
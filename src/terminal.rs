@@ -0,0 +1,232 @@
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{
+    tcgetattr, tcsetattr, InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, Termios,
+};
+use nix::unistd::read;
+
+/// How long to wait for the rest of an escape sequence before treating ESC as a key of its own.
+const ESCAPE_TIMEOUT_MS: i32 = 50;
+
+const DEFAULT_WIDTH: usize = 80;
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+/// A key press decoded from terminal input.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Key {
+    Char(char),
+    /// A control character, named by its lowercase letter, e.g. `Ctrl('a')` for C-a.
+    Ctrl(char),
+    /// A character typed with Meta (Alt), which terminals send as ESC followed by the character.
+    Meta(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    MetaBackspace,
+    /// Left with Meta or Ctrl held.
+    WordLeft,
+    /// Right with Meta or Ctrl held.
+    WordRight,
+    Unknown,
+}
+
+/// Puts a terminal into raw mode, restoring its previous settings when dropped.
+pub struct RawMode {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawMode {
+    /// Turns off line buffering, echo and signal keys so that every key press reaches the editor.
+    /// Output processing stays on, so `\n` still moves to the start of the next line.
+    pub fn enable(fd: RawFd) -> nix::Result<RawMode> {
+        let original = tcgetattr(fd)?;
+        let mut raw = original.clone();
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+        raw.input_flags
+            .remove(InputFlags::IXON | InputFlags::ICRNL | InputFlags::BRKINT);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(fd, SetArg::TCSADRAIN, &raw)?;
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSADRAIN, &self.original);
+    }
+}
+
+/// The width of the terminal on `fd` in columns.
+pub fn window_width(fd: RawFd) -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => size.ws_col as usize,
+        _ => DEFAULT_WIDTH,
+    }
+}
+
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGWINCH handler. Without `SA_RESTART`, a resize interrupts a blocked read, so the
+/// editor can redraw right away.
+pub fn watch_window_size() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_resize),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGWINCH, &action) }.map(|_| ())
+}
+
+/// Whether the window was resized since the last call.
+pub fn take_resized() -> bool {
+    RESIZED.swap(false, Ordering::SeqCst)
+}
+
+/// Reads one byte, returning `None` at end of input. Interrupted reads are returned as `EINTR`.
+pub fn read_byte(fd: RawFd) -> nix::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    match read(fd, &mut buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(buf[0])),
+    }
+}
+
+/// Reads the next byte of a sequence that has already started, giving up after a short wait.
+fn read_pending_byte(fd: RawFd) -> Option<u8> {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    match poll(&mut fds, ESCAPE_TIMEOUT_MS) {
+        Ok(n) if n > 0 => read_byte(fd).ok().flatten(),
+        _ => None,
+    }
+}
+
+/// Reads one key press, returning `None` at end of input.
+pub fn read_key(fd: RawFd) -> nix::Result<Option<Key>> {
+    Ok(read_byte(fd)?.map(|first| decode(first, &mut || read_pending_byte(fd))))
+}
+
+/// Decodes a key that starts with `first`. `next` returns the following bytes of the same key,
+/// or `None` if none arrive in time.
+pub fn decode(first: u8, next: &mut dyn FnMut() -> Option<u8>) -> Key {
+    match first {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => decode_escape(next),
+        0x01..=0x1a => Key::Ctrl((b'a' + first - 1) as char),
+        0x00..=0x1f => Key::Unknown,
+        0x80..=0xff => decode_utf8(first, next),
+        _ => Key::Char(first as char),
+    }
+}
+
+fn decode_utf8(first: u8, next: &mut dyn FnMut() -> Option<u8>) -> Key {
+    let len = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Key::Unknown,
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        match next() {
+            Some(b) => bytes.push(b),
+            None => return Key::Unknown,
+        }
+    }
+    match std::str::from_utf8(&bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+    {
+        Some(c) => Key::Char(c),
+        None => Key::Unknown,
+    }
+}
+
+fn decode_escape(next: &mut dyn FnMut() -> Option<u8>) -> Key {
+    match next() {
+        None => Key::Escape,
+        Some(b'[') => decode_csi(next),
+        Some(b'O') => match next() {
+            Some(b'A') => Key::Up,
+            Some(b'B') => Key::Down,
+            Some(b'C') => Key::Right,
+            Some(b'D') => Key::Left,
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
+            _ => Key::Unknown,
+        },
+        Some(b) => match decode(b, next) {
+            Key::Char(c) => Key::Meta(c),
+            Key::Backspace => Key::MetaBackspace,
+            Key::Left | Key::WordLeft => Key::WordLeft,
+            Key::Right | Key::WordRight => Key::WordRight,
+            _ => Key::Unknown,
+        },
+    }
+}
+
+/// Decodes a control sequence after `ESC [`, such as `1;5C` for Ctrl-Right or `3~` for Delete.
+fn decode_csi(next: &mut dyn FnMut() -> Option<u8>) -> Key {
+    let mut params = String::new();
+    let end = loop {
+        match next() {
+            Some(b @ 0x40..=0x7e) => break b,
+            Some(b) => params.push(b as char),
+            None => return Key::Unknown,
+        }
+    };
+    let modified = params.ends_with(";3") || params.ends_with(";5");
+    match (end, params.as_str()) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) if modified => Key::WordRight,
+        (b'D', _) if modified => Key::WordLeft,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) | (b'~', "1") | (b'~', "7") => Key::Home,
+        (b'F', _) | (b'~', "4") | (b'~', "8") => Key::End,
+        (b'~', "3") => Key::Delete,
+        _ => Key::Unknown,
+    }
+}
+
+#[cfg(test)]
+fn decode_bytes(bytes: &[u8]) -> Key {
+    let mut rest = bytes[1..].iter().copied();
+    decode(bytes[0], &mut || rest.next())
+}
+
+#[test]
+fn decodes_keys() {
+    assert_eq!(decode_bytes(b"a"), Key::Char('a'));
+    assert_eq!(decode_bytes(b"\x01"), Key::Ctrl('a'));
+    assert_eq!(decode_bytes(b"\x1bb"), Key::Meta('b'));
+    assert_eq!(decode_bytes(b"\x1b"), Key::Escape);
+    assert_eq!(decode_bytes(b"\x1b[A"), Key::Up);
+    assert_eq!(decode_bytes(b"\x1bOH"), Key::Home);
+    assert_eq!(decode_bytes(b"\x1b[3~"), Key::Delete);
+    assert_eq!(decode_bytes(b"\x1b[1;5C"), Key::WordRight);
+    assert_eq!(decode_bytes(b"\x1b\x1b[D"), Key::WordLeft);
+    assert_eq!(decode_bytes("é".as_bytes()), Key::Char('é'));
+    assert_eq!(decode_bytes("漢".as_bytes()), Key::Char('漢'));
+}
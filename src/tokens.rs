@@ -1,22 +1,31 @@
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Token {
     Text(String),
-    Glob,
-    Space,
     LogAnd,
     LogOr,
     Pipe,
     WriteFile,
     AppendFile,
     ReadFile,
+    DupInput,
+    DupOutput,
+    IoNumber(i32),
     Semicolon,
     Fork,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    /// The expression inside an `(( ... ))` command.
+    Arith(String),
+    /// The words and operators inside a `[[ ... ]]` command, excluding the brackets. Words are in
+    /// source form; operators are unquoted strings such as `&&` or `(`.
+    Conditional(Vec<String>),
 }
 
 impl Token {
-    pub(crate) fn text(text: &str) -> Token {
+    /// A word token holding `text`.
+    pub fn text(text: &str) -> Token {
         Token::Text(text.to_string())
     }
 }
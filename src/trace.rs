@@ -0,0 +1,82 @@
+use std::borrow::Cow;
+use std::os::unix::io::RawFd;
+
+use crate::executor::StreamSet;
+use crate::expand::quote;
+use crate::prompt;
+use crate::state::State;
+
+/// Characters that make a word need quotes to be read back as itself, wherever they are in it.
+const SPECIAL: &str = " \t\n'\"\\|&;()<>!{}*?[]^$`";
+
+/// Quotes `word` in single quotes if it would otherwise not read back as the same word.
+pub fn quote_word(word: &str) -> Cow<'_, str> {
+    let needs_quotes = word.is_empty()
+        || word.contains(|c| SPECIAL.contains(c))
+        || word.starts_with(['#', '~'].as_ref());
+    match needs_quotes {
+        true => quote(word).into(),
+        false => word.into(),
+    }
+}
+
+/// The streams trace lines go to: the descriptor in `BASH_XTRACEFD` if it names one, or stderr.
+fn trace_streams(state: &State, streams: &StreamSet) -> StreamSet {
+    let fd = state
+        .get_var("BASH_XTRACEFD")
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+        .filter(|fd| *fd >= 0);
+    match fd {
+        Some(fd) => StreamSet::new(None, None, streams.get(fd)),
+        None => *streams,
+    }
+}
+
+/// Writes `line` as a trace line for `set -x`, after the expanded `PS4` with its first character
+/// repeated once more for each subshell and function call the shell is in.
+pub fn trace(state: &mut State, line: &str, streams: &StreamSet) {
+    // Commands run to expand PS4 aren't traced.
+    state.xtrace = false;
+    let ps4 = prompt::render(state, "PS4");
+    state.xtrace = true;
+    let mut prefix = String::new();
+    if let Some(first) = ps4.chars().next() {
+        let depth = state.subshell_depth + state.function_depth();
+        prefix.extend(std::iter::repeat_n(first, depth));
+    }
+    prefix.push_str(&ps4);
+    trace_streams(state, streams).write_err(&format!("{}{}\n", prefix, line));
+}
+
+/// Echoes input as it is read, for `set -v`.
+pub fn echo_input(state: &State, input: &str, streams: &StreamSet) {
+    if !state.verbose {
+        return;
+    }
+    match input.ends_with('\n') {
+        true => streams.write_err(input),
+        false => streams.write_err(&format!("{}\n", input)),
+    }
+}
+
+#[test]
+fn quotes_words_only_when_needed() {
+    let quoted: Vec<_> = ["a=b", "a b", "", "it's", "*", "~x", "x~", "#c", "c#"]
+        .iter()
+        .map(|w| quote_word(w))
+        .collect();
+    assert_eq!(
+        quoted,
+        [
+            "a=b",
+            "'a b'",
+            "''",
+            "'it'\\''s'",
+            "'*'",
+            "'~x'",
+            "x~",
+            "'#c'",
+            "c#"
+        ]
+    );
+}
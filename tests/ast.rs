@@ -0,0 +1,45 @@
+//! Builds and inspects commands through the public API, as a crate depending on traash would.
+
+use traash::ast::{self, BinaryOp, Command, SingleCommand};
+use traash::lexer::lex;
+use traash::parser::parse;
+use traash::tokens::Token;
+
+fn words(words: &[&str]) -> Vec<String> {
+    words.iter().map(|word| word.to_string()).collect()
+}
+
+#[test]
+fn parsed_pipeline_exposes_its_words() {
+    let command = parse(&lex("echo a b | wc -l").unwrap()).unwrap();
+    let expr = match command {
+        Command::BinaryExpr(expr) => expr,
+        other => panic!("expected a pipeline, got {:?}", other),
+    };
+    assert_eq!(expr.op, BinaryOp::Pipe);
+    match (*expr.first, *expr.second) {
+        (Command::Single(first), Command::Single(second)) => {
+            assert_eq!(first.args, words(&["echo", "a", "b"]));
+            assert_eq!(second.args, words(&["wc", "-l"]));
+        }
+        other => panic!("expected two simple commands, got {:?}", other),
+    }
+}
+
+#[test]
+fn built_command_matches_parsed_one() {
+    let built = ast::log_and(
+        Command::Single(SingleCommand {
+            args: words(&["true"]),
+        }),
+        ast::single(words(&["echo", "ok"])),
+    );
+    let tokens = vec![
+        Token::text("true"),
+        Token::LogAnd,
+        Token::text("echo"),
+        Token::text("ok"),
+    ];
+    assert_eq!(parse(&tokens).unwrap(), built);
+    assert_eq!(built.to_string(), "true && echo ok");
+}
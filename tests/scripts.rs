@@ -0,0 +1,219 @@
+//! Runs each script in `tests/scripts` with the traash binary and checks what it writes and how
+//! it exits against the files next to it:
+//!
+//! - `name.stdout` and `name.stderr`, which are taken to be empty when missing, and
+//! - `name.status`, the exit status, taken to be 0 when missing.
+//!
+//! Scripts whose first line is `# posix` are also run with `/bin/sh` when there is one, which
+//! must agree on stdout and the status. Setting `TRAASH_BLESS=1` rewrites the expected files from
+//! what traash did instead of checking them.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a script may run before it is killed and counted as a failure.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What running a script produced.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout: String,
+    stderr: String,
+    status: String,
+}
+
+fn scripts_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts")
+}
+
+/// Runs `script` with `shell` in a new empty directory, which is also `HOME`.
+fn run(shell: &str, script: &Path) -> Outcome {
+    let name = script.file_stem().unwrap().to_string_lossy();
+    let shell_name = Path::new(shell).file_name().unwrap().to_string_lossy();
+    let dir = env::temp_dir().join(format!(
+        "traash-{}-{}-{}",
+        shell_name,
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let stdout = dir.join(".stdout");
+    let stderr = dir.join(".stderr");
+    let mut child = Command::new(shell)
+        .arg(script)
+        .current_dir(&dir)
+        .env_clear()
+        .env("PATH", env::var_os("PATH").unwrap_or_default())
+        .env("HOME", &dir)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .stdout(File::create(&stdout).unwrap())
+        .stderr(File::create(&stderr).unwrap())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            break child.wait().unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let outcome = Outcome {
+        stdout: fs::read_to_string(&stdout).unwrap(),
+        stderr: fs::read_to_string(&stderr).unwrap(),
+        status: match status.code() {
+            Some(code) => format!("{}\n", code),
+            None => format!("{}\n", status),
+        },
+    };
+    let _ = fs::remove_dir_all(&dir);
+    outcome
+}
+
+fn expected(script: &Path, extension: &str, default: &str) -> String {
+    fs::read_to_string(script.with_extension(extension)).unwrap_or_else(|_| default.to_string())
+}
+
+/// Writes `contents` as the expected file, or removes it when it would hold the default.
+fn bless(script: &Path, extension: &str, contents: &str, default: &str) {
+    let path = script.with_extension(extension);
+    if contents == default {
+        let _ = fs::remove_file(path);
+    } else {
+        fs::write(path, contents).unwrap();
+    }
+}
+
+/// A line diff of `expected` and `actual`, from their longest common subsequence of lines.
+fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            let _ = writeln!(out, "   {}", old[i]);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            let _ = writeln!(out, "  -{}", old[i]);
+            i += 1;
+        } else {
+            let _ = writeln!(out, "  +{}", new[j]);
+            j += 1;
+        }
+    }
+    let trailing = |s: &str| s.is_empty() || s.ends_with('\n');
+    if trailing(expected) != trailing(actual) {
+        out.push_str("  (the final newline differs)\n");
+    }
+    out
+}
+
+/// Describes each way `actual` differs from `expected`, labelled with what it was compared to.
+fn compare(label: &str, expected: &Outcome, actual: &Outcome, stderr: bool) -> Option<String> {
+    let mut report = String::new();
+    let mut parts = vec![
+        ("stdout", &expected.stdout, &actual.stdout),
+        ("status", &expected.status, &actual.status),
+    ];
+    if stderr {
+        parts.insert(1, ("stderr", &expected.stderr, &actual.stderr));
+    }
+    for (part, expected, actual) in parts {
+        if expected != actual {
+            let _ = writeln!(
+                report,
+                "{} differs from {} (-expected +actual):",
+                part, label
+            );
+            report.push_str(&diff(expected, actual));
+        }
+    }
+    match report.is_empty() {
+        true => None,
+        false => Some(report),
+    }
+}
+
+#[test]
+fn scripts_match_their_expected_output() {
+    let traash = env!("CARGO_BIN_EXE_traash");
+    let blessing = env::var_os("TRAASH_BLESS").is_some_and(|v| v == "1");
+    let sh = Path::new("/bin/sh");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(scripts_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "sh"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {:?}", scripts_dir());
+
+    let mut failures = vec![];
+    for script in &scripts {
+        let actual = run(traash, script);
+        if blessing {
+            bless(script, "stdout", &actual.stdout, "");
+            bless(script, "stderr", &actual.stderr, "");
+            bless(script, "status", &actual.status, "0\n");
+            continue;
+        }
+        let expected = Outcome {
+            stdout: expected(script, "stdout", ""),
+            stderr: expected(script, "stderr", ""),
+            status: expected(script, "status", "0\n"),
+        };
+        let name = script.file_name().unwrap().to_string_lossy();
+        if let Some(report) = compare("the expected files", &expected, &actual, true) {
+            failures.push(format!("{}: {}", name, report));
+        }
+        let posix = fs::read_to_string(script)
+            .unwrap()
+            .lines()
+            .next()
+            .is_some_and(|line| line.trim() == "# posix");
+        if posix && sh.exists() {
+            let reference = run("/bin/sh", script);
+            if let Some(report) = compare("/bin/sh", &reference, &actual, false) {
+                failures.push(format!("{}: {}", name, report));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} scripts failed\n\n{}",
+            failures.len(),
+            scripts.len(),
+            failures.join("\n")
+        );
+    }
+}
+
+#[test]
+fn diffs_show_changed_lines_in_context() {
+    assert_eq!(
+        diff("a\nb\nc\n", "a\nx\nc\nd\n"),
+        "   a\n  -b\n  +x\n   c\n  +d\n"
+    );
+    assert_eq!(diff("a\n", "a"), "   a\n  (the final newline differs)\n");
+}
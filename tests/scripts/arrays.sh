@@ -0,0 +1,7 @@
+fruits=(apple banana cherry)
+echo ${fruits[0]} ${fruits[2]}
+echo ${#fruits[@]}
+echo "${fruits[@]}"
+echo $fruits
+more=("with spaces" two)
+echo ${#more[@]} "${more[0]}"
@@ -0,0 +1,22 @@
+cd /
+pwd
+cd "$HOME"
+export EXPORTED=yes
+sh -c 'echo $EXPORTED'
+NOT_EXPORTED=no
+sh -c 'echo "[$NOT_EXPORTED]"'
+unset EXPORTED
+echo "[$EXPORTED]"
+test 1 -lt 2 && echo test
+[ -d / ] && echo bracket
+[[ abc == a* ]] && echo conditional
+(( 3 > 2 )) && echo arithmetic
+alias greet='echo hello from an alias'
+greet
+sleep 5 &
+kill $!
+wait $!
+echo killed $?
+(exit 4) &
+wait $!
+echo waited $?
@@ -0,0 +1,26 @@
+# posix
+true && echo and runs
+false && echo and skipped
+false || echo or runs
+true || echo or skipped
+false || false || echo chained
+true && false || echo recovered
+echo a; echo b | tr ab xy
+false && echo skipped | cat || echo pipelines bind tighter
+! false && echo negated
+! echo quiet | grep -q loud && echo negated pipeline
+true & echo after background
+wait
+greet() {
+    echo "hello $1"
+    return 3
+}
+greet there
+echo status $?
+(exit 5)
+echo subshell status $?
+x=outer
+(x=inner; echo $x)
+echo $x
+{ x=grouped; }
+echo $x
@@ -0,0 +1,11 @@
+no-such-command
+echo $?
+/no/such/path
+echo $?
+./
+echo $?
+cd /no/such/directory
+echo $?
+shift 5
+echo $?
+exit 7
@@ -0,0 +1,16 @@
+# posix
+name=world
+echo "hello, $name" "${name}s"
+echo ${unset:-fallback} ${name:+set} "${#name}"
+echo $(echo command $(echo substitution)) `echo backquotes`
+echo $((1 + 2 * 3)) $(( (1 + 2) * 3 )) $((7 / 2)) $((7 % 2))
+count=3
+echo $((count + 1))
+args() {
+    echo $# $1 $3
+    shift
+    echo $# $1
+    echo "$@"
+}
+args first "second word" third
+echo ~ | sed "s|$HOME|HOME|"
@@ -0,0 +1,23 @@
+count() {
+    echo $#
+}
+count a b c
+outer() {
+    local v=local
+    inner
+}
+inner() {
+    echo "inner sees $v"
+}
+v=global
+outer
+echo $v
+function keyword {
+    echo keyword function
+}
+keyword
+recurse() {
+    (( $1 > 0 )) && { echo $1; recurse $(( $1 - 1 )); }
+}
+recurse 3
+echo done
@@ -0,0 +1,12 @@
+# posix
+echo 'single quotes keep $HOME and  spacing  as it is'
+echo "double quotes expand $((6 * 7)) but not \$this"
+echo backslash\ joins\ words \"escaped\" \\
+words="two   spaces"
+echo $words
+echo "$words"
+echo ''"adjacent"'parts'
+empty=""
+echo [$empty] ["$empty"]
+echo "nested 'single' inside"
+echo 'nested "double" inside'
@@ -0,0 +1,15 @@
+# posix
+echo first > out.txt
+echo second >> out.txt
+cat out.txt
+cat < out.txt > copy.txt
+cat copy.txt
+echo to stderr >&2
+echo both 2>&1
+{ echo grouped; echo output; } > group.txt
+cat group.txt
+( echo from a subshell ) >> group.txt
+cat group.txt
+cat missing.txt 2> err.txt
+echo status $?
+cat err.txt | sed 's/.*missing.txt.*/missing/'
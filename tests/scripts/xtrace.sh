@@ -0,0 +1,16 @@
+set -x
+x="a b" y=1
+echo "$x" "" "it's" a=b "*"
+(echo sub)
+f() { echo in; }
+f
+PS4='[$y] '
+echo custom
+set +x
+echo quiet
+set -x
+BASH_XTRACEFD=1
+echo to stdout
+set +x
+set -v
+echo verbose